@@ -0,0 +1,59 @@
+use std::{env, fs, path::PathBuf};
+
+/// The board used when `LIGHTNING_BOARD` is not set.
+const DEFAULT_BOARD: &str = "qemu-virt";
+
+fn main() {
+    let board = env::var("LIGHTNING_BOARD").unwrap_or_else(|_| DEFAULT_BOARD.into());
+    let manifest = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let dir = manifest.join("src/hal/lds/boards").join(&board);
+    let memory = dir.join("memory.lds");
+
+    let script = fs::read_to_string(&memory)
+        .unwrap_or_else(|err| panic!("unknown board `{board}` ({}): {err}", memory.display()));
+
+    let max_harts = symbol(&script, "__max_harts");
+    let hart_stack_size = symbol(&script, "__hart_stack_size");
+    assert!(max_harts > 0, "`__max_harts` must be at least 1");
+    assert!(
+        hart_stack_size.is_power_of_two(),
+        "`__hart_stack_size` must be a power of two"
+    );
+
+    // The linker script `INCLUDE`s `memory.lds`, which is resolved through the search path.
+    println!("cargo:rustc-link-search={}", dir.display());
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(
+        out.join("board.rs"),
+        format!(
+            "/// The maximum amount of harts that will be brought up.\n\
+             pub const MAX_HARTS: usize = {max_harts};\n\
+             /// The size of the boot stack of every hart.\n\
+             pub const HART_STACK_SIZE: usize = {hart_stack_size:#x};\n"
+        ),
+    )
+    .unwrap();
+
+    println!("cargo:rerun-if-env-changed=LIGHTNING_BOARD");
+    println!("cargo:rerun-if-changed={}", memory.display());
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+/// Find a `name = value;` assignment in a linker script, and parse its value.
+fn symbol(script: &str, name: &str) -> usize {
+    let value = script
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix(name))
+        .filter_map(|rest| rest.trim_start().strip_prefix('='))
+        .find_map(|rest| rest.split(';').next())
+        .unwrap_or_else(|| panic!("board does not define `{name}`"))
+        .trim();
+
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+
+    parsed.unwrap_or_else(|_| panic!("`{name}` must be a plain integer, found `{value}`"))
+}
@@ -0,0 +1,138 @@
+//! Measuring the interrupt latency with `mcycle`, run by the `bench-interrupts` recipe.
+//!
+//! The core raises its own software interrupt, and counts the cycles until the handler runs and
+//! until the interrupted code continues. The results go to the console, then the system shuts down.
+//! Under QEMU, `mcycle` only counts something meaningful with `-icount`.
+
+use core::{
+    arch::asm,
+    fmt::{self, Write},
+    hint::spin_loop,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::hal::{
+    boot,
+    core::{id, kernel_mode},
+    execution::riscv::Mode,
+    interrupts::{self, Source, aclint},
+    power::{self, Reason},
+};
+
+/// The amount of interrupts measured.
+const ROUNDS: usize = 1000;
+
+/// The line status register of a 16550 UART, and its bit telling the transmitter is empty.
+const LSR: usize = 5;
+const LSR_THRE: u8 = 1 << 5;
+
+/// The cycle count when the handler ran, 0 until it did.
+static ENTERED: AtomicUsize = AtomicUsize::new(0);
+
+/// The least, most and total cycles of the rounds.
+struct Stats {
+    min: usize,
+    max: usize,
+    total: usize,
+}
+
+impl Stats {
+    fn new() -> Stats {
+        Stats {
+            min: usize::MAX,
+            max: 0,
+            total: 0,
+        }
+    }
+
+    fn add(&mut self, cycles: usize) {
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        self.total += cycles;
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (min, max, average) = (self.min, self.max, self.total / ROUNDS);
+        write!(f, "min {min}, max {max}, average {average} cycles")
+    }
+}
+
+/// Polled output to the console UART, enough for the results.
+struct Console(usize);
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // SAFETY: The UART is a valid MMIO region, and nothing else writes to it.
+            unsafe {
+                while ptr::read_volatile((self.0 + LSR) as *const u8) & LSR_THRE == 0 {
+                    spin_loop()
+                }
+                ptr::write_volatile(self.0 as *mut u8, byte)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Measure the latency of the software interrupt, print it and shut down, so this never returns.
+///
+/// # Panics
+///
+/// If the kernel does not run in M-mode, which `mcycle` is only readable in.
+///
+pub fn run() {
+    fn interrupt() {
+        ENTERED.store(mcycle(), Ordering::Relaxed);
+        aclint::clear_ipi();
+    }
+
+    assert_eq!(
+        kernel_mode(),
+        Mode::Machine,
+        "mcycle is only readable in M-mode"
+    );
+    interrupts::register(Source::Software, &interrupt);
+    interrupts::enable();
+
+    let (mut entry, mut round_trip) = (Stats::new(), Stats::new());
+    for _ in 0..ROUNDS {
+        ENTERED.store(0, Ordering::Relaxed);
+
+        let start = mcycle();
+        aclint::send_ipi(id());
+        while ENTERED.load(Ordering::Relaxed) == 0 {
+            spin_loop()
+        }
+        let end = mcycle();
+
+        entry.add(ENTERED.load(Ordering::Relaxed).wrapping_sub(start));
+        round_trip.add(end.wrapping_sub(start));
+    }
+
+    if let Some(uart) = boot::info().uart {
+        let mut console = Console(uart.region.start);
+        let mode = if cfg!(feature = "riscv_vectored") {
+            "vectored"
+        } else {
+            "direct"
+        };
+
+        let _ = writeln!(console, "{mode} trap mode, {ROUNDS} software interrupts:");
+        let _ = writeln!(console, "  to the handler: {entry}");
+        let _ = writeln!(console, "  round trip:     {round_trip}");
+    }
+
+    power::shutdown(Reason::Done)
+}
+
+/// The cycle counter, its lower half on RV32.
+fn mcycle() -> usize {
+    let cycles;
+    // SAFETY: Reading the cycle counter has no side effects.
+    unsafe { asm!("csrr {}, mcycle", out(reg) cycles, options(nomem, nostack)) }
+    cycles
+}
@@ -1,6 +1,34 @@
-mod boot;
+pub mod board;
+pub mod boot;
 
 pub mod core;
 pub mod execution;
+pub mod frames;
+pub mod heap;
 pub mod interrupts;
+pub mod ipi;
+pub mod mmu;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub mod power;
+#[cfg(all(
+    any(target_arch = "riscv32", target_arch = "riscv64"),
+    not(feature = "riscv_isa_e")
+))]
+pub mod sbi;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+#[expect(
+    dead_code,
+    reason = "the kernel has no system calls yet, only the self-test makes one"
+)]
+pub mod syscall;
+pub mod time;
 pub mod trap;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+#[cfg_attr(
+    not(feature = "self_test"),
+    expect(dead_code, reason = "the kernel runs no user code yet, only the self-test does")
+)]
+pub mod user;
+
+#[cfg(all(feature = "riscv_sbi", feature = "riscv_isa_e"))]
+compile_error!("the SBI calling convention uses `a6` and `a7`, which RV32E does not have");
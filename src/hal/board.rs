@@ -0,0 +1,6 @@
+//! Constants describing the board the kernel is built for.
+//!
+//! They are generated by `build.rs` from `src/hal/lds/boards/<board>/memory.lds`, where the board
+//! is selected with the `LIGHTNING_BOARD` environment variable (defaulting to `qemu-virt`).
+
+include!(concat!(env!("OUT_DIR"), "/board.rs"));
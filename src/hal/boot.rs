@@ -1,21 +1,161 @@
-use core::slice;
+use core::{
+    hint::spin_loop,
+    mem::MaybeUninit,
+    slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::hal::{
-    core::{CoreState, is_primary_core},
+    core::{Core, is_primary_core},
     execution::Environment as _,
+    frames,
+    interrupts::{aclint, plic},
+    ipi, time,
     trap::setup_trap_handler,
 };
 
-use super::interrupts;
+pub use fdt::{Fdt, Region};
+pub use info::{Aclint, BootInfo, Cpu, List, Plic, PlicContext, Uart};
 
+pub mod fdt;
+mod info;
 #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
 mod riscv;
 
-fn setup() -> ! {
-    if !is_primary_core() {
-        park()
+/// The boot barrier is closed until the primary core has prepared the shared kernel state.
+const BARRIER_CLOSED: usize = 0xb007;
+/// The primary core has relocated the image, and mapped the kernel space if the kernel moves. The
+/// other cores can move there, and wait for the barrier to open once they did.
+const BARRIER_MAPPED: usize = 0xb008;
+const BARRIER_OPEN: usize = 0;
+
+/// Secondary cores wait on this until the primary core releases them.
+///
+/// It starts out as [`BARRIER_CLOSED`], so it is placed in `.data` instead of `.bss`, which is
+/// only zeroed once the primary core gets to it.
+///
+static BOOT_BARRIER: AtomicUsize = AtomicUsize::new(BARRIER_CLOSED);
+
+/// Written once by the primary core before it opens the [`BOOT_BARRIER`], read-only afterwards.
+static mut BOOT_INFO: MaybeUninit<BootInfo> = MaybeUninit::uninit();
+
+/// The common boot code, `hart_id` and `dtb` are passed along from the firmware.
+///
+/// It runs where the firmware loaded the kernel, before the image is relocated. So until then it
+/// must not use function pointers, trait objects or references in statics. Every core continues in
+/// [`start`], where the kernel runs.
+///
+/// The image is relocated to where it was loaded. It only moves to a randomized address in the
+/// higher half for RV64 S-mode kernels under an SBI firmware (the `riscv_sbi` feature). Kernels
+/// with the built-in firmware share the image with it, and Sv32 has no room for a higher half, see
+/// `riscv::map_kernel_space`.
+///
+extern "C" fn setup(hart_id: usize, dtb: *const u8) -> ! {
+    if is_primary_core() {
+        // SAFETY: Nothing used the addresses in the image yet, and the other cores wait.
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        unsafe {
+            riscv::relocate()
+        };
+
+        clear_bss();
+
+        // SAFETY: The firmware passes either nothing or a valid DTB, which we never overwrite.
+        let fdt = unsafe { Fdt::from_ptr(dtb) }.ok();
+        let info = BootInfo::new(fdt);
+
+        // SAFETY: This is the primary core, and the other cores wait for the barrier.
+        unsafe { frames::init(&info) };
+
+        #[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+        riscv::map_kernel_space(&info);
+
+        // SAFETY: Only the primary core writes, and nobody reads until the barrier opens.
+        unsafe {
+            (*&raw mut BOOT_INFO).write(info);
+        }
+
+        BOOT_BARRIER.store(BARRIER_MAPPED, Ordering::Release);
+    } else {
+        while BOOT_BARRIER.load(Ordering::Acquire) == BARRIER_CLOSED {
+            spin_loop()
+        }
+    }
+
+    // SAFETY: The image is relocated, and the kernel space mapped if the kernel moves.
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    unsafe {
+        riscv::enter_kernel_space(hart_id, dtb, start)
+    }
+}
+
+/// The common boot code, once the core runs where the kernel does.
+extern "C" fn start(_hart_id: usize, dtb: *const u8) -> ! {
+    if is_primary_core() {
+        // SAFETY: The other cores do not use the addresses in the image until the barrier opens.
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        unsafe {
+            riscv::finish_move()
+        };
+
+        BOOT_BARRIER.store(BARRIER_OPEN, Ordering::Release);
+    } else {
+        while BOOT_BARRIER.load(Ordering::Acquire) != BARRIER_OPEN {
+            spin_loop()
+        }
+    }
+
+    let info = info();
+
+    #[cfg(feature = "riscv_sbi")]
+    if is_primary_core() {
+        riscv::start_secondary_harts(info, dtb);
+    }
+
+    // SAFETY: Every core passes here once, before setting up its trap handler.
+    let core = unsafe { Core::boot() };
+
+    setup_trap_handler(&core);
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    {
+        aclint::init();
+        plic::init();
+        time::init();
+        ipi::init();
+    }
+
+    // Everything is set up, kernel time! (activating the environment will jump to the kernel)
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    // SAFETY: Trap handler is properly set up.
+    unsafe {
+        core.state.env.activate(info)
     }
+}
 
+/// The boot information collected by the primary core.
+///
+/// # Panics
+///
+/// When called before the primary core has collected it.
+///
+pub fn info() -> &'static BootInfo {
+    assert_eq!(
+        BOOT_BARRIER.load(Ordering::Acquire),
+        BARRIER_OPEN,
+        "boot info requested before boot finished"
+    );
+
+    // SAFETY: The barrier is open, so the boot info is initialized and never written again.
+    unsafe { (*&raw const BOOT_INFO).assume_init_ref() }
+}
+
+/// The boot information, or `None` before the primary core has collected it.
+pub fn try_info() -> Option<&'static BootInfo> {
+    (BOOT_BARRIER.load(Ordering::Acquire) == BARRIER_OPEN).then(info)
+}
+
+/// Zero the `.bss` section, must only be done once, by the primary core.
+fn clear_bss() {
     // SAFETY: We depend on the symbols being properly defined at link time.
     unsafe extern "C" {
         unsafe static mut __bss_start: u8;
@@ -34,22 +174,35 @@ fn setup() -> ! {
     };
 
     bss.fill(0);
+}
 
-    let state = CoreState::new();
-    let core = state.load();
-
-    setup_trap_handler(&core);
+/// The memory occupied by the kernel image, including the boot stacks.
+fn kernel_image() -> Region {
+    // SAFETY: We depend on the symbols being properly defined at link time.
+    unsafe extern "C" {
+        unsafe static __kernel_start: u8;
+        unsafe static __kernel_end: u8;
+    }
 
-    // Everything is set up, kernel time! (activating the environment will jump to the kernel)
-    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
-    // SAFETY: Trap handler is properly set up.
-    unsafe {
-        core.state.env.activate()
+    let start = &raw const __kernel_start as usize;
+    Region {
+        start,
+        size: &raw const __kernel_end as usize - start,
     }
 }
 
-fn park() -> ! {
-    loop {
-        interrupts::wait()
+/// The RAM after the kernel image according to the linker script, for machines whose device tree
+/// describes no memory.
+fn linker_ram() -> Region {
+    // SAFETY: We depend on the symbols being properly defined at link time.
+    unsafe extern "C" {
+        unsafe static __heap_start: u8;
+        unsafe static __heap_end: u8;
+    }
+
+    let start = &raw const __heap_start as usize;
+    Region {
+        start,
+        size: (&raw const __heap_end as usize).saturating_sub(start),
     }
 }
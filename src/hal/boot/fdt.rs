@@ -0,0 +1,484 @@
+//! A minimal, allocation free, parser for flattened device trees (FDT/DTB).
+//!
+//! See the [devicetree specification](https://devicetree-specification.readthedocs.io) for the
+//! format.
+
+use core::{ffi::CStr, slice, str};
+
+const MAGIC: u32 = 0xd00d_feed;
+const HEADER_SIZE: usize = 40;
+/// The version of the format we understand.
+const VERSION: u32 = 17;
+/// The oldest version whose blobs we still understand, the ones before it have another layout.
+const OLDEST_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+
+/// The reasons a device tree blob can be rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// No device tree was passed at all.
+    Null,
+    /// The blob does not start with the FDT magic number.
+    BadMagic,
+    /// The blob uses a version of the format we do not understand.
+    BadVersion,
+    /// The header points outside of the blob.
+    Truncated,
+}
+
+/// A parsed device tree blob.
+#[derive(Clone, Copy)]
+pub struct Fdt<'dtb> {
+    blob: &'dtb [u8],
+    structs: &'dtb [u8],
+    strings: &'dtb [u8],
+    reservations: &'dtb [u8],
+}
+
+/// A physical memory range.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: usize,
+    pub size: usize,
+}
+
+impl Region {
+    /// The first address after the region.
+    pub fn end(&self) -> usize {
+        self.start + self.size
+    }
+
+    /// The region, if it does not wrap around the end of the address space.
+    fn checked(self) -> Option<Region> {
+        self.start.checked_add(self.size).map(|_| self)
+    }
+}
+
+/// The `#address-cells` and `#size-cells` used to decode the `reg` property of a node.
+#[derive(Debug, Clone, Copy)]
+struct Cells {
+    address: usize,
+    size: usize,
+}
+
+impl Default for Cells {
+    /// The defaults mandated by the specification.
+    fn default() -> Cells {
+        Cells {
+            address: 2,
+            size: 1,
+        }
+    }
+}
+
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn be64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let bytes = bytes.get(offset..offset.checked_add(8)?)?;
+    Some(u64::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn c_str(bytes: &[u8], offset: usize) -> Option<&str> {
+    let bytes = CStr::from_bytes_until_nul(bytes.get(offset..)?).ok()?;
+    bytes.to_str().ok()
+}
+
+fn align4(offset: usize) -> Option<usize> {
+    Some(offset.checked_add(3)? & !3)
+}
+
+/// Read a big endian number made up of `cells` 32-bit cells.
+///
+/// Numbers that do not fit in a `usize` are truncated, which is fine for the machines we run on.
+///
+fn read_cells(bytes: &[u8], cells: usize) -> Option<usize> {
+    (0..cells).try_fold(0usize, |acc, cell| {
+        let cell = be32(bytes, cell.checked_mul(4)?)? as usize;
+        Some(acc.checked_shl(32).unwrap_or(0) | cell)
+    })
+}
+
+impl<'dtb> Fdt<'dtb> {
+    /// Parse the device tree blob at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be null, or point to memory that is readable for at least the size of the FDT
+    /// header, and for the `totalsize` it advertises if the magic number matches.
+    /// That memory must not be written to during `'dtb`.
+    ///
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Fdt<'dtb>, Error> {
+        if ptr.is_null() {
+            return Err(Error::Null);
+        }
+
+        // SAFETY: The caller guarantees the header is readable.
+        let header = unsafe { slice::from_raw_parts(ptr, HEADER_SIZE) };
+        if be32(header, 0) != Some(MAGIC) {
+            return Err(Error::BadMagic);
+        }
+
+        let size = be32(header, 4).ok_or(Error::Truncated)? as usize;
+        if size < HEADER_SIZE || (ptr as usize).checked_add(size).is_none() {
+            return Err(Error::Truncated);
+        }
+
+        // SAFETY: The caller guarantees the whole blob is readable.
+        Fdt::new(unsafe { slice::from_raw_parts(ptr, size) })
+    }
+
+    /// Parse a device tree blob.
+    pub fn new(blob: &'dtb [u8]) -> Result<Fdt<'dtb>, Error> {
+        let field = |index: usize| {
+            be32(blob, index * 4)
+                .map(|value| value as usize)
+                .ok_or(Error::Truncated)
+        };
+
+        if field(0)? as u32 != MAGIC {
+            return Err(Error::BadMagic);
+        }
+
+        // The blob must not be older than what we understand, nor need a newer parser.
+        let (version, last_compatible_version) = (field(5)? as u32, field(6)? as u32);
+        if version < OLDEST_VERSION || last_compatible_version > VERSION {
+            return Err(Error::BadVersion);
+        }
+
+        let size = field(1)?;
+        let blob = blob.get(..size).ok_or(Error::Truncated)?;
+        let section = |offset: usize, size: usize| {
+            offset
+                .checked_add(size)
+                .and_then(|end| blob.get(offset..end))
+                .ok_or(Error::Truncated)
+        };
+
+        let (struct_offset, strings_offset, reservations_offset) = (field(2)?, field(3)?, field(4)?);
+        let (strings_size, struct_size) = (field(8)?, field(9)?);
+
+        Ok(Fdt {
+            blob,
+            structs: section(struct_offset, struct_size)?,
+            strings: section(strings_offset, strings_size)?,
+            reservations: blob.get(reservations_offset..).ok_or(Error::Truncated)?,
+        })
+    }
+
+    /// The memory occupied by the blob itself.
+    pub fn region(&self) -> Region {
+        Region {
+            start: self.blob.as_ptr() as usize,
+            size: self.blob.len(),
+        }
+    }
+
+    /// The entries of the memory reservation block.
+    pub fn reservations(&self) -> impl Iterator<Item = Region> + use<'dtb> {
+        let block = self.reservations;
+        (0..)
+            .map(move |entry: usize| {
+                let offset = entry.checked_mul(16)?;
+                let start = be64(block, offset)?;
+                let size = be64(block, offset.checked_add(8)?)?;
+                Region {
+                    start: start as usize,
+                    size: size as usize,
+                }
+                .checked()
+            })
+            .map_while(|region| region.filter(|region| *region != Region::default()))
+    }
+
+    /// The root node of the tree.
+    pub fn root(&self) -> Option<Node<'dtb>> {
+        match self.tokens(0).next()? {
+            Token::BeginNode(name, body) => Some(Node {
+                fdt: *self,
+                name,
+                body,
+                cells: Cells::default(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Find a node by its absolute path (like `/soc/serial@10000000`) or alias (like `serial0`).
+    ///
+    /// Path components without a unit address match any node with that name.
+    ///
+    pub fn find(&self, path: &str) -> Option<Node<'dtb>> {
+        let root = self.root()?;
+        if let Some(path) = path.strip_prefix('/') {
+            return path
+                .split('/')
+                .filter(|component| !component.is_empty())
+                .try_fold(root, |node, component| node.child(component));
+        }
+
+        let alias = root.child("aliases")?.property(path)?.as_str()?;
+        if alias.starts_with('/') {
+            self.find(alias)
+        } else {
+            None
+        }
+    }
+
+    fn tokens(&self, offset: usize) -> Tokens<'dtb> {
+        Tokens { fdt: *self, offset }
+    }
+}
+
+enum Token<'dtb> {
+    /// The name of the node, and the offset of its first property or child.
+    BeginNode(&'dtb str, usize),
+    EndNode,
+    Property(Property<'dtb>),
+}
+
+/// Iterates over the tokens in the structure block, stops at `FDT_END` or malformed input.
+struct Tokens<'dtb> {
+    fdt: Fdt<'dtb>,
+    offset: usize,
+}
+
+impl<'dtb> Iterator for Tokens<'dtb> {
+    type Item = Token<'dtb>;
+
+    fn next(&mut self) -> Option<Token<'dtb>> {
+        let structs = self.fdt.structs;
+        loop {
+            let offset = self.offset;
+            let after = offset.checked_add(4)?;
+            let (token, next) = match be32(structs, offset)? {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(structs, after)?;
+                    let next = align4(after.checked_add(name.len())?.checked_add(1)?)?;
+                    (Token::BeginNode(name, next), next)
+                }
+                FDT_END_NODE => (Token::EndNode, after),
+                FDT_PROP => {
+                    let len = be32(structs, after)? as usize;
+                    let name_offset = be32(structs, after.checked_add(4)?)? as usize;
+                    let name = c_str(self.fdt.strings, name_offset)?;
+                    let start = after.checked_add(8)?;
+                    let end = start.checked_add(len)?;
+                    let value = structs.get(start..end)?;
+                    let property = Property { name, value };
+                    (Token::Property(property), align4(end)?)
+                }
+                FDT_NOP => {
+                    self.offset = after;
+                    continue;
+                }
+                _ => return None,
+            };
+
+            self.offset = next;
+            return Some(token);
+        }
+    }
+}
+
+/// A property of a node.
+#[derive(Clone, Copy)]
+pub struct Property<'dtb> {
+    pub name: &'dtb str,
+    pub value: &'dtb [u8],
+}
+
+impl<'dtb> Property<'dtb> {
+    /// The value as a single 32-bit cell.
+    pub fn as_u32(&self) -> Option<u32> {
+        be32(self.value, 0)
+    }
+
+    /// The value as a one or two cell number.
+    pub fn as_usize(&self) -> Option<usize> {
+        match self.value.len() {
+            4 | 8 => read_cells(self.value, self.value.len() / 4),
+            _ => None,
+        }
+    }
+
+    /// The value as a string, without the terminating nul.
+    pub fn as_str(&self) -> Option<&'dtb str> {
+        c_str(self.value, 0)
+    }
+
+    /// The value as a list of strings.
+    pub fn as_strs(&self) -> impl Iterator<Item = &'dtb str> + use<'dtb> {
+        self.value
+            .split(|byte| *byte == 0)
+            .filter(|string| !string.is_empty())
+            .filter_map(|string| str::from_utf8(string).ok())
+    }
+
+    /// The value as a list of 32-bit cells.
+    pub fn as_u32s(&self) -> impl Iterator<Item = u32> + use<'dtb> {
+        self.value
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+    }
+}
+
+/// A node in the tree.
+#[derive(Clone, Copy)]
+pub struct Node<'dtb> {
+    fdt: Fdt<'dtb>,
+    name: &'dtb str,
+    body: usize,
+    /// The cells of the parent, which describe our `reg` property.
+    cells: Cells,
+}
+
+impl<'dtb> Node<'dtb> {
+    /// The full name of the node, including the unit address.
+    pub fn name(&self) -> &'dtb str {
+        self.name
+    }
+
+    /// The properties of the node.
+    pub fn properties(&self) -> impl Iterator<Item = Property<'dtb>> + use<'dtb> {
+        self.fdt
+            .tokens(self.body)
+            .map_while(|token| match token {
+                Token::Property(property) => Some(property),
+                _ => None,
+            })
+    }
+
+    /// The property called `name`, if present.
+    pub fn property(&self, name: &str) -> Option<Property<'dtb>> {
+        self.properties().find(|property| property.name == name)
+    }
+
+    /// The direct children of the node.
+    pub fn children(&self) -> Children<'dtb> {
+        Children {
+            tokens: self.fdt.tokens(self.body),
+            cells: self.child_cells(),
+            done: false,
+        }
+    }
+
+    /// The child called `name`, `name` may leave out the unit address.
+    pub fn child(&self, name: &str) -> Option<Node<'dtb>> {
+        self.children().find(|child| {
+            child.name == name || child.name.split('@').next() == Some(name)
+        })
+    }
+
+    /// Whether the node is compatible with any of `compatible`.
+    pub fn is_compatible(&self, compatible: &[&str]) -> bool {
+        self.property("compatible")
+            .is_some_and(|property| property.as_strs().any(|value| compatible.contains(&value)))
+    }
+
+    /// Whether the node is usable, nodes without a `status` are.
+    pub fn is_enabled(&self) -> bool {
+        self.property("status")
+            .and_then(|property| property.as_str())
+            .is_none_or(|status| status == "okay" || status == "ok")
+    }
+
+    /// Find the first enabled node compatible with any of `compatible`, in this node or below.
+    pub fn find_compatible(&self, compatible: &[&str]) -> Option<Node<'dtb>> {
+        if self.is_enabled() && self.is_compatible(compatible) {
+            return Some(*self);
+        }
+
+        self.children()
+            .find_map(|child| child.find_compatible(compatible))
+    }
+
+    /// The regions of the `reg` property.
+    pub fn reg(&self) -> impl Iterator<Item = Region> + use<'dtb> {
+        let Cells { address, size } = self.cells;
+        // Cell counts that overflow are as malformed as no cells at all.
+        let entry = address
+            .checked_add(size)
+            .and_then(|cells| cells.checked_mul(4))
+            .unwrap_or(0);
+        let value = self
+            .property("reg")
+            .filter(|_| entry != 0)
+            .map_or(&[][..], |property| property.value);
+
+        value
+            .chunks_exact(entry.max(1))
+            .filter_map(move |entry| {
+                Region {
+                    start: read_cells(entry, address)?,
+                    size: read_cells(&entry[address * 4..], size)?,
+                }
+                .checked()
+            })
+    }
+
+    /// The cells used to decode the `reg` properties of our children.
+    fn child_cells(&self) -> Cells {
+        let default = Cells::default();
+        let cells = |name| {
+            self.property(name)
+                .and_then(|property| property.as_u32())
+                .map(|cells| cells as usize)
+        };
+
+        Cells {
+            address: cells("#address-cells").unwrap_or(default.address),
+            size: cells("#size-cells").unwrap_or(default.size),
+        }
+    }
+}
+
+/// Iterates over the direct children of a node.
+pub struct Children<'dtb> {
+    tokens: Tokens<'dtb>,
+    cells: Cells,
+    done: bool,
+}
+
+impl<'dtb> Iterator for Children<'dtb> {
+    type Item = Node<'dtb>;
+
+    fn next(&mut self) -> Option<Node<'dtb>> {
+        while !self.done {
+            match self.tokens.next() {
+                Some(Token::Property(_)) => {}
+                Some(Token::BeginNode(name, body)) => {
+                    // Skip over the subtree of the child, so the next call finds its sibling.
+                    let mut depth = 1usize;
+                    while depth > 0 {
+                        match self.tokens.next() {
+                            Some(Token::BeginNode(..)) => depth += 1,
+                            Some(Token::EndNode) => depth -= 1,
+                            Some(Token::Property(_)) => {}
+                            None => self.done = true,
+                        }
+                        if self.done {
+                            break;
+                        }
+                    }
+
+                    return Some(Node {
+                        fdt: self.tokens.fdt,
+                        name,
+                        body,
+                        cells: self.cells,
+                    });
+                }
+                Some(Token::EndNode) | None => self.done = true,
+            }
+        }
+
+        None
+    }
+}
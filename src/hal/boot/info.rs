@@ -0,0 +1,417 @@
+use core::ops::Deref;
+
+use crate::hal::board::MAX_HARTS;
+
+use super::{
+    fdt::{Fdt, Node, Region},
+    kernel_image, linker_ram,
+};
+
+/// The interrupt of the M-mode external interrupt, in the `interrupts-extended` of a PLIC.
+const MACHINE_EXTERNAL: u32 = 11;
+/// The interrupt of the S-mode external interrupt, in the `interrupts-extended` of a PLIC.
+const SUPERVISOR_EXTERNAL: u32 = 9;
+
+/// Compatible strings of the SiFive core-local interruptor, an MSWI and an MTIMER in one device.
+const CLINT: &[&str] = &["riscv,clint0", "sifive,clint0"];
+/// Compatible strings of the ACLINT devices.
+const ACLINT_MSWI: &[&str] = &["riscv,aclint-mswi"];
+const ACLINT_MTIMER: &[&str] = &["riscv,aclint-mtimer"];
+const ACLINT_SSWI: &[&str] = &["riscv,aclint-sswi"];
+
+/// Offsets into a SiFive CLINT.
+const CLINT_MTIMECMP: usize = 0x4000;
+const CLINT_MTIME: usize = 0xbff8;
+/// Compatible strings of the platform-level interrupt controller.
+const PLIC: &[&str] = &["riscv,plic0", "sifive,plic-1.0.0"];
+/// Compatible strings of the SiFive test device, which QEMU uses for shutting down and rebooting.
+const POWER: &[&str] = &["sifive,test0", "sifive,test1"];
+/// Compatible strings of the UARTs we know how to drive.
+const UART: &[&str] = &["ns16550a", "ns16550"];
+
+/// A list with a fixed capacity, entries that do not fit are dropped.
+#[derive(Debug, Clone, Copy)]
+pub struct List<T, const N: usize> {
+    items: [T; N],
+    len: usize,
+}
+
+impl<T: Copy + Default, const N: usize> List<T, N> {
+    fn new() -> List<T, N> {
+        List {
+            items: [T::default(); N],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, item: T) {
+        if let Some(slot) = self.items.get_mut(self.len) {
+            *slot = item;
+            self.len += 1;
+        }
+    }
+}
+
+impl<T, const N: usize> Deref for List<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.items[..self.len]
+    }
+}
+
+/// A hart described by the device tree.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Cpu {
+    /// The hart ID.
+    pub id: usize,
+    /// The `riscv,isa` string, like `rv64imafdc_zicsr`.
+    pub isa: &'static str,
+    /// The `mmu-type`, like `riscv,sv39`.
+    pub mmu_type: Option<&'static str>,
+}
+
+impl Cpu {
+    /// Whether the ISA string lists the multi-letter extension `name`, like `sstc`.
+    pub fn has_extension(&self, name: &str) -> bool {
+        self.isa
+            .split('_')
+            .skip(1)
+            .any(|extension| extension.eq_ignore_ascii_case(name))
+    }
+}
+
+/// The core-local interruptor devices, from an ACLINT or a SiFive CLINT.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Aclint {
+    /// The M-mode software interrupt device, with an `msip` register per hart.
+    pub mswi: Option<Region>,
+    /// The address of the `mtime` register.
+    pub mtime: Option<usize>,
+    /// The `mtimecmp` registers, one per hart.
+    pub mtimecmp: Option<Region>,
+    /// The S-mode software interrupt device, with a `setssip` register per hart.
+    pub sswi: Option<Region>,
+}
+
+/// The platform-level interrupt controller.
+#[derive(Debug, Clone, Copy)]
+pub struct Plic {
+    pub region: Region,
+    /// The amount of interrupt sources (`riscv,ndev`).
+    pub sources: u32,
+    /// The contexts wired to harts, from `interrupts-extended`.
+    pub contexts: List<PlicContext, { 2 * MAX_HARTS }>,
+}
+
+/// A PLIC context, through which a hart receives external interrupts in one mode.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct PlicContext {
+    /// The number of the context at the PLIC.
+    pub index: usize,
+    /// The hart ID.
+    pub hart: usize,
+    /// Whether these are M-mode external interrupts, instead of S-mode ones.
+    pub machine: bool,
+}
+
+/// A serial port.
+#[derive(Debug, Clone, Copy)]
+pub struct Uart {
+    pub region: Region,
+    /// The first compatible string of the device.
+    pub compatible: &'static str,
+    /// The interrupt line at the interrupt controller.
+    pub irq: Option<u32>,
+    /// The frequency of the input clock, in Hz.
+    pub clock_frequency: Option<u32>,
+}
+
+/// Everything the kernel needs to know about the machine it is running on.
+///
+/// Collected once by the primary core, and shared with the rest.
+///
+pub struct BootInfo {
+    /// The device tree passed by the firmware, if any.
+    pub fdt: Option<Fdt<'static>>,
+    /// The memory regions, or the RAM the linker script assumes if the device tree has none.
+    pub memory: List<Region, 8>,
+    /// Memory that must not be used, from the reservation block and `/reserved-memory`.
+    pub reserved: List<Region, 16>,
+    /// The memory occupied by the kernel image, including the boot stacks.
+    pub kernel: Region,
+    /// The initial ramdisk (`/chosen/linux,initrd-start` and `linux,initrd-end`).
+    pub initrd: Option<Region>,
+    /// The harts.
+    pub cpus: List<Cpu, MAX_HARTS>,
+    /// The frequency of the timebase (`mtime`), in Hz.
+    pub timebase_frequency: Option<u32>,
+    /// The command line (`/chosen/bootargs`).
+    pub bootargs: Option<&'static str>,
+    /// The path to the console device (`/chosen/stdout-path`), without options.
+    pub stdout_path: Option<&'static str>,
+    /// Entropy for randomizing where the kernel runs (`/chosen/kaslr-seed`, or `rng-seed` folded
+    /// into one number).
+    pub kaslr_seed: Option<u64>,
+    pub aclint: Aclint,
+    pub plic: Option<Plic>,
+    /// The console, or the first UART if no console was chosen.
+    pub uart: Option<Uart>,
+    /// The device used for shutting down and rebooting.
+    pub power: Option<Region>,
+}
+
+impl BootInfo {
+    /// Collect the boot information from the device tree.
+    ///
+    /// Without a device tree, everything but the memory the linker script assumes and the kernel
+    /// image is left empty.
+    ///
+    pub fn new(fdt: Option<Fdt<'static>>) -> BootInfo {
+        let mut info = BootInfo {
+            fdt,
+            memory: List::new(),
+            reserved: List::new(),
+            kernel: kernel_image(),
+            initrd: None,
+            cpus: List::new(),
+            timebase_frequency: None,
+            bootargs: None,
+            stdout_path: None,
+            kaslr_seed: None,
+            aclint: Aclint::default(),
+            plic: None,
+            uart: None,
+            power: None,
+        };
+
+        let Some((fdt, root)) = fdt.and_then(|fdt| Some((fdt, fdt.root()?))) else {
+            info.memory.push(linker_ram());
+            return info;
+        };
+
+        for node in root.children() {
+            let is_memory = node
+                .property("device_type")
+                .and_then(|property| property.as_str())
+                == Some("memory");
+
+            if is_memory && node.is_enabled() {
+                node.reg().for_each(|region| info.memory.push(region));
+            }
+        }
+
+        if info.memory.is_empty() {
+            info.memory.push(linker_ram());
+        }
+
+        fdt.reservations()
+            .for_each(|region| info.reserved.push(region));
+        if let Some(reserved) = root.child("reserved-memory") {
+            reserved
+                .children()
+                .flat_map(|node| node.reg())
+                .for_each(|region| info.reserved.push(region));
+        }
+
+        // The phandles of the interrupt controllers of the harts, which other devices refer to.
+        let mut controllers = List::<(u32, usize), MAX_HARTS>::new();
+
+        if let Some(cpus) = root.child("cpus") {
+            let frequency = |node: &Node| {
+                node.property("timebase-frequency")
+                    .and_then(|property| property.as_u32())
+            };
+
+            // Usually set once for all harts, but it may also be set on each of them.
+            info.timebase_frequency = frequency(&cpus);
+
+            for cpu in cpus.children().filter(|node| is_cpu(node)) {
+                info.timebase_frequency = info.timebase_frequency.or_else(|| frequency(&cpu));
+
+                let Some(id) = cpu.reg().next() else {
+                    continue;
+                };
+
+                let phandle = cpu
+                    .child("interrupt-controller")
+                    .and_then(|node| node.property("phandle"))
+                    .and_then(|property| property.as_u32());
+                if let Some(phandle) = phandle {
+                    controllers.push((phandle, id.start));
+                }
+
+                let string = |name| cpu.property(name).and_then(|property| property.as_str());
+                info.cpus.push(Cpu {
+                    id: id.start,
+                    isa: string("riscv,isa").unwrap_or_default(),
+                    mmu_type: string("mmu-type"),
+                });
+            }
+        }
+
+        if let Some(chosen) = root.child("chosen") {
+            let string = |name| chosen.property(name).and_then(|property| property.as_str());
+
+            info.bootargs = string("bootargs").filter(|args| !args.is_empty());
+            info.stdout_path = string("stdout-path")
+                .or_else(|| string("linux,stdout-path"))
+                .and_then(|path| path.split(':').next());
+
+            info.kaslr_seed = chosen
+                .property("kaslr-seed")
+                .and_then(|property| property.as_usize())
+                .map(|seed| seed as u64)
+                .or_else(|| {
+                    let cells = chosen.property("rng-seed")?.as_u32s();
+                    Some(cells.fold(0, |seed, cell| {
+                        (seed ^ cell as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+                    }))
+                });
+
+            let address = |name| {
+                chosen
+                    .property(name)
+                    .and_then(|property| property.as_usize())
+            };
+            info.initrd = address("linux,initrd-start")
+                .zip(address("linux,initrd-end"))
+                .filter(|(start, end)| start < end)
+                .map(|(start, end)| Region {
+                    start,
+                    size: end - start,
+                });
+        }
+
+        info.aclint = aclint(&root);
+
+        info.plic = root.find_compatible(PLIC).and_then(|node| {
+            Some(Plic {
+                region: node.reg().next()?,
+                sources: node.property("riscv,ndev")?.as_u32()?,
+                contexts: plic_contexts(&node, &controllers),
+            })
+        });
+
+        info.power = root
+            .find_compatible(POWER)
+            .and_then(|node| node.reg().next());
+
+        let console = info
+            .stdout_path
+            .and_then(|path| fdt.find(path))
+            .filter(|node| node.is_compatible(UART));
+        info.uart = console
+            .or_else(|| root.find_compatible(UART))
+            .and_then(|node| uart(&node));
+
+        info
+    }
+}
+
+fn is_cpu(node: &Node) -> bool {
+    node.is_enabled()
+        && node
+            .property("device_type")
+            .and_then(|property| property.as_str())
+            == Some("cpu")
+}
+
+fn aclint(root: &Node) -> Aclint {
+    let region = |compatible| {
+        root.find_compatible(compatible)
+            .and_then(|node| node.reg().next())
+    };
+
+    let mut aclint = Aclint {
+        mswi: region(ACLINT_MSWI),
+        sswi: region(ACLINT_SSWI),
+        ..Aclint::default()
+    };
+
+    // An MTIMER has a region for `mtime` and one for the `mtimecmp` registers, in either order.
+    if let Some(node) = root.find_compatible(ACLINT_MTIMER) {
+        for region in node.reg() {
+            if region.size == 8 {
+                aclint.mtime = Some(region.start);
+            } else {
+                aclint.mtimecmp = Some(region);
+            }
+        }
+    }
+
+    // A CLINT too small for `mtime` is not one.
+    let clint = region(CLINT).filter(|clint| clint.size >= CLINT_MTIME + 8);
+    let registers = clint.and_then(|clint| {
+        Some((
+            clint.start,
+            clint.start.checked_add(CLINT_MTIMECMP)?,
+            clint.start.checked_add(CLINT_MTIME)?,
+        ))
+    });
+
+    if let Some((mswi, mtimecmp, mtime)) = registers {
+        aclint.mswi = aclint.mswi.or(Some(Region {
+            start: mswi,
+            size: CLINT_MTIMECMP,
+        }));
+        aclint.mtimecmp = aclint.mtimecmp.or(Some(Region {
+            start: mtimecmp,
+            size: CLINT_MTIME - CLINT_MTIMECMP,
+        }));
+        aclint.mtime = aclint.mtime.or(Some(mtime));
+    }
+
+    aclint
+}
+
+/// Decode the `interrupts-extended` of a PLIC, every context is a pair of the phandle of a hart's
+/// interrupt controller and the interrupt it raises there.
+fn plic_contexts(
+    node: &Node,
+    controllers: &[(u32, usize)],
+) -> List<PlicContext, { 2 * MAX_HARTS }> {
+    let mut contexts = List::new();
+    let Some(property) = node.property("interrupts-extended") else {
+        return contexts;
+    };
+
+    let mut cells = property.as_u32s();
+    let mut index = 0;
+    while let (Some(phandle), Some(interrupt)) = (cells.next(), cells.next()) {
+        let hart = controllers
+            .iter()
+            .find(|(controller, _)| *controller == phandle)
+            .map(|(_, hart)| *hart);
+
+        // Unused contexts refer to no hart, or to an interrupt we do not take.
+        let machine = match interrupt {
+            MACHINE_EXTERNAL => Some(true),
+            SUPERVISOR_EXTERNAL => Some(false),
+            _ => None,
+        };
+
+        if let (Some(hart), Some(machine)) = (hart, machine) {
+            contexts.push(PlicContext {
+                index,
+                hart,
+                machine,
+            });
+        }
+
+        index += 1;
+    }
+
+    contexts
+}
+
+fn uart(node: &Node<'static>) -> Option<Uart> {
+    let cell = |name| node.property(name).and_then(|property| property.as_u32());
+
+    Some(Uart {
+        region: node.reg().next()?,
+        compatible: node.property("compatible")?.as_strs().next()?,
+        irq: cell("interrupts"),
+        clock_frequency: cell("clock-frequency"),
+    })
+}
@@ -1,7 +1,123 @@
-use core::arch::naked_asm;
+use core::{
+    arch::{asm, naked_asm},
+    ptr::read_volatile,
+    slice,
+};
+#[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
+use crate::hal::board::{HART_STACK_SIZE, MAX_HARTS};
+#[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+use crate::hal::{
+    boot::{Cpu, Region},
+    frames,
+    mmu::{self, AddressSpace, Flags, PAGE_SIZE, PagingMode},
+};
+#[cfg(feature = "riscv_sbi")]
+use crate::hal::{
+    core::id,
+    sbi::{base, hsm},
+};
+
+#[cfg(feature = "riscv_sbi")]
+use super::BootInfo;
 use super::setup;
 
+/// The type of the relocations that do nothing.
+const R_RISCV_NONE: usize = 0;
+/// The type of the relocations that add where the image runs to their addend, the only ones of a
+/// PIE that imports nothing.
+const R_RISCV_RELATIVE: usize = 3;
+
+/// The alignment of the randomized address of the kernel, which keeps megapages usable.
+#[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+const KASLR_ALIGN: usize = 2 << 20;
+
+/// An entry of `.rela.dyn`.
+#[repr(C)]
+struct Rela {
+    offset: usize,
+    info: usize,
+    addend: usize,
+}
+
+/// A link-time address.
+struct LinkAddress(*const u8);
+
+// SAFETY: It is never written, nor dereferenced.
+unsafe impl Sync for LinkAddress {}
+
+// SAFETY: We depend on the symbols being properly defined at link time.
+unsafe extern "C" {
+    unsafe static __kernel_link: u8;
+}
+
+/// The address the image is linked at. As `__kernel_link` is an absolute symbol, the linker fills
+/// it in, instead of leaving it to a relocation.
+static KERNEL_LINK: LinkAddress = LinkAddress(&raw const __kernel_link);
+
+/// The `satp` of the address space the kernel moves to, `0` while it stays where it was loaded.
+#[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+static KERNEL_SATP: AtomicUsize = AtomicUsize::new(0);
+/// How far the kernel moves, from its physical to its virtual address.
+#[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+static KERNEL_DELTA: AtomicUsize = AtomicUsize::new(0);
+/// Where the address space the kernel moves to maps physical memory.
+#[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+static PHYSICAL_MAP: AtomicUsize = AtomicUsize::new(0);
+
+/// The kernel entry point, every hart starts here.
+///
+/// Each hart gets its own boot stack, hart `n` uses the `n`th [`HART_STACK_SIZE`] sized stack
+/// counting down from `__stack_start`. Harts with an ID of [`MAX_HARTS`] or higher are parked.
+///
+/// The firmware passes the hart ID in `a0` and the device tree in `a1`, these are left untouched
+/// so they end up as the arguments of [`setup`]. The hart ID is also kept in `tp` for
+/// [`hart_id`](crate::hal::core::id), as `mhartid` is not accessible once the kernel runs in S-mode.
+/// `mscratch` is cleared, so no core state is loaded until the boot code loads one. Addresses are
+/// taken relative to the code, as the image is not relocated yet.
+///
+#[cfg(not(feature = "riscv_sbi"))]
+#[unsafe(no_mangle)]
+#[unsafe(naked)]
+#[unsafe(link_section = ".text.init")]
+extern "C" fn _start() -> ! {
+    #[allow(unused_unsafe)]
+    unsafe {
+        naked_asm!(
+            ".option push",
+            ".option norelax",
+            "lla gp, __global_pointer$",
+            ".option pop",
+            "csrr t0, mhartid",
+            "li t1, {max_harts}",
+            "bgeu t0, t1, 2f",
+            "mv tp, t0",
+            "csrw mscratch, zero",
+            "slli t0, t0, {stack_shift}",
+            "lla sp, __stack_start",
+            "sub sp, sp, t0",
+            "j {}",
+            "2:",
+            "wfi",
+            "j 2b",
+            sym setup,
+            max_harts = const MAX_HARTS,
+            stack_shift = const HART_STACK_SIZE.trailing_zeros(),
+        )
+    };
+}
+
+/// The kernel entry point when running as an S-mode payload, every hart starts here.
+///
+/// Like the M-mode entry point, but the hart ID comes from `a0` since `mhartid` is not accessible.
+/// It is kept in `tp` for [`hart_id`](crate::hal::core::id). `sscratch` is cleared, so no core
+/// state is loaded until the boot code loads one.
+///
+#[cfg(feature = "riscv_sbi")]
 #[unsafe(no_mangle)]
 #[unsafe(naked)]
 #[unsafe(link_section = ".text.init")]
@@ -11,11 +127,406 @@ extern "C" fn _start() -> ! {
         naked_asm!(
             ".option push",
             ".option norelax",
-            "la gp, __global_pointer$",
+            "lla gp, __global_pointer$",
             ".option pop",
-            "la sp, __stack_start",
+            "li t1, {max_harts}",
+            "bgeu a0, t1, 2f",
+            "mv tp, a0",
+            "csrw sscratch, zero",
+            "slli t0, a0, {stack_shift}",
+            "lla sp, __stack_start",
+            "sub sp, sp, t0",
             "j {}",
+            "2:",
+            "wfi",
+            "j 2b",
             sym setup,
+            max_harts = const MAX_HARTS,
+            stack_shift = const HART_STACK_SIZE.trailing_zeros(),
+        )
+    };
+}
+
+/// Ask the firmware to start every other hart at [`_start`], passing `dtb` along.
+///
+/// Firmware without the HSM extension starts every hart by itself, those harts will already be
+/// waiting at the boot barrier.
+///
+#[cfg(feature = "riscv_sbi")]
+pub(super) fn start_secondary_harts(info: &BootInfo, dtb: *const u8) {
+    if !base::probe_extension(hsm::EID) {
+        return;
+    }
+
+    let primary = id();
+    for cpu in info.cpus.iter() {
+        if cpu.id == primary || cpu.id >= MAX_HARTS {
+            continue;
+        }
+
+        // SAFETY: `_start` is the entry point for every hart.
+        // Harts that fail to start simply do not join, the kernel works with the ones that do.
+        let _ = unsafe { hsm::hart_start(cpu.id, physical(_start as usize), dtb as usize) };
+    }
+}
+
+/// The physical address of `address` in the kernel image.
+#[cfg(feature = "riscv_sbi")]
+fn physical(address: usize) -> usize {
+    #[cfg(target_pointer_width = "64")]
+    let address = address - KERNEL_DELTA.load(Ordering::Relaxed);
+    address
+}
+
+/// Apply the relocations of the image for the address it runs at.
+///
+/// The image is found through `lla`, which works before anything is relocated. Applying them again
+/// once the image runs elsewhere updates the addresses for the new place. Stops the hart on a
+/// relocation that is not [`R_RISCV_RELATIVE`].
+///
+/// # Safety
+///
+/// MUST only run on one hart, while nothing uses the addresses stored in the image. The code before
+/// it must not use any either, so no function pointers, trait objects or references in statics.
+///
+pub(super) unsafe fn relocate() {
+    let (start, rela_start, rela_end): (usize, usize, usize);
+
+    // SAFETY: Computing addresses has no side effects.
+    unsafe {
+        asm!(
+            "lla {start}, __kernel_start",
+            "lla {rela_start}, __rela_start",
+            "lla {rela_end}, __rela_end",
+            start = out(reg) start,
+            rela_start = out(reg) rela_start,
+            rela_end = out(reg) rela_end,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+
+    // SAFETY: The static is valid. Reading it as volatile keeps the compiler from replacing it
+    // with a reference to the absolute symbol, which a PIE can not address relative to the code.
+    let link = unsafe { read_volatile(&raw const KERNEL_LINK) }.0 as usize;
+    let offset = start.wrapping_sub(link);
+
+    // SAFETY: The linker script puts the relocations between the symbols.
+    let relocations = unsafe {
+        slice::from_raw_parts(
+            rela_start as *const Rela,
+            rela_end.wrapping_sub(rela_start) / size_of::<Rela>(),
         )
     };
+
+    for rela in relocations {
+        match rela.info {
+            R_RISCV_NONE => continue,
+            R_RISCV_RELATIVE => {}
+            // The image would run with broken addresses. Nothing can be reported this early, even
+            // panicking needs them, so the boot stops here and the other harts wait forever.
+            _ => loop {
+                // SAFETY: Waiting for an interrupt has no side effects.
+                unsafe { asm!("wfi") }
+            },
+        }
+
+        let location = rela.offset.wrapping_add(offset) as *mut usize;
+
+        // SAFETY: The relocations only refer to the image, which is ours.
+        unsafe { location.write(rela.addend.wrapping_add(offset)) }
+    }
+}
+
+/// Map the kernel to a randomized address in the higher half, for [`enter_kernel_space`].
+///
+/// Physical memory is mapped at the start of the higher half, and the kernel image somewhere in
+/// the last quarter of the address space, its text executable, its read-only data read-only. Only
+/// the devices and the device tree stay identity mapped, as the kernel reaches them at their
+/// physical address. The kernel stays where it is if the MMU is not usable, or the address space
+/// can not be built.
+///
+/// Only RV64 kernels under an SBI firmware move. Without one (`-bios none`) the S-mode kernel
+/// shares the image with the built-in firmware, which runs in M-mode without translation, and the
+/// relocations can only make the image work at one address. Sv32 leaves no room for a higher
+/// half, RAM usually sits in the upper half of the 32 bit address space itself.
+///
+#[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+pub(super) fn map_kernel_space(info: &BootInfo) {
+    let hart = id();
+    let cpu = info.cpus.iter().find(|cpu| cpu.id == hart);
+    let Some(mode) = PagingMode::from_mmu_type(cpu.and_then(|cpu| cpu.mmu_type)) else {
+        return;
+    };
+
+    let bits = mode.address_bits();
+    let upper = usize::MAX << (bits - 1);
+    let quarter = 1 << (bits - 2);
+
+    // Physical memory must fit below the kernel.
+    if info.memory.iter().any(|region| region.end() > quarter) {
+        return;
+    }
+
+    let image = Region {
+        start: info.kernel.start,
+        size: info.kernel.size.next_multiple_of(PAGE_SIZE),
+    };
+    let slots = (quarter - image.size) / KASLR_ALIGN;
+    let base = upper + quarter + (seed(info, cpu) % slots as u64) as usize * KASLR_ALIGN;
+
+    let mut frames = frames::Global;
+    let mut map = || -> Result<AddressSpace, mmu::Error> {
+        let mut space = AddressSpace::new(mode, 0, &mut frames)?;
+        let data = Flags::READ | Flags::WRITE | Flags::GLOBAL;
+
+        for region in device_regions(info).into_iter().flatten() {
+            let flags = Flags::READ | Flags::WRITE;
+            space.map_range(region.start, region.start, region.size, flags, &mut frames)?;
+        }
+        if let Some(fdt) = &info.fdt {
+            let dtb = pages(fdt.region());
+            space.map_range(dtb.start, dtb.start, dtb.size, Flags::READ, &mut frames)?;
+        }
+
+        for region in info.memory.iter() {
+            let region = pages(*region);
+            space.map_range(
+                upper + region.start,
+                region.start,
+                region.size,
+                data,
+                &mut frames,
+            )?;
+        }
+
+        for (region, flags) in image_sections(image) {
+            let offset = region.start - image.start;
+            space.map_range(base + offset, region.start, region.size, flags, &mut frames)?;
+        }
+        Ok(space)
+    };
+
+    let Ok(space) = map() else {
+        return;
+    };
+
+    KERNEL_DELTA.store(base - image.start, Ordering::Relaxed);
+    PHYSICAL_MAP.store(upper, Ordering::Relaxed);
+    KERNEL_SATP.store(space.satp(), Ordering::Release);
+}
+
+/// `region`, grown to whole pages.
+#[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+fn pages(region: Region) -> Region {
+    let start = region.start / PAGE_SIZE * PAGE_SIZE;
+    Region {
+        start,
+        size: region.end().next_multiple_of(PAGE_SIZE) - start,
+    }
+}
+
+/// The regions of the devices the kernel uses, grown to whole pages and merged where they
+/// overlap, as devices may share pages.
+#[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+fn device_regions(info: &BootInfo) -> [Option<Region>; 7] {
+    let aclint = &info.aclint;
+    let mtime = aclint.mtime.map(|start| Region { start, size: 8 });
+    let regions = [
+        aclint.mswi,
+        mtime,
+        aclint.mtimecmp,
+        aclint.sswi,
+        info.plic.as_ref().map(|plic| plic.region),
+        info.uart.map(|uart| uart.region),
+        info.power,
+    ];
+
+    let mut merged = [None; 7];
+    for mut region in regions.into_iter().flatten().map(pages) {
+        // Absorbing a region can make it overlap one that was checked already, so start over.
+        while let Some(slot) = merged.iter_mut().find(|slot| {
+            slot.is_some_and(|other: Region| {
+                other.start <= region.end() && region.start <= other.end()
+            })
+        }) {
+            let other = slot.take().unwrap();
+            let start = region.start.min(other.start);
+            region = Region {
+                start,
+                size: region.end().max(other.end()) - start,
+            };
+        }
+
+        if let Some(slot) = merged.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(region);
+        }
+    }
+
+    merged
+}
+
+/// The sections of the kernel `image` at its physical address, and how they are mapped.
+///
+/// The linker script aligns them to pages. The data runs up to the end of the image, including the
+/// boot stacks.
+///
+#[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+fn image_sections(image: Region) -> [(Region, Flags); 3] {
+    // SAFETY: We depend on the symbols being properly defined at link time.
+    unsafe extern "C" {
+        unsafe static __text_end: u8;
+        unsafe static __data_start: u8;
+    }
+
+    let text_end = &raw const __text_end as usize;
+    let data_start = &raw const __data_start as usize;
+    let between = |start: usize, end: usize| Region {
+        start,
+        size: end - start,
+    };
+
+    [
+        (
+            between(image.start, text_end),
+            Flags::READ | Flags::EXECUTE | Flags::GLOBAL,
+        ),
+        (between(text_end, data_start), Flags::READ | Flags::GLOBAL),
+        (
+            between(data_start, image.end()),
+            Flags::READ | Flags::WRITE | Flags::GLOBAL,
+        ),
+    ]
+}
+
+/// The seed for the address of the kernel, from the device tree and the Zkr `seed` CSR.
+///
+/// The time since reset is mixed in as well, which is all there is on machines without either.
+///
+#[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+fn seed(info: &BootInfo, cpu: Option<&Cpu>) -> u64 {
+    let mut seed = info.kaslr_seed.unwrap_or_default();
+
+    if cpu.is_some_and(|cpu| cpu.has_extension("zkr")) {
+        seed ^= zkr_seed().unwrap_or_default();
+    }
+
+    let time: u64;
+    // SAFETY: Reading the time has no side effects.
+    unsafe { asm!("rdtime {}", out(reg) time, options(nomem, nostack)) };
+    seed ^= time;
+
+    // The finalizer of SplitMix64, so every bit of the seed affects the address.
+    seed = (seed ^ (seed >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    seed = (seed ^ (seed >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    seed ^ (seed >> 31)
+}
+
+/// 64 bits from the entropy source of the Zkr extension, or `None` if it failed.
+///
+/// The firmware must allow S-mode to access `seed` (through `mseccfg.SSEED`), which OpenSBI does
+/// on harts with Zkr.
+///
+#[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+fn zkr_seed() -> Option<u64> {
+    /// `seed.OPST`, the state of the entropy source.
+    const OPST_SHIFT: usize = 30;
+    const ES16: usize = 0b10;
+    const DEAD: usize = 0b11;
+    /// The polls before giving up on an entropy source that keeps waiting.
+    const POLLS: usize = 1 << 16;
+
+    let mut seed = 0;
+    let mut bits = 0;
+
+    for _ in 0..POLLS {
+        let value: usize;
+        // SAFETY: The hart has Zkr. `seed` must be accessed with a write, which is ignored.
+        unsafe { asm!("csrrw {}, 0x015, zero", out(reg) value, options(nomem, nostack)) };
+
+        match (value >> OPST_SHIFT) & 0b11 {
+            ES16 => {
+                seed = seed << 16 | (value & 0xffff) as u64;
+                bits += 16;
+                if bits == 64 {
+                    return Some(seed);
+                }
+            }
+            DEAD => return None,
+            _ => spin_loop(),
+        }
+    }
+
+    None
+}
+
+/// Switch the current hart to the address space of the kernel, and continue in `next` there.
+///
+/// The stack and global pointers move along, the stack frames of the callers are left behind. The
+/// image is not identity mapped, so fetching the next instruction after switching faults, and the
+/// hart continues at the trap vector instead, which is set to where that instruction is mapped.
+///
+/// # Safety
+///
+/// The image MUST be relocated, and the kernel space mapped if it moves. `next` MUST be in the
+/// image.
+///
+pub(super) unsafe fn enter_kernel_space(
+    hart_id: usize,
+    dtb: *const u8,
+    next: extern "C" fn(usize, *const u8) -> !,
+) -> ! {
+    #[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+    {
+        let satp = KERNEL_SATP.load(Ordering::Acquire);
+        if satp != 0 {
+            // SAFETY: The caller guarantees the address space maps the image. The firmware
+            // delegates instruction page faults, and the trap handler is set up later on.
+            unsafe {
+                asm!(
+                    "lla {vector}, 1f",
+                    "add {vector}, {vector}, {delta}",
+                    "csrw stvec, {vector}",
+                    "add sp, sp, {delta}",
+                    "add gp, gp, {delta}",
+                    "add {next}, {next}, {delta}",
+                    "sfence.vma",
+                    "csrw satp, {satp}",
+                    ".balign 4", // the vector must be aligned
+                    "1:",
+                    "sfence.vma",
+                    "jr {next}",
+                    satp = in(reg) satp,
+                    delta = in(reg) KERNEL_DELTA.load(Ordering::Relaxed),
+                    next = in(reg) next,
+                    vector = in(reg) 0usize,
+                    in("a0") hart_id,
+                    in("a1") dtb,
+                    options(noreturn),
+                )
+            }
+        }
+    }
+
+    next(hart_id, dtb)
+}
+
+/// Finish moving the kernel on the primary hart, once it runs in the kernel space.
+///
+/// The relocations are applied again for the new addresses, and physical memory is reached through
+/// its mapping in the higher half from now on.
+///
+/// # Safety
+///
+/// Like [`relocate`], and MUST be called after [`enter_kernel_space`].
+///
+pub(super) unsafe fn finish_move() {
+    #[cfg(all(feature = "riscv_sbi", target_pointer_width = "64"))]
+    if KERNEL_SATP.load(Ordering::Acquire) != 0 {
+        // SAFETY: The caller guarantees nothing uses the addresses, and every core maps physical
+        // memory there once it moved.
+        unsafe {
+            relocate();
+            mmu::set_physical_offset(PHYSICAL_MAP.load(Ordering::Relaxed));
+        }
+    }
 }
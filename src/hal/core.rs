@@ -1,80 +1,292 @@
+use core::{cell::UnsafeCell, marker::PhantomData, mem::MaybeUninit};
+
 use crate::handle_trap;
 
-use super::trap::{Trap, TrapHandler};
+use super::{
+    board::MAX_HARTS,
+    interrupts::{Handlers, without_interrupts},
+    trap::{Resume, Trap, TrapFrame, TrapHandler, TrapStacks},
+};
+
+pub use percpu::PerCore;
+pub(crate) use percpu::percpu;
 
+mod percpu;
 #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
 mod riscv;
 
+/// Get the ID of the currently running core.
+pub fn id() -> usize {
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    riscv::hart_id()
+}
+
 /// Check whether the currently running core is the primary one of the system.
 pub fn is_primary_core() -> bool {
     #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
     riscv::is_primary_hart()
 }
 
+/// The mode the kernel runs in.
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub(crate) fn kernel_mode() -> super::execution::riscv::Mode {
+    riscv::kernel_mode()
+}
+
+/// The state loaded on the currently running core, if any.
+fn loaded() -> Option<&'static CoreState> {
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    let state = riscv::loaded_state() as *const CoreState;
+
+    // SAFETY: Only `'static` states are ever loaded.
+    unsafe { state.as_ref() }
+}
+
+/// Call `f` with the state loaded on the currently running core.
+///
+/// # Panics
+///
+/// If no state is loaded, which is only the case before `hal::boot` sets up the core.
+///
+pub fn with_current<R>(f: impl FnOnce(&CoreState) -> R) -> R {
+    f(loaded().expect("no core state loaded"))
+}
+
 #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
 type Env = super::execution::riscv::ExecutionEnvironment;
 
 /// The state of a core.
+///
+/// The trap entry code finds it through the scratch registers while it is loaded, so only
+/// `'static` states can be loaded.
+///
 pub struct CoreState {
-    /// The trap handler for the core.
+    /// The trap handler for the core, for traps without a registered handler.
     pub trap_handler: TrapHandler,
+    /// The interrupt and exception handlers registered on the core.
+    pub handlers: Handlers,
     /// The execution environment of the core.
     pub env: Env,
+    /// Where the trap entry code switches to, for traps from less privileged modes.
+    pub(crate) trap_stacks: TrapStacks,
+    /// The core the state was created for, the only one it can be loaded on.
+    core: usize,
 }
 
-/// A handle to a core, ensures the [`CoreState`] is loaded during its lifetime.
-pub struct Core<'core> {
+/// Storage for a [`CoreState`] of every core, written once by the core itself.
+struct States([UnsafeCell<MaybeUninit<CoreState>>; MAX_HARTS]);
+
+// SAFETY: A core only writes its own state, before anything refers to it.
+unsafe impl Sync for States {}
+
+impl States {
+    const fn new() -> States {
+        States([const { UnsafeCell::new(MaybeUninit::uninit()) }; MAX_HARTS])
+    }
+
+    /// Store `state` as the one of the current core.
+    ///
+    /// # Safety
+    ///
+    /// MUST only be called once per core.
+    ///
+    unsafe fn init(&'static self, state: CoreState) -> &'static CoreState {
+        // SAFETY: The caller guarantees nothing refers to the state of the core yet.
+        unsafe { (*self.0[id()].get()).write(state) }
+    }
+}
+
+/// The states loaded while no other state is, their trap handler is [`fallback_trap_handler`].
+static FALLBACK_STATES: States = States::new();
+/// The states the cores boot with.
+static BOOT_STATES: States = States::new();
+
+/// Handles the traps taken while no other state is loaded, which the kernel never expects.
+fn fallback_trap_handler(_trap: Trap, _frame: &mut TrapFrame) -> Resume {
+    Resume::Fatal
+}
+
+/// A handle to a loaded [`CoreState`], dropping it loads the state that was loaded before.
+///
+/// Handles nest, but must be dropped in the reverse order they were created in. As they belong to
+/// the core they were created on, they can not be sent to other threads.
+///
+pub struct Core {
     /// The state of the core.
-    pub state: &'core CoreState,
+    pub state: &'static CoreState,
+    /// The state to load again once this one is done.
+    previous: &'static CoreState,
+    _core_local: PhantomData<*const ()>,
 }
 
-impl Drop for Core<'_> {
-    fn drop(&mut self) {
-        use ::riscv::register::mscratch;
+impl Core {
+    /// Set up the states of the current core, and load the one it boots with.
+    ///
+    /// Once the boot state is unloaded, the fallback state is loaded instead of none at all. So the
+    /// trap entry code always finds a state.
+    ///
+    /// # Safety
+    ///
+    /// MUST be called once per core, before its trap handler is set up.
+    ///
+    pub(crate) unsafe fn boot() -> Core {
+        let fallback = CoreState {
+            trap_handler: fallback_trap_handler,
+            ..CoreState::new()
+        };
 
-        let state = unsafe { &*(mscratch::read() as *mut CoreState) };
-        unsafe {
-            mscratch::write(0);
-        }
+        // SAFETY: The caller guarantees this is the only time, so nothing refers to them yet.
+        let (fallback, state) = unsafe {
+            (
+                FALLBACK_STATES.init(fallback),
+                BOOT_STATES.init(CoreState::new()),
+            )
+        };
 
-        if state.env.kernel == super::execution::riscv::Mode::Supervisor {
-            unsafe {
-                use ::riscv::register::sscratch;
-                sscratch::write(0);
-            }
-        }
+        fallback.install();
+        state.load()
+    }
+}
+
+impl Drop for Core {
+    fn drop(&mut self) {
+        self.previous.install()
     }
 }
 
 impl CoreState {
+    /// Create a state for the current core.
     pub fn new() -> CoreState {
+        let core = id();
         CoreState {
             trap_handler: handle_trap,
+            handlers: Handlers::new(),
             env: Env::new(),
+            trap_stacks: TrapStacks::new(core),
+            core,
         }
     }
 
-    pub fn load<'core>(&'core self) -> Core<'core> {
-        let raw = self as *const _ as usize;
+    /// Load the state on the current core, until the returned handle is dropped.
+    ///
+    /// Interrupt sources without a handler on the state keep the one of the state loaded before.
+    ///
+    /// # Panics
+    ///
+    /// If the state was created for another core, or the core has not booted yet.
+    ///
+    pub fn load(&'static self) -> Core {
+        assert_eq!(
+            self.core,
+            id(),
+            "core state of core {} loaded on another core",
+            self.core
+        );
+
+        let previous = loaded().expect("core states are not set up yet");
+        // The interrupts of the core must not see the handlers change halfway.
+        without_interrupts(|| {
+            self.handlers.inherit(&previous.handlers);
+            self.install();
+        });
+
+        Core {
+            state: self,
+            previous,
+            _core_local: PhantomData,
+        }
+    }
 
-        // SAFETY: The lifetime ensures this will never point to an invalid state
+    /// Point the scratch registers at the state, for the trap entry code.
+    fn install(&'static self) {
+        // SAFETY: The state is `'static`, and only ever loaded on its own core.
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
         unsafe {
-            use ::riscv::register::mscratch;
-            mscratch::write(raw);
+            riscv::install_state(self as *const _ as usize, self.env.kernel)
         }
+    }
+
+    /// Let the registered exception handler handle the trap, or the trap handler if there is none.
+    pub fn handle_trap(&self, trap: Trap, frame: &mut TrapFrame) -> Resume {
+        self.handlers
+            .dispatch_exception(trap, frame)
+            .unwrap_or_else(|| (self.trap_handler)(trap, frame))
+    }
+}
+
+/// Check that nested handles load their states in order, and unload them in reverse.
+///
+/// The trap entry code must find the loaded state, so a breakpoint goes to a handler registered
+/// on it. The software interrupt must still reach the handler of the boot state. The states are
+/// leaked, like any state that was ever loaded.
+///
+#[cfg(feature = "self_test")]
+pub(crate) fn self_test() {
+    use alloc::boxed::Box;
+    use core::{cell::Cell, hint::spin_loop};
 
-        if self.env.kernel == super::execution::riscv::Mode::Supervisor {
-            // SAFETY: The lifetime ensures this will never point to an invalid state
+    use super::{
+        interrupts,
+        ipi::{self, Target},
+        trap::Exception,
+    };
+
+    percpu! {
+        static BREAKPOINTS: Cell<usize> = Cell::new(0);
+        static CALLS: Cell<usize> = Cell::new(0);
+    }
+
+    fn breakpoint(_trap: Trap, _frame: &mut TrapFrame) -> Resume {
+        BREAKPOINTS.get().set(BREAKPOINTS.get().get() + 1);
+        Resume::Skip
+    }
+
+    fn call() {
+        CALLS.get().set(CALLS.get().get() + 1)
+    }
+
+    let address = |state: &CoreState| state as *const CoreState as usize;
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    let scratch = riscv::loaded_state;
+
+    let boot = scratch();
+    let outer: &'static CoreState = Box::leak(Box::new(CoreState::new()));
+    let inner: &'static CoreState = Box::leak(Box::new(CoreState::new()));
+
+    {
+        let _outer = outer.load();
+        assert_eq!(scratch(), address(outer), "outer state not loaded");
+
+        {
+            let _inner = inner.load();
+            assert_eq!(scratch(), address(inner), "inner state not loaded");
+
+            interrupts::register_exception(Exception::Breakpoint, &breakpoint);
+            // SAFETY: The handler skips the breakpoint.
+            #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
             unsafe {
-                use ::riscv::register::sscratch;
-                sscratch::write(raw);
+                core::arch::asm!("ebreak")
             }
+            assert_eq!(
+                BREAKPOINTS.get().get(),
+                1,
+                "breakpoint not handled by the loaded state"
+            );
+
+            // Queuing a call on the current core raises its software interrupt.
+            ipi::call_async(Target::Core(id()), &call);
+            interrupts::without_interrupts(|| {
+                interrupts::enable();
+                // SAFETY: The counter is only written by this core, the handler included.
+                while unsafe { CALLS.get().as_ptr().read_volatile() } == 0 {
+                    spin_loop()
+                }
+            });
+            assert_eq!(CALLS.get().get(), 1, "software interrupt not handled");
         }
 
-        Core { state: self }
+        assert_eq!(scratch(), address(outer), "outer state not loaded again");
     }
 
-    pub fn handle_trap(&self, trap: Trap) {
-        (self.trap_handler)(trap)
-    }
+    assert_eq!(scratch(), boot, "boot state not loaded again");
 }
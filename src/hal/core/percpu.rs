@@ -0,0 +1,78 @@
+use crate::hal::board::MAX_HARTS;
+
+use super::id;
+
+/// A value with an instance for every core, see [`percpu!`](crate::hal::core::percpu).
+///
+/// Every core only gets at its own instance, so it needs no lock. Traps on the same core can still
+/// get at it though, so changes must be done with interrupts disabled.
+///
+/// The instances are laid out statically, indexed by the core ID that `hal::boot` keeps in `tp`
+/// for every hart. Code never moves between cores, so an instance is never used by two of them.
+///
+pub struct PerCore<T> {
+    values: [T; MAX_HARTS],
+}
+
+// SAFETY: Every core only uses its own instance, unless `T` is `Sync`. The instance of a core may
+// be created on another one, so `T` must be `Send`.
+unsafe impl<T: Send> Sync for PerCore<T> {}
+
+impl<T> PerCore<T> {
+    pub const fn new(values: [T; MAX_HARTS]) -> PerCore<T> {
+        PerCore { values }
+    }
+
+    /// The instance of the current core.
+    pub fn get(&self) -> &T {
+        &self.values[id()]
+    }
+
+    /// The instance of `core`, which other cores may use as well.
+    ///
+    /// # Panics
+    ///
+    /// If there is no core `core`.
+    ///
+    pub fn of(&self, core: usize) -> &T
+    where
+        T: Sync,
+    {
+        &self.values[core]
+    }
+
+    /// The instances of every core.
+    pub fn iter(&self) -> impl Iterator<Item = &T>
+    where
+        T: Sync,
+    {
+        self.values.iter()
+    }
+}
+
+/// Declare statics with an instance for every core, see [`PerCore`].
+///
+/// The initializer has to be a constant, every core gets its own copy.
+///
+/// ```ignore
+/// percpu! {
+///     /// The amount of timer interrupts taken by the core.
+///     static TICKS: Cell<usize> = Cell::new(0);
+/// }
+///
+/// TICKS.get().set(TICKS.get().get() + 1);
+/// ```
+///
+macro_rules! percpu {
+    ($($(#[$attribute:meta])* $visibility:vis static $name:ident: $type:ty = $init:expr;)*) => {
+        $(
+            $(#[$attribute])*
+            $visibility static $name: $crate::hal::core::PerCore<$type> =
+                $crate::hal::core::PerCore::new(
+                    [const { $init }; $crate::hal::board::MAX_HARTS],
+                );
+        )*
+    };
+}
+
+pub(crate) use percpu;
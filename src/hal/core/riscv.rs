@@ -1,11 +1,124 @@
-use riscv::register::mhartid;
+use core::arch::asm;
+#[cfg(not(feature = "riscv_sbi"))]
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "riscv_sbi")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(not(feature = "riscv_sbi"))]
+use riscv::register::mscratch;
+use riscv::register::sscratch;
+
+use crate::hal::execution::riscv::Mode;
+
+/// Whether the kernel runs in S-mode, so the loaded core state is found in `sscratch`.
+///
+/// Every hart runs the kernel in the same mode.
+///
+#[cfg(not(feature = "riscv_sbi"))]
+static SUPERVISOR_KERNEL: AtomicBool = AtomicBool::new(false);
+
+/// The hart that entered the kernel first, which becomes the primary hart.
+///
+/// An SBI firmware picks the boot hart itself, so it is not necessarily hart 0.
+///
+#[cfg(feature = "riscv_sbi")]
+static BOOT_HART: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Get the ID of the current hart.
+///
+/// `_start` keeps it in `tp`, as `mhartid` is not accessible from S-mode.
+///
+pub(crate) fn hart_id() -> usize {
+    let id;
+    // SAFETY: Reading `tp` has no side effects.
+    unsafe {
+        asm!("mv {}, tp", out(reg) id, options(nomem, nostack, preserves_flags));
+    }
+    id
+}
+
+/// Checks if the current hart is the primary one.
+///
+/// # Returns
+///
+/// Whether the hart running this code is the primary hart.
+///
+#[cfg(not(feature = "riscv_sbi"))]
+pub(crate) fn is_primary_hart() -> bool {
+    hart_id() == 0
+}
 
 /// Checks if the current hart is the primary one.
 ///
+/// The first hart to call this claims the primary role, so the boot code must call it before
+/// starting any other hart.
+///
 /// # Returns
 ///
 /// Whether the hart running this code is the primary hart.
 ///
+#[cfg(feature = "riscv_sbi")]
 pub(crate) fn is_primary_hart() -> bool {
-    mhartid::read() == 0
+    let id = hart_id();
+    match BOOT_HART.compare_exchange(usize::MAX, id, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => true,
+        Err(primary) => primary == id,
+    }
+}
+
+/// Remember the mode the kernel runs in, for [`kernel_mode`].
+#[cfg(not(feature = "riscv_sbi"))]
+fn set_kernel_mode(kernel: Mode) {
+    SUPERVISOR_KERNEL.store(kernel == Mode::Supervisor, Ordering::Relaxed);
+}
+
+/// Under an SBI firmware the kernel always runs in S-mode.
+#[cfg(feature = "riscv_sbi")]
+fn set_kernel_mode(_kernel: Mode) {}
+
+/// Point the scratch registers at the core state at `state`, and remember the mode the kernel runs
+/// in with it.
+///
+/// Traps to M-mode need the state even when the kernel runs in S-mode, for the firmware. Under an
+/// SBI firmware `mscratch` is not ours.
+///
+/// # Safety
+///
+/// `state` MUST be the address of a `'static` core state, created for this hart.
+///
+pub(crate) unsafe fn install_state(state: usize, kernel: Mode) {
+    // SAFETY: The caller guarantees the trap entry code can always use the state.
+    unsafe {
+        #[cfg(not(feature = "riscv_sbi"))]
+        mscratch::write(state);
+
+        set_kernel_mode(kernel);
+
+        if kernel == Mode::Supervisor {
+            sscratch::write(state);
+        }
+    }
+}
+
+/// The address of the core state loaded on this hart, `0` if there is none.
+///
+/// The scratch register of the mode the kernel runs in holds it, the other one may not be
+/// accessible.
+///
+pub(crate) fn loaded_state() -> usize {
+    match kernel_mode() {
+        #[cfg(not(feature = "riscv_sbi"))]
+        Mode::Machine => mscratch::read(),
+        _ => sscratch::read(),
+    }
+}
+
+/// The mode the kernel runs in.
+pub(crate) fn kernel_mode() -> Mode {
+    #[cfg(not(feature = "riscv_sbi"))]
+    if !SUPERVISOR_KERNEL.load(Ordering::Relaxed) {
+        return Mode::Machine;
+    }
+
+    Mode::Supervisor
 }
@@ -1,5 +1,7 @@
+#[cfg(not(feature = "riscv_sbi"))]
 use core::arch::asm;
 
+#[cfg(not(feature = "riscv_sbi"))]
 use riscv::register::{
     medeleg::{self, Medeleg},
     mepc,
@@ -9,13 +11,19 @@ use riscv::register::{
     satp::{self, Satp},
 };
 
-#[cfg(feature = "riscv_pmp")]
+#[cfg(all(feature = "riscv_pmp", not(feature = "riscv_sbi")))]
 use riscv::register::{Permission, Range, pmpaddr0, pmpcfg0};
 
-use crate::main;
+#[cfg(all(not(feature = "riscv_sbi"), not(feature = "riscv_isa_e")))]
+use crate::hal::sbi::firmware;
+use crate::{hal::boot::BootInfo, main};
 
 use super::Environment;
 
+/// The exception code of an `ecall` from S-mode.
+#[cfg(not(feature = "riscv_sbi"))]
+const ECALL_FROM_SUPERVISOR: usize = 9;
+
 /// A privilege mode of a hart
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Mode {
@@ -37,6 +45,7 @@ impl ExecutionEnvironment {
     /// Will prefer S-mode for kernel space, falling back to M-mode if unavailable.
     /// Will prefer U-mode for user space, falling back to M-mode if unavailable.
     ///
+    #[cfg(not(feature = "riscv_sbi"))]
     pub fn new() -> ExecutionEnvironment {
         let isa = misa::read();
         let kernel = if isa.has_extension('S') {
@@ -53,6 +62,25 @@ impl ExecutionEnvironment {
 
         ExecutionEnvironment { kernel, user }
     }
+
+    /// Create the default [`ExecutionEnvironment`].
+    ///
+    /// As an S-mode payload of an SBI firmware the kernel is already in S-mode, which implies the
+    /// presence of U-mode. (`misa` can not be read from S-mode)
+    ///
+    #[cfg(feature = "riscv_sbi")]
+    pub fn new() -> ExecutionEnvironment {
+        ExecutionEnvironment {
+            kernel: Mode::Supervisor,
+            user: Mode::User,
+        }
+    }
+}
+
+/// The S-mode entry point of the kernel, `mret` lands here with the boot info in `a0`.
+#[cfg(not(feature = "riscv_sbi"))]
+extern "C" fn enter_kernel(info: &'static BootInfo) -> ! {
+    main(info)
 }
 
 impl Environment for ExecutionEnvironment {
@@ -63,7 +91,8 @@ impl Environment for ExecutionEnvironment {
     /// MUST be called in M-mode, with no virtual memory set up.
     /// A valid M-mode trap handler must be active.
     ///
-    unsafe fn activate(&self) -> ! {
+    #[cfg(not(feature = "riscv_sbi"))]
+    unsafe fn activate(&self, info: &'static BootInfo) -> ! {
         if self.kernel == Mode::Supervisor {
             // switch to supervisor mode
 
@@ -73,7 +102,8 @@ impl Environment for ExecutionEnvironment {
                 satp::write(Satp::from_bits(0));
 
                 // Then, delegate all exceptions we can to supervisor mode.
-                medeleg::write(Medeleg::from_bits(!0));
+                // Except for S-mode `ecall`s, which our SBI firmware handles.
+                medeleg::write(Medeleg::from_bits(!(1 << ECALL_FROM_SUPERVISOR)));
 
                 // Also delegate all interrupts to supervisor mode.
                 mideleg::write(Mideleg::from_bits(!0));
@@ -85,15 +115,32 @@ impl Environment for ExecutionEnvironment {
                     pmpaddr0::write(!0);
                 }
 
+                #[cfg(not(feature = "riscv_isa_e"))]
+                firmware::init();
+
                 // Everything is set up, time for S-mode!
-                mepc::write(main as usize);
+                mepc::write(enter_kernel as usize);
                 mstatus::set_mpp(MPP::Supervisor);
 
-                asm!("mret", options(noreturn));
+                asm!("mret", in("a0") info, options(noreturn));
             }
         } else {
             // Just call the kernel directly
-            main()
+            main(info)
         }
     }
+
+    /// Activate the execution environment.
+    ///
+    /// The firmware already put us in S-mode, so this just calls the kernel.
+    ///
+    /// # Safety
+    ///
+    /// MUST be called in S-mode, with no virtual memory set up.
+    /// A valid S-mode trap handler must be active.
+    ///
+    #[cfg(feature = "riscv_sbi")]
+    unsafe fn activate(&self, info: &'static BootInfo) -> ! {
+        main(info)
+    }
 }
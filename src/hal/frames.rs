@@ -0,0 +1,351 @@
+//! The physical frame allocator, which hands out the RAM nothing else occupies.
+//!
+//! Frames are tracked in a bitmap with a bit per frame of [`PAGE_SIZE`], from the lowest to the
+//! highest address of RAM. The kernel image, the device tree, the initial ramdisk and the reserved
+//! regions are never handed out. The bitmap itself is stored in the first piece of RAM that is
+//! large enough, and reached through [`physical_offset`] like page tables are.
+
+use core::{cell::RefCell, slice};
+
+use critical_section::Mutex;
+
+use super::{
+    boot::{BootInfo, Region},
+    mmu::{FrameAllocator, PAGE_SIZE, physical_offset},
+};
+
+/// The frames tracked by a word of the bitmap.
+const BITS: usize = usize::BITS as usize;
+
+static FRAMES: Mutex<RefCell<Option<Bitmap>>> = Mutex::new(RefCell::new(None));
+
+/// How many frames are in use.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// The amount of frames that can be handed out.
+    pub total: usize,
+    /// The amount of those that are not in use.
+    pub free: usize,
+}
+
+impl Stats {
+    /// The amount of frames in use.
+    pub fn used(&self) -> usize {
+        self.total - self.free
+    }
+}
+
+/// A bitmap of frames, a set bit is a frame in use or one that does not exist.
+struct Bitmap {
+    /// The physical address of the first frame.
+    base: usize,
+    /// The amount of frames from `base`.
+    frames: usize,
+    /// The physical address of the bitmap.
+    bits: usize,
+    stats: Stats,
+    /// Where the last allocation ended, searches start there.
+    next: usize,
+}
+
+impl Bitmap {
+    fn words(&mut self) -> &mut [usize] {
+        let words = (self.bits + physical_offset()) as *mut usize;
+
+        // SAFETY: The bitmap is ours, and reachable through the physical memory mapping.
+        unsafe { slice::from_raw_parts_mut(words, self.frames.div_ceil(BITS)) }
+    }
+
+    fn is_free(&mut self, frame: usize) -> bool {
+        self.words()[frame / BITS] & 1 << (frame % BITS) == 0
+    }
+
+    /// Mark `frame` as used or free.
+    ///
+    /// # Returns
+    ///
+    /// Whether that changed anything.
+    ///
+    fn set(&mut self, frame: usize, used: bool) -> bool {
+        let was_free = self.is_free(frame);
+        let word = &mut self.words()[frame / BITS];
+
+        if used {
+            *word |= 1 << (frame % BITS);
+        } else {
+            *word &= !(1 << (frame % BITS));
+        }
+
+        was_free == used
+    }
+
+    /// The frames of `region` that are in the bitmap, `whole` only counts the ones completely
+    /// inside of it.
+    fn frames_of(&self, region: Region, whole: bool) -> (usize, usize) {
+        let (start, end) = if whole {
+            (
+                region.start.next_multiple_of(PAGE_SIZE),
+                region.end() / PAGE_SIZE * PAGE_SIZE,
+            )
+        } else {
+            (
+                region.start / PAGE_SIZE * PAGE_SIZE,
+                region.end().next_multiple_of(PAGE_SIZE),
+            )
+        };
+
+        let frame =
+            |address: usize| (address.saturating_sub(self.base) / PAGE_SIZE).min(self.frames);
+        (frame(start), frame(end).max(frame(start)))
+    }
+
+    /// Hand out the frames completely inside `region`.
+    fn add(&mut self, region: Region) {
+        let (start, end) = self.frames_of(region, true);
+        for frame in start..end {
+            if self.set(frame, false) {
+                self.stats.free += 1;
+                self.stats.total += 1;
+            }
+        }
+    }
+
+    /// Never hand out the frames overlapping `region`.
+    fn exclude(&mut self, region: Region) {
+        let (start, end) = self.frames_of(region, false);
+        for frame in start..end {
+            if self.set(frame, true) {
+                self.stats.free -= 1;
+                self.stats.total -= 1;
+            }
+        }
+    }
+
+    /// The first of `count` free frames from `from`, whose address is aligned to `align` frames.
+    fn find(&mut self, from: usize, count: usize, align: usize) -> Option<usize> {
+        let first = self.base / PAGE_SIZE;
+        let aligned = |frame: usize| (first + frame).next_multiple_of(align) - first;
+
+        let mut frame = aligned(from);
+        while frame + count <= self.frames {
+            // Skip words without free frames at once.
+            if frame % BITS == 0 && self.words()[frame / BITS] == usize::MAX {
+                frame = aligned(frame + BITS);
+                continue;
+            }
+
+            match (frame..frame + count).find(|&frame| !self.is_free(frame)) {
+                Some(used) => frame = aligned(used + 1),
+                None => return Some(frame),
+            }
+        }
+
+        None
+    }
+
+    fn allocate(&mut self, count: usize, align: usize) -> Option<usize> {
+        if count == 0 || count > self.stats.free {
+            return None;
+        }
+
+        let frame = self
+            .find(self.next, count, align)
+            .or_else(|| self.find(0, count, align))?;
+
+        for frame in frame..frame + count {
+            self.set(frame, true);
+        }
+        self.stats.free -= count;
+        self.next = frame + count;
+
+        Some(self.base + frame * PAGE_SIZE)
+    }
+
+    fn deallocate(&mut self, address: usize, count: usize) {
+        assert!(
+            address % PAGE_SIZE == 0 && address >= self.base,
+            "{address:#x} is not a frame"
+        );
+
+        let start = (address - self.base) / PAGE_SIZE;
+        for frame in start..start + count {
+            assert!(
+                frame < self.frames && !self.is_free(frame),
+                "frame {:#x} is not in use",
+                self.base + frame * PAGE_SIZE
+            );
+            self.set(frame, false);
+        }
+        self.stats.free += count;
+    }
+}
+
+/// Allocate a frame, its contents are undefined.
+///
+/// # Returns
+///
+/// The physical address of the frame, or `None` if there are none left.
+///
+pub fn allocate() -> Option<usize> {
+    allocate_contiguous(1, PAGE_SIZE)
+}
+
+/// Allocate `count` consecutive frames, starting at an address aligned to `align` bytes.
+///
+/// # Returns
+///
+/// The physical address of the first frame, or `None` if there is no such run of free frames.
+///
+/// # Panics
+///
+/// If `align` is not a power of two.
+///
+pub fn allocate_contiguous(count: usize, align: usize) -> Option<usize> {
+    assert!(
+        align.is_power_of_two(),
+        "alignment {align} is not a power of two"
+    );
+    let align = align.div_ceil(PAGE_SIZE);
+
+    critical_section::with(|cs| {
+        let mut frames = FRAMES.borrow_ref_mut(cs);
+        frames.as_mut()?.allocate(count, align)
+    })
+}
+
+/// Give back a frame returned by [`allocate`].
+///
+/// # Panics
+///
+/// If the frame is not in use.
+///
+pub fn deallocate(frame: usize) {
+    deallocate_contiguous(frame, 1)
+}
+
+/// Give back the `count` frames from `start`, returned by [`allocate_contiguous`].
+///
+/// Frames can be given back in smaller pieces than they were allocated in.
+///
+/// # Panics
+///
+/// If any of the frames is not in use.
+///
+pub fn deallocate_contiguous(start: usize, count: usize) {
+    critical_section::with(|cs| {
+        let mut frames = FRAMES.borrow_ref_mut(cs);
+        frames
+            .as_mut()
+            .expect("no frames were ever handed out")
+            .deallocate(start, count)
+    })
+}
+
+/// How many frames are in use, all zero before the allocator is set up.
+pub fn stats() -> Stats {
+    critical_section::with(|cs| {
+        let frames = FRAMES.borrow_ref(cs);
+        frames
+            .as_ref()
+            .map(|frames| frames.stats)
+            .unwrap_or_default()
+    })
+}
+
+/// The frame allocator as a [`FrameAllocator`], for page tables.
+#[derive(Debug, Clone, Copy)]
+pub struct Global;
+
+impl FrameAllocator for Global {
+    fn allocate(&mut self) -> Option<usize> {
+        allocate()
+    }
+
+    fn deallocate(&mut self, frame: usize) {
+        deallocate(frame)
+    }
+}
+
+fn overlaps(a: Region, b: Region) -> bool {
+    a.start < b.end() && b.start < a.end()
+}
+
+/// The first page aligned place of `size` bytes in `memory` that none of `excluded` overlaps.
+fn place(memory: &[Region], excluded: &[Region], size: usize) -> Option<usize> {
+    let candidates = memory
+        .iter()
+        .map(|region| region.start)
+        .chain(excluded.iter().map(|region| region.end()));
+
+    candidates
+        .map(|start| start.next_multiple_of(PAGE_SIZE))
+        .find(|&start| {
+            let place = Region { start, size };
+            memory
+                .iter()
+                .any(|region| region.start <= start && place.end() <= region.end())
+                && !excluded.iter().any(|region| overlaps(*region, place))
+        })
+}
+
+/// Set up the frame allocator with the memory in `info`.
+///
+/// # Safety
+///
+/// MUST be called once, by the primary core before the others run. The memory in `info` MUST be
+/// reachable at [`physical_offset`].
+///
+pub(crate) unsafe fn init(info: &BootInfo) {
+    let memory: &[Region] = &info.memory;
+    // The kernel image, the device tree, the initial ramdisk, the reserved regions and the bitmap.
+    let mut excluded = [Region::default(); 4 + 16];
+    let mut len = 0;
+
+    let fixed = [
+        Some(info.kernel),
+        info.fdt.map(|fdt| fdt.region()),
+        info.initrd,
+    ];
+    for region in fixed
+        .into_iter()
+        .flatten()
+        .chain(info.reserved.iter().copied())
+    {
+        excluded[len] = region;
+        len += 1;
+    }
+
+    let (Some(start), Some(end)) = (
+        memory.iter().map(|region| region.start).min(),
+        memory.iter().map(|region| region.end()).max(),
+    ) else {
+        return;
+    };
+
+    let base = start / PAGE_SIZE * PAGE_SIZE;
+    let frames = (end - base).div_ceil(PAGE_SIZE);
+    let size = frames.div_ceil(BITS) * size_of::<usize>();
+
+    let Some(bits) = place(memory, &excluded[..len], size) else {
+        return;
+    };
+    excluded[len] = Region { start: bits, size };
+    len += 1;
+
+    let mut bitmap = Bitmap {
+        base,
+        frames,
+        bits,
+        stats: Stats::default(),
+        next: 0,
+    };
+
+    // Everything starts out in use, including the gaps between the regions of memory.
+    bitmap.words().fill(usize::MAX);
+    memory.iter().for_each(|region| bitmap.add(*region));
+    excluded[..len]
+        .iter()
+        .for_each(|region| bitmap.exclude(*region));
+
+    critical_section::with(|cs| *FRAMES.borrow_ref_mut(cs) = Some(bitmap));
+}
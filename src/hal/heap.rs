@@ -0,0 +1,293 @@
+//! The kernel heap, which backs `alloc`.
+//!
+//! Small objects come from slab caches, one for every power of two from [`MIN_SIZE`] to
+//! [`MAX_SIZE`] bytes. A cache carves frames from the frame allocator into objects, and keeps the
+//! free ones in a list threaded through them. Carved frames stay with their cache, for the next
+//! objects of its size. Larger objects, or ones aligned to more than [`MAX_SIZE`], get frames of
+//! their own, which are given back once the object is freed.
+//!
+//! When the frames run out, the allocation fails and is counted in [`Stats::failures`]. Fallible
+//! allocations (like `Vec::try_reserve`) report the error, the rest panic through the default
+//! allocation error handler.
+//!
+//! Objects are reached through the mapping of physical memory at [`physical_offset`], which
+//! changes when the kernel moves during boot. So the heap can only be used once boot finished, no
+//! object may be allocated before the move and freed after it.
+//!
+//! With the `heap_debug` feature, objects are poisoned when allocated and freed, writes to freed
+//! objects are caught when they are handed out again, and the live objects are counted.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::RefCell,
+    ptr::{self, null_mut},
+};
+
+use critical_section::Mutex;
+
+use super::{
+    boot, frames,
+    mmu::{PAGE_SIZE, physical_offset},
+};
+
+/// The size of the objects of the smallest cache, they must fit the link of the free list.
+pub const MIN_SIZE: usize = 16;
+/// The size of the objects of the largest cache.
+pub const MAX_SIZE: usize = 2048;
+/// The amount of caches.
+const CACHES: usize = (MAX_SIZE / MIN_SIZE).trailing_zeros() as usize + 1;
+
+/// The byte allocated objects are filled with.
+#[cfg(feature = "heap_debug")]
+const POISON_ALLOCATED: u8 = 0xcd;
+/// The byte free objects are filled with, after the link of the free list.
+#[cfg(feature = "heap_debug")]
+const POISON_FREED: u8 = 0xdd;
+
+#[global_allocator]
+static ALLOCATOR: KernelHeap = KernelHeap;
+
+static HEAP: Mutex<RefCell<Heap>> = Mutex::new(RefCell::new(Heap::new()));
+
+/// How the heap is used.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// The frames carved into objects by the caches.
+    pub slab_frames: usize,
+    /// The frames of the large objects in use.
+    pub large_frames: usize,
+    /// The allocations that failed, as there were no frames left.
+    pub failures: usize,
+    /// The objects in use of every cache, from the smallest to the largest, followed by the large
+    /// objects. Counts that keep growing point at a leak.
+    #[cfg(feature = "heap_debug")]
+    pub live: [usize; CACHES + 1],
+}
+
+/// The free objects of one size, a list linked through their first word.
+#[derive(Clone, Copy)]
+struct Cache {
+    /// The address of the first free object, `0` if there is none.
+    free: usize,
+}
+
+impl Cache {
+    fn pop(&mut self) -> Option<usize> {
+        let object = self.free;
+        if object == 0 {
+            return None;
+        }
+
+        // SAFETY: Free objects are ours, and start with the link to the next one.
+        self.free = unsafe { ptr::read(object as *const usize) };
+        Some(object)
+    }
+
+    fn push(&mut self, object: usize) {
+        // SAFETY: The object is free, so its first word is ours to use as the link.
+        unsafe { ptr::write(object as *mut usize, self.free) };
+        self.free = object;
+    }
+}
+
+struct Heap {
+    caches: [Cache; CACHES],
+    stats: Stats,
+}
+
+impl Heap {
+    const fn new() -> Heap {
+        Heap {
+            caches: [Cache { free: 0 }; CACHES],
+            stats: Stats {
+                slab_frames: 0,
+                large_frames: 0,
+                failures: 0,
+                #[cfg(feature = "heap_debug")]
+                live: [0; CACHES + 1],
+            },
+        }
+    }
+
+    /// An object from the cache `index`, which takes another frame if it has no free objects.
+    fn allocate(&mut self, index: usize) -> Option<usize> {
+        let cache = &mut self.caches[index];
+        if cache.free == 0 {
+            let frame = frames::allocate()? + physical_offset();
+            self.stats.slab_frames += 1;
+
+            // Pushed in reverse, so objects are handed out in address order.
+            let size = size(index);
+            for object in (frame..frame + PAGE_SIZE).step_by(size).rev() {
+                poison_freed(object, size);
+                cache.push(object);
+            }
+        }
+
+        let object = cache.pop()?;
+        check_freed(object, size(index));
+        Some(object)
+    }
+}
+
+/// The size of the objects of the cache `index`.
+fn size(index: usize) -> usize {
+    MIN_SIZE << index
+}
+
+/// The cache for objects of `layout`, or `None` for large objects.
+fn cache(layout: Layout) -> Option<usize> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(MIN_SIZE)
+        .next_power_of_two();
+
+    (size <= MAX_SIZE).then(|| (size / MIN_SIZE).trailing_zeros() as usize)
+}
+
+/// The amount of frames of a large object of `layout`.
+fn large_frames(layout: Layout) -> usize {
+    layout.size().div_ceil(PAGE_SIZE)
+}
+
+/// Fill the object with the freed poison, except for the link of the free list.
+#[cfg_attr(not(feature = "heap_debug"), allow(unused_variables))]
+fn poison_freed(object: usize, size: usize) {
+    #[cfg(feature = "heap_debug")]
+    // SAFETY: The object is ours, and at least `size` bytes.
+    unsafe {
+        let link = size_of::<usize>();
+        ptr::write_bytes((object + link) as *mut u8, POISON_FREED, size - link)
+    }
+}
+
+/// Check that nothing wrote to the object since it was freed, then poison it as allocated.
+#[cfg_attr(not(feature = "heap_debug"), allow(unused_variables))]
+fn check_freed(object: usize, size: usize) {
+    #[cfg(feature = "heap_debug")]
+    {
+        let link = size_of::<usize>();
+
+        // SAFETY: The object is ours, and at least `size` bytes.
+        let bytes = unsafe { core::slice::from_raw_parts_mut(object as *mut u8, size) };
+        if let Some(offset) = bytes[link..].iter().position(|&byte| byte != POISON_FREED) {
+            panic!(
+                "heap object at {object:#x} was written at offset {} after being freed",
+                link + offset
+            );
+        }
+
+        bytes.fill(POISON_ALLOCATED);
+    }
+}
+
+/// Update the live objects of the cache `index`, or of the large objects for `None`.
+#[cfg_attr(not(feature = "heap_debug"), allow(unused_variables))]
+fn count(stats: &mut Stats, index: Option<usize>, allocated: bool) {
+    #[cfg(feature = "heap_debug")]
+    {
+        let live = &mut stats.live[index.unwrap_or(CACHES)];
+        if allocated {
+            *live += 1;
+        } else {
+            *live -= 1;
+        }
+    }
+}
+
+/// How the heap is used.
+pub fn stats() -> Stats {
+    critical_section::with(|cs| HEAP.borrow_ref(cs).stats)
+}
+
+/// The global allocator, on top of the slab caches and the frame allocator.
+pub struct KernelHeap;
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        assert!(
+            boot::try_info().is_some(),
+            "heap used before boot finished, while physical memory may still move"
+        );
+        let index = cache(layout);
+
+        critical_section::with(|cs| {
+            let mut heap = HEAP.borrow_ref_mut(cs);
+
+            let object = match index {
+                Some(index) => heap.allocate(index),
+                None => {
+                    let pages = large_frames(layout);
+                    let object = frames::allocate_contiguous(pages, layout.align())
+                        .map(|frame| frame + physical_offset());
+
+                    if object.is_some() {
+                        heap.stats.large_frames += pages;
+                    }
+                    object
+                }
+            };
+
+            let Some(object) = object else {
+                heap.stats.failures += 1;
+                return null_mut();
+            };
+
+            // Objects of caches are poisoned when handed out, large objects are not from a cache.
+            #[cfg(feature = "heap_debug")]
+            if index.is_none() {
+                // SAFETY: The frames are ours, and hold at least the size of the object.
+                unsafe { ptr::write_bytes(object as *mut u8, POISON_ALLOCATED, layout.size()) }
+            }
+
+            count(&mut heap.stats, index, true);
+            object as *mut u8
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let object = ptr as usize;
+        let index = cache(layout);
+
+        critical_section::with(|cs| {
+            let mut heap = HEAP.borrow_ref_mut(cs);
+
+            match index {
+                Some(index) => {
+                    poison_freed(object, size(index));
+                    heap.caches[index].push(object)
+                }
+                None => {
+                    let pages = large_frames(layout);
+                    poison_freed(object, pages * PAGE_SIZE);
+                    frames::deallocate_contiguous(object - physical_offset(), pages);
+                    heap.stats.large_frames -= pages;
+                }
+            }
+
+            count(&mut heap.stats, index, false);
+        })
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // SAFETY: The caller guarantees the new size with the old alignment is a valid layout.
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+
+        // An object that still fits its cache stays where it is.
+        if cache(layout).is_some() && cache(layout) == cache(new_layout) {
+            return ptr;
+        }
+
+        // SAFETY: The caller guarantees `ptr` was allocated with `layout`, and `new_layout` is
+        // valid. The objects are distinct, and the copy covers the smaller of the two.
+        unsafe {
+            let new = self.alloc(new_layout);
+            if !new.is_null() {
+                ptr::copy_nonoverlapping(ptr, new, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+            new
+        }
+    }
+}
@@ -1,4 +1,318 @@
-use riscv::asm::wfi;
+use core::{cell::Cell, marker::PhantomData};
+#[cfg(target_has_atomic = "ptr")]
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use ::riscv::asm::wfi;
+
+#[cfg(target_has_atomic = "ptr")]
+use super::core::id;
+use super::{
+    core::with_current,
+    trap::{Exception, Resume, Trap, TrapFrame},
+};
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub mod aclint;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub mod plic;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+mod riscv;
+
+/// The amount of external interrupt lines a core can have handlers for.
+pub const MAX_IRQS: usize = 128;
+
+/// Handles an interrupt, any context it needs is part of the handler itself.
+pub trait InterruptHandler {
+    fn handle(&self);
+}
+
+impl<F: Fn()> InterruptHandler for F {
+    fn handle(&self) {
+        self()
+    }
+}
+
+/// Handles an exception, any context it needs is part of the handler itself.
+///
+/// Like a [`TrapHandler`](super::trap::TrapHandler), it decides how execution continues.
+///
+pub trait ExceptionHandler {
+    fn handle(&self, trap: Trap, frame: &mut TrapFrame) -> Resume;
+}
+
+impl<F: Fn(Trap, &mut TrapFrame) -> Resume> ExceptionHandler for F {
+    fn handle(&self, trap: Trap, frame: &mut TrapFrame) -> Resume {
+        self(trap, frame)
+    }
+}
+
+/// Where an interrupt comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Timer,
+    Software,
+    /// An external interrupt line, as numbered by the interrupt controller.
+    External(u32),
+}
+
+impl Source {
+    /// The source of the interrupt, if the trap is one that is dispatched directly.
+    ///
+    /// External interrupts first have to be claimed at the interrupt controller to know the line,
+    /// see [`plic`].
+    ///
+    pub fn of(trap: Trap) -> Option<Source> {
+        match trap {
+            Trap::Timer => Some(Source::Timer),
+            Trap::Software => Some(Source::Software),
+            _ => None,
+        }
+    }
+}
+
+type Slot<H> = Cell<Option<&'static H>>;
+
+/// The handlers registered on a core.
+///
+/// Traps without a registered handler go to the [`TrapHandler`](super::trap::TrapHandler) of the
+/// core.
+///
+pub struct Handlers {
+    timer: Slot<dyn InterruptHandler>,
+    software: Slot<dyn InterruptHandler>,
+    external: [Slot<dyn InterruptHandler>; MAX_IRQS],
+    exceptions: [Slot<dyn ExceptionHandler>; Exception::COUNT],
+}
+
+impl Handlers {
+    pub fn new() -> Handlers {
+        Handlers {
+            timer: Cell::new(None),
+            software: Cell::new(None),
+            external: [const { Cell::new(None) }; MAX_IRQS],
+            exceptions: [const { Cell::new(None) }; Exception::COUNT],
+        }
+    }
+
+    fn slot(&self, source: Source) -> Option<&Slot<dyn InterruptHandler>> {
+        match source {
+            Source::Timer => Some(&self.timer),
+            Source::Software => Some(&self.software),
+            Source::External(irq) => self.external.get(irq as usize),
+        }
+    }
+
+    /// Take over the interrupt handlers of `other` for the sources without one.
+    ///
+    /// Interrupts are raised for the core, whatever state it has loaded. So a state loaded on top
+    /// of another keeps serving them, instead of leaving them pending forever.
+    ///
+    pub(crate) fn inherit(&self, other: &Handlers) {
+        let sources = [&self.timer, &self.software].into_iter().chain(&self.external);
+        let others = [&other.timer, &other.software].into_iter().chain(&other.external);
+
+        for (slot, other) in sources.zip(others) {
+            if slot.get().is_none() {
+                slot.set(other.get())
+            }
+        }
+    }
+
+    /// Call the handler registered for `source`.
+    ///
+    /// # Returns
+    ///
+    /// Whether there was one.
+    ///
+    pub fn dispatch(&self, source: Source) -> bool {
+        let handler = self.slot(source).and_then(Cell::get);
+        handler.map(|handler| handler.handle()).is_some()
+    }
+
+    /// Call the handler registered for the kind of exception `trap` is.
+    ///
+    /// # Returns
+    ///
+    /// How to continue, if there was a handler.
+    ///
+    pub fn dispatch_exception(&self, trap: Trap, frame: &mut TrapFrame) -> Option<Resume> {
+        let handler = self.exceptions[trap.exception()? as usize].get()?;
+        Some(handler.handle(trap, frame))
+    }
+}
+
+/// Attach `handler` to `source` on the current core, replacing the previous one.
+///
+/// # Panics
+///
+/// If `source` is an external line of at least [`MAX_IRQS`].
+///
+pub fn register(source: Source, handler: &'static dyn InterruptHandler) {
+    set(source, Some(handler))
+}
+
+/// Detach the handler of `source` on the current core.
+pub fn unregister(source: Source) {
+    set(source, None)
+}
+
+fn set(source: Source, handler: Option<&'static dyn InterruptHandler>) {
+    with_current(|state| {
+        let slot = state
+            .handlers
+            .slot(source)
+            .unwrap_or_else(|| panic!("no handlers for interrupt source {source:?}"));
+
+        // A trap in between the two halves of the write would see a broken handler.
+        without_interrupts(|| slot.set(handler));
+    })
+}
+
+/// Attach `handler` to the exceptions of kind `kind` on the current core, replacing the previous
+/// one.
+pub fn register_exception(kind: Exception, handler: &'static dyn ExceptionHandler) {
+    set_exception(kind, Some(handler))
+}
+
+/// Detach the handler of the exceptions of kind `kind` on the current core.
+pub fn unregister_exception(kind: Exception) {
+    set_exception(kind, None)
+}
+
+fn set_exception(kind: Exception, handler: Option<&'static dyn ExceptionHandler>) {
+    with_current(|state| {
+        let slot = &state.handlers.exceptions[kind as usize];
+
+        // A trap in between the two halves of the write would see a broken handler.
+        without_interrupts(|| slot.set(handler));
+    })
+}
+
+/// Whether interrupts are enabled on the current core.
+pub fn are_enabled() -> bool {
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    riscv::are_enabled()
+}
+
+/// Enable interrupts on the current core.
+///
+/// Prefer [`InterruptGuard`] or [`without_interrupts`], which restore the previous state instead.
+///
+pub fn enable() {
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    riscv::enable()
+}
+
+/// Disable interrupts on the current core.
+///
+/// Prefer [`InterruptGuard`] or [`without_interrupts`], which restore the previous state instead.
+///
+pub fn disable() {
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    riscv::disable();
+}
+
+/// Disables interrupts on the current core while it lives, restoring the previous state after.
+///
+/// Guards nest, but must be dropped in the reverse order they were created in. As they belong to
+/// the core they were created on, they can not be sent to other threads.
+///
+pub struct InterruptGuard {
+    /// Whether interrupts were enabled before.
+    enabled: bool,
+    _core_local: PhantomData<*const ()>,
+}
+
+impl InterruptGuard {
+    pub fn new() -> InterruptGuard {
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        let enabled = riscv::disable();
+
+        InterruptGuard {
+            enabled,
+            _core_local: PhantomData,
+        }
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.enabled {
+            enable()
+        }
+    }
+}
+
+/// Run `f` with interrupts disabled on the current core.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = InterruptGuard::new();
+    f()
+}
+
+/// The core in a critical section, [`NO_OWNER`] if there is none.
+#[cfg(target_has_atomic = "ptr")]
+static OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+#[cfg(target_has_atomic = "ptr")]
+const NO_OWNER: usize = usize::MAX;
+
+/// [`RawRestoreState`](critical_section::RawRestoreState) bit: interrupts were enabled before.
+const RESTORE_INTERRUPTS: u8 = 1 << 0;
+/// [`RawRestoreState`](critical_section::RawRestoreState) bit: this section took the lock, it is
+/// not nested in another one.
+#[cfg(target_has_atomic = "ptr")]
+const RESTORE_LOCK: u8 = 1 << 1;
+
+/// Critical sections for the `critical-section` crate.
+///
+/// They disable interrupts on the current core, and keep the other cores out with a spin lock.
+/// Without compare-and-swap atomics (like on rv32e) there is no lock, so only a single core is
+/// supported there.
+///
+struct CriticalSection;
+
+critical_section::set_impl!(CriticalSection);
+
+// SAFETY: Interrupts are disabled and the lock is held until the outermost section is released.
+unsafe impl critical_section::Impl for CriticalSection {
+    unsafe fn acquire() -> u8 {
+        let mut state = 0;
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        if riscv::disable() {
+            state |= RESTORE_INTERRUPTS;
+        }
+
+        #[cfg(target_has_atomic = "ptr")]
+        {
+            let id = id();
+            if OWNER.load(Ordering::Relaxed) != id {
+                while OWNER
+                    .compare_exchange_weak(NO_OWNER, id, Ordering::Acquire, Ordering::Relaxed)
+                    .is_err()
+                {
+                    spin_loop()
+                }
+
+                state |= RESTORE_LOCK;
+            }
+        }
+
+        state
+    }
+
+    unsafe fn release(state: u8) {
+        #[cfg(target_has_atomic = "ptr")]
+        if state & RESTORE_LOCK != 0 {
+            OWNER.store(NO_OWNER, Ordering::Release);
+        }
+
+        if state & RESTORE_INTERRUPTS != 0 {
+            enable()
+        }
+    }
+}
 
 /// Block until the next interrupt.
 /// In most cases this will map to a single hardware instruction.
@@ -0,0 +1,351 @@
+//! The RISC-V advanced core-local interruptor, which provides the timer and software interrupts.
+//!
+//! An ACLINT is made of an MTIMER, with the shared `mtime` and an `mtimecmp` register per hart, an
+//! MSWI with an `msip` register per hart, and optionally an SSWI with a `setssip` register per
+//! hart. A SiFive CLINT is an MTIMER and an MSWI in one device. The per-hart registers are indexed
+//! by hart ID. See the [ACLINT specification](https://github.com/riscv/riscv-aclint) for the
+//! details.
+//!
+//! An M-mode kernel drives the devices directly. An S-mode kernel arms its timer through the
+//! `stimecmp` CSR if the hart has the Sstc extension, and through the SBI otherwise, and sends
+//! IPIs through the SSWI if there is one, and through the SBI otherwise.
+
+use core::{
+    arch::asm,
+    ptr::{read_volatile, write_volatile},
+};
+
+#[cfg(not(feature = "riscv_isa_e"))]
+use crate::hal::sbi::{self, HartMask};
+use crate::hal::{
+    boot::{self, Region},
+    core::{id, kernel_mode},
+    execution::riscv::Mode,
+};
+
+/// `mie.MSIE`, enables M-mode software interrupts.
+const MSIE: usize = 1 << 3;
+/// `mie.MTIE`, enables M-mode timer interrupts.
+const MTIE: usize = 1 << 7;
+/// `sie.SSIE`, enables S-mode software interrupts. Also `sip.SSIP`, the pending bit.
+const SSIE: usize = 1 << 1;
+/// `sie.STIE`, enables S-mode timer interrupts.
+const STIE: usize = 1 << 5;
+
+/// The M-mode software interrupt device.
+#[derive(Debug, Clone, Copy)]
+pub struct Mswi {
+    region: Region,
+}
+
+impl Mswi {
+    /// The MSWI of the machine, if it has one.
+    pub fn get() -> Option<Mswi> {
+        let region = boot::info().aclint.mswi?;
+        Some(Mswi { region })
+    }
+
+    /// Raise (or clear) the M-mode software interrupt of `hart`.
+    ///
+    /// # Panics
+    ///
+    /// If `hart` has no `msip` register.
+    ///
+    pub fn set_pending(&self, hart: usize, pending: bool) {
+        let msip = register(self.region, hart, 4) as *mut u32;
+
+        // SAFETY: The MSWI is a valid MMIO region, and every hart has its own `msip` register.
+        unsafe { write_volatile(msip, pending as u32) }
+    }
+}
+
+/// The M-mode timer device.
+#[derive(Debug, Clone, Copy)]
+pub struct Mtimer {
+    mtime: usize,
+    mtimecmp: Region,
+}
+
+impl Mtimer {
+    /// The MTIMER of the machine, if it has one.
+    pub fn get() -> Option<Mtimer> {
+        let aclint = &boot::info().aclint;
+        Some(Mtimer {
+            mtime: aclint.mtime?,
+            mtimecmp: aclint.mtimecmp?,
+        })
+    }
+
+    /// The value of `mtime`.
+    pub fn now(&self) -> u64 {
+        let mtime = self.mtime as *const u32;
+
+        // SAFETY: `mtime` is a valid MMIO register, reading it has no side effects.
+        // On 32-bit, the high half is read twice to catch the low half overflowing in between.
+        unsafe {
+            #[cfg(target_pointer_width = "32")]
+            loop {
+                let high = read_volatile(mtime.add(1));
+                let low = read_volatile(mtime);
+                if read_volatile(mtime.add(1)) == high {
+                    return (high as u64) << 32 | low as u64;
+                }
+            }
+            #[cfg(target_pointer_width = "64")]
+            read_volatile(mtime as *const u64)
+        }
+    }
+
+    /// Program the `mtimecmp` register of `hart`, the M-mode timer interrupt of `hart` is pending
+    /// while `mtime` is at least `deadline`.
+    ///
+    /// # Panics
+    ///
+    /// If `hart` has no `mtimecmp` register.
+    ///
+    pub fn set_compare(&self, hart: usize, deadline: u64) {
+        let mtimecmp = register(self.mtimecmp, hart, 8) as *mut u32;
+
+        // SAFETY: The MTIMER is a valid MMIO region, and every hart has its own `mtimecmp`
+        // register. Writing the high half to the maximum first ensures no spurious interrupt
+        // fires in between.
+        unsafe {
+            #[cfg(target_pointer_width = "32")]
+            {
+                write_volatile(mtimecmp.add(1), u32::MAX);
+                write_volatile(mtimecmp, deadline as u32);
+                write_volatile(mtimecmp.add(1), (deadline >> 32) as u32);
+            }
+            #[cfg(target_pointer_width = "64")]
+            write_volatile(mtimecmp as *mut u64, deadline);
+        }
+    }
+}
+
+/// The S-mode software interrupt device.
+#[derive(Debug, Clone, Copy)]
+pub struct Sswi {
+    region: Region,
+}
+
+impl Sswi {
+    /// The SSWI of the machine, if it has one.
+    pub fn get() -> Option<Sswi> {
+        let region = boot::info().aclint.sswi?;
+        Some(Sswi { region })
+    }
+
+    /// Raise the S-mode software interrupt of `hart`, the hart clears it itself in `sip`.
+    ///
+    /// # Panics
+    ///
+    /// If `hart` has no `setssip` register.
+    ///
+    pub fn raise(&self, hart: usize) {
+        let setssip = register(self.region, hart, 4) as *mut u32;
+
+        // SAFETY: The SSWI is a valid MMIO region, and every hart has its own `setssip` register.
+        unsafe { write_volatile(setssip, 1) }
+    }
+}
+
+/// The address of the register of `hart` in an array of registers of `size` bytes.
+fn register(region: Region, hart: usize, size: usize) -> usize {
+    assert!(
+        (hart + 1) * size <= region.size,
+        "hart {hart} has no register at {:#x}",
+        region.start
+    );
+
+    region.start + hart * size
+}
+
+/// Whether `hart` has the Sstc extension, as reported by its ISA string. (`misa` only reports the
+/// single-letter extensions)
+pub(crate) fn has_sstc(hart: usize) -> bool {
+    boot::info()
+        .cpus
+        .iter()
+        .any(|cpu| cpu.id == hart && cpu.has_extension("sstc"))
+}
+
+/// Program the `stimecmp` CSR of the current hart.
+///
+/// # Safety
+///
+/// The hart MUST have the Sstc extension, and `menvcfg.STCE` MUST be set when called from S-mode.
+///
+pub(crate) unsafe fn write_stimecmp(deadline: u64) {
+    // SAFETY: The caller guarantees `stimecmp` (0x14d) and `stimecmph` (0x15d) exist.
+    // Writing the low half to the maximum first ensures no spurious interrupt fires in between.
+    unsafe {
+        #[cfg(target_pointer_width = "32")]
+        asm!(
+            "csrw 0x14d, {max}",
+            "csrw 0x15d, {high}",
+            "csrw 0x14d, {low}",
+            max = in(reg) u32::MAX,
+            high = in(reg) (deadline >> 32) as u32,
+            low = in(reg) deadline as u32,
+            options(nomem, nostack),
+        );
+        #[cfg(target_pointer_width = "64")]
+        asm!("csrw 0x14d, {}", in(reg) deadline, options(nomem, nostack));
+    }
+}
+
+/// Read the `time` CSR, the shadow of `mtime`.
+fn read_time() -> u64 {
+    // SAFETY: Reading the time has no side effects, the firmware allows it through `mcounteren`.
+    unsafe {
+        #[cfg(target_pointer_width = "32")]
+        loop {
+            let (high, low, again): (u32, u32, u32);
+            asm!(
+                "csrr {high}, timeh",
+                "csrr {low}, time",
+                "csrr {again}, timeh",
+                high = out(reg) high,
+                low = out(reg) low,
+                again = out(reg) again,
+                options(nomem, nostack),
+            );
+            if high == again {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+        #[cfg(target_pointer_width = "64")]
+        {
+            let time: u64;
+            asm!("csrr {}, time", out(reg) time, options(nomem, nostack));
+            time
+        }
+    }
+}
+
+/// The current time, in ticks of the timebase.
+///
+/// An M-mode kernel reads `mtime` from the MTIMER, falling back to the `time` CSR without one.
+///
+pub fn now() -> u64 {
+    match (kernel_mode(), Mtimer::get()) {
+        (Mode::Machine, Some(mtimer)) => mtimer.now(),
+        _ => read_time(),
+    }
+}
+
+/// Fire the timer interrupt of the current hart once [`now`] reaches `deadline`.
+///
+/// This replaces the previous deadline, and clears a pending timer interrupt. A deadline of
+/// `u64::MAX` disables the timer.
+///
+/// # Panics
+///
+/// If the kernel runs in M-mode without an MTIMER, or in S-mode without Sstc and without an SBI.
+///
+pub fn set_deadline(deadline: u64) {
+    match kernel_mode() {
+        Mode::Machine => {
+            let mtimer = Mtimer::get().expect("no MTIMER for the M-mode timer");
+            mtimer.set_compare(id(), deadline);
+
+            // SAFETY: Timer interrupts are dispatched to the registered handler.
+            unsafe { asm!("csrs mie, {}", in(reg) MTIE) }
+        }
+        _ => {
+            if has_sstc(id()) {
+                // SAFETY: The hart has Sstc, which the firmware enables for S-mode.
+                unsafe { write_stimecmp(deadline) }
+            } else {
+                #[cfg(not(feature = "riscv_isa_e"))]
+                sbi::time::set_timer(deadline).expect("the SBI firmware has no timer");
+                #[cfg(feature = "riscv_isa_e")]
+                panic!("no Sstc and no SBI for the S-mode timer");
+            }
+
+            // SAFETY: Timer interrupts are dispatched to the registered handler.
+            unsafe { asm!("csrs sie, {}", in(reg) STIE) }
+        }
+    }
+}
+
+/// Raise the software interrupt of `hart`, in the mode the kernel runs in.
+///
+/// # Panics
+///
+/// If the kernel runs in M-mode without an MSWI, or in S-mode without an SSWI and without an SBI,
+/// or if `hart` does not exist.
+///
+pub fn send_ipi(hart: usize) {
+    match kernel_mode() {
+        Mode::Machine => Mswi::get()
+            .expect("no MSWI for M-mode IPIs")
+            .set_pending(hart, true),
+        _ => match Sswi::get() {
+            Some(sswi) => sswi.raise(hart),
+            #[cfg(not(feature = "riscv_isa_e"))]
+            None => sbi::ipi::send_ipi(HartMask::single(hart))
+                .unwrap_or_else(|error| panic!("failed to send an IPI to hart {hart}: {error:?}")),
+            #[cfg(feature = "riscv_isa_e")]
+            None => panic!("no SSWI and no SBI for S-mode IPIs"),
+        },
+    }
+}
+
+/// Raise the software interrupt of `hart` like [`send_ipi`], but without ever panicking, for the
+/// panic handler.
+///
+/// # Returns
+///
+/// Whether it was raised, which it is not before boot finished or without a device for it.
+///
+pub fn try_send_ipi(hart: usize) -> bool {
+    let Some(aclint) = boot::try_info().map(|info| &info.aclint) else {
+        return false;
+    };
+    let has_register = |region: &Region| (hart + 1) * 4 <= region.size;
+
+    match (kernel_mode(), aclint.mswi, aclint.sswi) {
+        (Mode::Machine, Some(region), _) if has_register(&region) => {
+            Mswi { region }.set_pending(hart, true);
+            true
+        }
+        (Mode::Machine, ..) => false,
+        (_, _, Some(region)) if has_register(&region) => {
+            Sswi { region }.raise(hart);
+            true
+        }
+        (_, _, Some(_)) => false,
+        #[cfg(not(feature = "riscv_isa_e"))]
+        (_, _, None) => sbi::ipi::send_ipi(HartMask::single(hart)).is_ok(),
+        #[cfg(feature = "riscv_isa_e")]
+        (_, _, None) => false,
+    }
+}
+
+/// Clear the pending software interrupt of the current hart.
+pub fn clear_ipi() {
+    match kernel_mode() {
+        Mode::Machine => {
+            if let Some(mswi) = Mswi::get() {
+                mswi.set_pending(id(), false)
+            }
+        }
+        // SAFETY: `sip.SSIP` is ours to clear, whoever raised it.
+        _ => unsafe { asm!("csrc sip, {}", in(reg) SSIE) },
+    }
+}
+
+/// Let the current hart take software interrupts, in the mode the kernel runs in.
+///
+/// The timer interrupt is only enabled once a deadline is set.
+///
+pub(crate) fn init() {
+    // SAFETY: Software interrupts are dispatched to the registered handler.
+    unsafe {
+        match kernel_mode() {
+            Mode::Machine => asm!("csrs mie, {}", in(reg) MSIE),
+            _ => asm!("csrs sie, {}", in(reg) SSIE),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! The RISC-V platform-level interrupt controller, which routes external interrupts to harts.
+//!
+//! Each hart has a context per mode it takes external interrupts in. A context has its own enable
+//! bits and priority threshold, and claims and completes the interrupts routed to it. See the
+//! [PLIC specification](https://github.com/riscv/riscv-plic-spec) for the details.
+
+use core::{
+    arch::asm,
+    ptr::{read_volatile, write_volatile},
+};
+
+use crate::hal::{
+    boot::{self, Plic as PlicInfo},
+    core::{id, kernel_mode},
+    execution::riscv::Mode,
+};
+
+use super::{Handlers, Source, without_interrupts};
+
+const PRIORITY: usize = 0x0;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const THRESHOLD: usize = 0x20_0000;
+const CLAIM: usize = 0x20_0004;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+/// `mie.MEIE`, enables M-mode external interrupts.
+const MEIE: usize = 1 << 11;
+/// `sie.SEIE`, enables S-mode external interrupts.
+const SEIE: usize = 1 << 9;
+
+/// The PLIC, as described by the device tree.
+#[derive(Debug, Clone, Copy)]
+pub struct Plic {
+    info: &'static PlicInfo,
+}
+
+impl Plic {
+    /// The PLIC of the machine, if it has one.
+    pub fn get() -> Option<Plic> {
+        let info = boot::info().plic.as_ref()?;
+        Some(Plic { info })
+    }
+
+    /// The amount of interrupt sources, the valid IRQs are `1..=sources`.
+    pub fn sources(&self) -> u32 {
+        self.info.sources
+    }
+
+    /// The context the current hart takes its external interrupts through, in the mode the
+    /// kernel runs in.
+    ///
+    /// Without a mapping in the device tree, the usual layout of an M-mode and an S-mode context
+    /// per hart is assumed.
+    ///
+    fn context(&self) -> usize {
+        let (hart, machine) = (id(), kernel_mode() == Mode::Machine);
+        let contexts = &self.info.contexts;
+
+        match contexts
+            .iter()
+            .find(|context| context.hart == hart && context.machine == machine)
+        {
+            Some(context) => context.index,
+            None if contexts.is_empty() => 2 * hart + !machine as usize,
+            None => panic!("hart {hart} has no PLIC context"),
+        }
+    }
+
+    fn register(&self, offset: usize) -> *mut u32 {
+        (self.info.region.start + offset) as *mut u32
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        // SAFETY: The offset is within the PLIC, which is always readable.
+        unsafe { read_volatile(self.register(offset)) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        // SAFETY: The offset is within the PLIC, its registers only affect interrupt routing.
+        unsafe { write_volatile(self.register(offset), value) }
+    }
+
+    fn check(&self, irq: u32) {
+        assert!(
+            (1..=self.info.sources).contains(&irq),
+            "IRQ {irq} is not a PLIC source"
+        );
+    }
+
+    /// Set the priority of `irq`, `0` never interrupts.
+    ///
+    /// # Panics
+    ///
+    /// If `irq` is not a source of the PLIC.
+    ///
+    pub fn set_priority(&self, irq: u32, priority: u32) {
+        self.check(irq);
+        self.write(PRIORITY + 4 * irq as usize, priority)
+    }
+
+    /// Route `irq` to the current hart, in the mode the kernel runs in.
+    ///
+    /// # Panics
+    ///
+    /// If `irq` is not a source of the PLIC.
+    ///
+    pub fn enable(&self, irq: u32) {
+        self.set_enabled(irq, true)
+    }
+
+    /// Stop routing `irq` to the current hart.
+    ///
+    /// # Panics
+    ///
+    /// If `irq` is not a source of the PLIC.
+    ///
+    pub fn disable(&self, irq: u32) {
+        self.set_enabled(irq, false)
+    }
+
+    fn set_enabled(&self, irq: u32, enabled: bool) {
+        self.check(irq);
+
+        let offset = ENABLE + ENABLE_STRIDE * self.context() + 4 * (irq as usize / 32);
+        let bit = 1 << (irq % 32);
+
+        // Only the current hart changes its own enable bits, with interrupts disabled so a
+        // handler can not do the same in between.
+        without_interrupts(|| {
+            let bits = self.read(offset);
+            self.write(offset, if enabled { bits | bit } else { bits & !bit })
+        })
+    }
+
+    /// Only interrupts with a priority above `threshold` interrupt the current hart.
+    pub fn set_threshold(&self, threshold: u32) {
+        self.write(THRESHOLD + CONTEXT_STRIDE * self.context(), threshold)
+    }
+
+    /// Claim the highest priority pending interrupt of the current hart, if any.
+    pub fn claim(&self) -> Option<u32> {
+        match self.read(CLAIM + CONTEXT_STRIDE * self.context()) {
+            0 => None,
+            irq => Some(irq),
+        }
+    }
+
+    /// Signal that `irq`, as returned by [`claim`](Plic::claim), has been handled.
+    pub fn complete(&self, irq: u32) {
+        self.write(CLAIM + CONTEXT_STRIDE * self.context(), irq)
+    }
+}
+
+/// Let the current hart take external interrupts from the PLIC, if there is one.
+///
+/// Every source starts out disabled, drivers enable the ones they handle.
+///
+pub(crate) fn init() {
+    let Some(plic) = Plic::get() else {
+        return;
+    };
+
+    plic.set_threshold(0);
+
+    // SAFETY: External interrupts are dispatched to the registered handlers.
+    unsafe {
+        match kernel_mode() {
+            Mode::Machine => asm!("csrs mie, {}", in(reg) MEIE),
+            _ => asm!("csrs sie, {}", in(reg) SEIE),
+        }
+    }
+}
+
+/// Claim and dispatch the pending external interrupts of the current hart.
+///
+/// Interrupts without a handler are disabled, so they do not fire again.
+///
+/// # Returns
+///
+/// Whether there is a PLIC to claim them from.
+///
+pub(crate) fn handle(handlers: &Handlers) -> bool {
+    let Some(plic) = Plic::get() else {
+        return false;
+    };
+
+    while let Some(irq) = plic.claim() {
+        if !handlers.dispatch(Source::External(irq)) {
+            plic.disable(irq);
+        }
+
+        plic.complete(irq);
+    }
+
+    true
+}
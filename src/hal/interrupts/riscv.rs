@@ -0,0 +1,66 @@
+use core::arch::asm;
+
+use crate::hal::{core::kernel_mode, execution::riscv::Mode};
+
+/// `mstatus.MIE`, enables M-mode interrupts.
+#[cfg(not(feature = "riscv_sbi"))]
+const MIE: usize = 1 << 3;
+/// `sstatus.SIE`, enables S-mode interrupts.
+const SIE: usize = 1 << 1;
+
+/// Whether the interrupts of the kernel mode are enabled on this hart.
+pub(super) fn are_enabled() -> bool {
+    let status: usize;
+
+    // SAFETY: Reading the status has no side effects.
+    unsafe {
+        match kernel_mode() {
+            #[cfg(not(feature = "riscv_sbi"))]
+            Mode::Machine => {
+                asm!("csrr {}, mstatus", out(reg) status, options(nomem, nostack));
+                return status & MIE != 0;
+            }
+            _ => asm!("csrr {}, sstatus", out(reg) status, options(nomem, nostack)),
+        }
+    }
+
+    status & SIE != 0
+}
+
+/// Enable the interrupts of the kernel mode on this hart.
+pub(super) fn enable() {
+    // SAFETY: The kernel is always prepared to take its own interrupts. The asm is a compiler
+    // barrier, so memory accesses are not moved out of a critical section.
+    unsafe {
+        match kernel_mode() {
+            #[cfg(not(feature = "riscv_sbi"))]
+            Mode::Machine => asm!("csrs mstatus, {}", in(reg) MIE, options(nostack)),
+            _ => asm!("csrs sstatus, {}", in(reg) SIE, options(nostack)),
+        }
+    }
+}
+
+/// Disable the interrupts of the kernel mode on this hart.
+///
+/// # Returns
+///
+/// Whether they were enabled before.
+///
+pub(super) fn disable() -> bool {
+    let status: usize;
+
+    // SAFETY: Disabling interrupts can not break anything. The asm is a compiler barrier, so
+    // memory accesses are not moved out of a critical section.
+    unsafe {
+        match kernel_mode() {
+            #[cfg(not(feature = "riscv_sbi"))]
+            Mode::Machine => {
+                asm!("csrrc {}, mstatus, {}", out(reg) status, in(reg) MIE, options(nostack));
+                return status & MIE != 0;
+            }
+            _ => asm!("csrrc {}, sstatus, {}", out(reg) status, in(reg) SIE, options(nostack)),
+        }
+    }
+
+    status & SIE != 0
+}
@@ -0,0 +1,258 @@
+//! Running functions on other cores, through inter-processor interrupts.
+//!
+//! Every core has a mailbox of calls, which other cores fill before raising its software
+//! interrupt. Cores only take calls once they are [online](init), calls to other cores are
+//! ignored until then.
+
+use core::{
+    cell::RefCell,
+    hint::spin_loop,
+    mem::transmute,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use critical_section::Mutex;
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+use super::interrupts::aclint;
+use super::{
+    board::MAX_HARTS,
+    core::id,
+    interrupts::{self, Source},
+};
+
+/// The amount of calls that can be queued on a core, senders wait while it is full.
+pub const MAX_CALLS: usize = 16;
+
+/// The cores a call runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// The core with this ID.
+    Core(usize),
+    /// Every online core, including the current one.
+    All,
+    /// Every online core but the current one.
+    Others,
+}
+
+impl Target {
+    fn contains(self, core: usize) -> bool {
+        match self {
+            Target::Core(target) => core == target,
+            Target::All => true,
+            Target::Others => core != id(),
+        }
+    }
+}
+
+/// A function queued on a core.
+#[derive(Clone, Copy)]
+struct Call {
+    function: &'static (dyn Fn() + Sync),
+    /// Set once the function has run, for synchronous calls.
+    done: Option<&'static AtomicBool>,
+}
+
+/// The calls queued on a core, in the order they were sent.
+struct Mailbox {
+    calls: [Option<Call>; MAX_CALLS],
+    head: usize,
+    len: usize,
+}
+
+impl Mailbox {
+    const fn new() -> Mailbox {
+        Mailbox {
+            calls: [None; MAX_CALLS],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Queue `call`, or give it back if the mailbox is full.
+    fn push(&mut self, call: Call) -> Result<(), Call> {
+        if self.len == MAX_CALLS {
+            return Err(call);
+        }
+
+        self.calls[(self.head + self.len) % MAX_CALLS] = Some(call);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Call> {
+        let call = self.calls[self.head].take()?;
+        self.head = (self.head + 1) % MAX_CALLS;
+        self.len -= 1;
+        Some(call)
+    }
+}
+
+static MAILBOXES: [Mutex<RefCell<Mailbox>>; MAX_HARTS] =
+    [const { Mutex::new(RefCell::new(Mailbox::new())) }; MAX_HARTS];
+
+/// The cores that take calls.
+static ONLINE: [AtomicBool; MAX_HARTS] = [const { AtomicBool::new(false) }; MAX_HARTS];
+
+/// Set once a core halts the others, which stop at their next software interrupt.
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+/// Queue `call` on `core`, and interrupt it.
+///
+/// While its mailbox is full, the calls queued on the current core are run, so two cores sending
+/// to each other can not wait on each other forever.
+///
+fn send(core: usize, call: Call) {
+    let mut call = call;
+    loop {
+        let queued = critical_section::with(|cs| MAILBOXES[core].borrow_ref_mut(cs).push(call));
+        match queued {
+            Ok(()) => break,
+            Err(rejected) => {
+                call = rejected;
+                handle();
+                spin_loop()
+            }
+        }
+    }
+
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    aclint::send_ipi(core);
+}
+
+/// The online cores in `target`.
+fn cores(target: Target) -> impl Iterator<Item = usize> {
+    (0..MAX_HARTS)
+        .filter(move |&core| target.contains(core) && ONLINE[core].load(Ordering::Acquire))
+}
+
+/// Run `function` on the cores in `target`, and wait until they all did.
+///
+/// The current core runs it directly if it is part of `target`, and keeps running the calls sent
+/// to it while it waits.
+///
+pub fn call(target: Target, function: &(dyn Fn() + Sync)) {
+    // Cores the call is not sent to count as done.
+    let done = [const { AtomicBool::new(true) }; MAX_HARTS];
+
+    // SAFETY: We wait until every core ran the call and set its flag, after which neither the
+    // function nor the flags are used anymore. So they are never used after they go away.
+    let (function, flags) = unsafe {
+        (
+            transmute::<&(dyn Fn() + Sync), &'static (dyn Fn() + Sync)>(function),
+            transmute::<&[AtomicBool; MAX_HARTS], &'static [AtomicBool; MAX_HARTS]>(&done),
+        )
+    };
+
+    let this = id();
+    let mut run_here = false;
+
+    for core in cores(target) {
+        if core == this {
+            run_here = true;
+            continue;
+        }
+
+        flags[core].store(false, Ordering::Relaxed);
+        let done = Some(&flags[core]);
+        send(core, Call { function, done });
+    }
+
+    if run_here {
+        function()
+    }
+
+    for flag in flags {
+        while !flag.load(Ordering::Acquire) {
+            handle();
+            spin_loop()
+        }
+    }
+}
+
+/// Run `function` on the cores in `target`, without waiting for them.
+///
+/// The current core queues it as well if it is part of `target`, to run once it takes interrupts.
+///
+pub fn call_async(target: Target, function: &'static (dyn Fn() + Sync)) {
+    for core in cores(target) {
+        send(
+            core,
+            Call {
+                function,
+                done: None,
+            },
+        );
+    }
+}
+
+/// Run the calls queued on the current core.
+fn handle() {
+    let core = id();
+
+    while let Some(call) = critical_section::with(|cs| MAILBOXES[core].borrow_ref_mut(cs).pop()) {
+        (call.function)();
+
+        if let Some(done) = call.done {
+            done.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// Stop every other online core, for the panic handler.
+///
+/// Unlike a [`call`], this takes no lock and never panics. Cores that have interrupts disabled
+/// only stop once they enable them again.
+///
+/// # Returns
+///
+/// Whether this was the first time, `false` when another core already halts the others, or the
+/// current core panicked while doing so.
+///
+pub fn halt_others() -> bool {
+    #[cfg(target_has_atomic = "8")]
+    let halting = HALTING.swap(true, Ordering::AcqRel);
+    // Without compare-and-swap atomics only a single core is supported, so it is enough to keep
+    // interrupts from halting in between.
+    #[cfg(not(target_has_atomic = "8"))]
+    let halting = interrupts::without_interrupts(|| {
+        let halting = HALTING.load(Ordering::Acquire);
+        HALTING.store(true, Ordering::Release);
+        halting
+    });
+
+    if halting {
+        return false;
+    }
+
+    for core in cores(Target::Others) {
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        aclint::try_send_ipi(core);
+    }
+    true
+}
+
+/// Stop the current core for good, it does not even take interrupts anymore.
+pub fn halt() -> ! {
+    interrupts::disable();
+
+    loop {
+        interrupts::wait()
+    }
+}
+
+/// Bring the current core online, so it takes calls from its software interrupt.
+pub(crate) fn init() {
+    fn interrupt() {
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        aclint::clear_ipi();
+
+        if HALTING.load(Ordering::Acquire) {
+            halt()
+        }
+        handle()
+    }
+
+    interrupts::register(Source::Software, &interrupt);
+    ONLINE[id()].store(true, Ordering::Release);
+}
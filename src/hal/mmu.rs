@@ -0,0 +1,129 @@
+//! Page tables, and the address spaces built from them.
+//!
+//! Page tables are stored in physical frames handed out by a [`FrameAllocator`]. The kernel
+//! reaches them through the mapping of physical memory at [`physical_offset`], which is the
+//! identity mapping until the kernel moves itself elsewhere.
+
+use core::{
+    ops::{BitOr, BitOrAssign},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::ipi::{self, Target};
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+mod riscv;
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub use riscv::{AddressSpace, PageSize, PagingMode, flush, flush_address, flush_all, flush_asid};
+
+/// The size of a page, and of the frames page tables are stored in.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Where physical memory is mapped, `0` for the identity mapping.
+static PHYSICAL_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// The virtual address at which physical address `0` is mapped, the kernel reaches page tables
+/// through it.
+pub fn physical_offset() -> usize {
+    PHYSICAL_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Change where physical memory is mapped.
+///
+/// # Safety
+///
+/// Physical memory MUST be mapped at `offset` in the address space of every core.
+///
+pub unsafe fn set_physical_offset(offset: usize) {
+    PHYSICAL_OFFSET.store(offset, Ordering::Relaxed)
+}
+
+/// Hands out the physical frames page tables are stored in.
+pub trait FrameAllocator {
+    /// A frame of [`PAGE_SIZE`], aligned to it, or `None` if there are none left.
+    fn allocate(&mut self) -> Option<usize>;
+
+    /// Give back a frame returned by [`allocate`](FrameAllocator::allocate).
+    fn deallocate(&mut self, frame: usize);
+}
+
+/// What a mapping allows.
+///
+/// The bits are those of a RISC-V page table entry.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const NONE: Flags = Flags(0);
+    pub const READ: Flags = Flags(1 << 1);
+    pub const WRITE: Flags = Flags(1 << 2);
+    pub const EXECUTE: Flags = Flags(1 << 3);
+    /// Accessible from U-mode (and only from there, unless `sstatus.SUM` is set).
+    pub const USER: Flags = Flags(1 << 4);
+    /// Present in every address space, so it survives flushes of a single ASID.
+    pub const GLOBAL: Flags = Flags(1 << 5);
+    /// The page has been accessed since the bit was cleared.
+    pub const ACCESSED: Flags = Flags(1 << 6);
+    /// The page has been written since the bit was cleared.
+    pub const DIRTY: Flags = Flags(1 << 7);
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u8) -> Flags {
+        Flags(bits)
+    }
+
+    /// Whether all flags of `other` are set.
+    pub const fn contains(self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The flags without those of `other`.
+    pub const fn without(self, other: Flags) -> Flags {
+        Flags(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, other: Flags) -> Flags {
+        Flags(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Flags {
+    fn bitor_assign(&mut self, other: Flags) {
+        self.0 |= other.0
+    }
+}
+
+/// Why changing a mapping failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The frame allocator ran out of frames for page tables.
+    OutOfFrames,
+    /// Part of the range is already mapped.
+    AlreadyMapped,
+    /// The address is not mapped.
+    NotMapped,
+    /// An address or length is not aligned to the page size.
+    Misaligned,
+    /// The virtual address is outside of what the paging mode can map.
+    InvalidAddress,
+    /// The paging mode has no pages of that size.
+    UnsupportedSize,
+    /// The flags are not a valid combination, like writable but not readable.
+    InvalidFlags,
+}
+
+/// Flush the translations of `address` (or all of them) for `asid` (or every address space) on
+/// every core, and wait until they all did.
+pub fn shootdown(address: Option<usize>, asid: Option<u16>) {
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    ipi::call(Target::All, &|| flush(address, asid));
+}
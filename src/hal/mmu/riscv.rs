@@ -0,0 +1,550 @@
+use core::{
+    arch::asm,
+    ptr::{self, read_volatile, write_volatile},
+};
+
+use crate::hal::{
+    boot,
+    core::{id, kernel_mode},
+    execution::riscv::Mode,
+};
+
+use super::{Error, Flags, FrameAllocator, PAGE_SIZE, physical_offset};
+
+/// Page table entry bits.
+const VALID: usize = 1 << 0;
+const READ: usize = 1 << 1;
+const WRITE: usize = 1 << 2;
+const EXECUTE: usize = 1 << 3;
+const ACCESSED: usize = 1 << 6;
+const DIRTY: usize = 1 << 7;
+/// The bits a [`Flags`] covers.
+const FLAGS: usize = 0xfe;
+/// Where the physical page number starts in an entry.
+const PPN_SHIFT: usize = 10;
+/// The bits of the physical page number, the ones above are reserved or for extensions.
+#[cfg(target_pointer_width = "32")]
+const PPN_MASK: usize = usize::MAX >> PPN_SHIFT;
+#[cfg(target_pointer_width = "64")]
+const PPN_MASK: usize = (1 << 44) - 1;
+
+const PAGE_SHIFT: usize = PAGE_SIZE.trailing_zeros() as usize;
+/// The entries in a page table.
+const ENTRIES: usize = PAGE_SIZE / size_of::<usize>();
+/// The virtual page number bits each level translates.
+const VPN_BITS: usize = ENTRIES.trailing_zeros() as usize;
+
+/// The position of the mode and ASID in `satp`.
+#[cfg(target_pointer_width = "32")]
+const SATP_MODE_SHIFT: usize = 31;
+#[cfg(target_pointer_width = "32")]
+const SATP_ASID_SHIFT: usize = 22;
+#[cfg(target_pointer_width = "64")]
+const SATP_MODE_SHIFT: usize = 60;
+#[cfg(target_pointer_width = "64")]
+const SATP_ASID_SHIFT: usize = 44;
+
+/// A virtual memory scheme, they differ in the amount of page table levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PagingMode {
+    /// 32-bit virtual addresses, with 2 levels, only on RV32.
+    Sv32,
+    /// 39-bit virtual addresses, with 3 levels, only on RV64.
+    Sv39,
+    /// 48-bit virtual addresses, with 4 levels, only on RV64.
+    Sv48,
+    /// 57-bit virtual addresses, with 5 levels, only on RV64.
+    Sv57,
+}
+
+impl PagingMode {
+    /// The best mode the current hart supports, according to the `mmu-type` of the device tree.
+    ///
+    /// Without one, the mode every RV32 or RV64 MMU has is assumed. M-mode kernels run without an
+    /// MMU, as there is no S-mode to translate for.
+    ///
+    pub fn best() -> Option<PagingMode> {
+        if kernel_mode() == Mode::Machine {
+            return None;
+        }
+
+        let hart = id();
+        let cpu = boot::info().cpus.iter().find(|cpu| cpu.id == hart);
+        PagingMode::from_mmu_type(cpu.and_then(|cpu| cpu.mmu_type))
+    }
+
+    /// The best mode of an MMU with the `mmu-type` of the device tree, see [`best`](PagingMode::best).
+    pub fn from_mmu_type(mmu_type: Option<&str>) -> Option<PagingMode> {
+        match mmu_type {
+            #[cfg(target_pointer_width = "32")]
+            Some("riscv,sv32") | None => Some(PagingMode::Sv32),
+            #[cfg(target_pointer_width = "64")]
+            Some("riscv,sv39") | None => Some(PagingMode::Sv39),
+            #[cfg(target_pointer_width = "64")]
+            Some("riscv,sv48") => Some(PagingMode::Sv48),
+            #[cfg(target_pointer_width = "64")]
+            Some("riscv,sv57") => Some(PagingMode::Sv57),
+            Some(_) => None,
+        }
+    }
+
+    /// The amount of page table levels.
+    pub fn levels(self) -> usize {
+        match self {
+            PagingMode::Sv32 => 2,
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+            PagingMode::Sv57 => 5,
+        }
+    }
+
+    /// The bits of a virtual address, the ones above must be copies of the highest one.
+    pub fn address_bits(self) -> usize {
+        PAGE_SHIFT + self.levels() * VPN_BITS
+    }
+
+    /// The value of `satp.MODE`.
+    fn satp(self) -> usize {
+        match self {
+            PagingMode::Sv32 => 1,
+            PagingMode::Sv39 => 8,
+            PagingMode::Sv48 => 9,
+            PagingMode::Sv57 => 10,
+        }
+    }
+
+    /// Whether `address` is one the mode can translate.
+    fn is_canonical(self, address: usize) -> bool {
+        let bits = self.address_bits();
+        if bits >= usize::BITS as usize {
+            return true;
+        }
+
+        // The upper bits must all be copies of the highest translated one.
+        let upper = address >> (bits - 1);
+        upper == 0 || upper == usize::MAX >> (bits - 1)
+    }
+}
+
+/// The sizes of pages, larger pages are mapped by entries of higher page table levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageSize {
+    /// A 4 KiB page.
+    Page,
+    /// A 4 MiB page on Sv32, or a 2 MiB page.
+    Megapage,
+    /// A 1 GiB page, from Sv39.
+    Gigapage,
+    /// A 512 GiB page, from Sv48.
+    Terapage,
+    /// A 256 TiB page, from Sv57.
+    Petapage,
+}
+
+impl PageSize {
+    const ALL: [PageSize; 5] = [
+        PageSize::Page,
+        PageSize::Megapage,
+        PageSize::Gigapage,
+        PageSize::Terapage,
+        PageSize::Petapage,
+    ];
+
+    /// The page table level its entries are in, `0` being the last one.
+    fn level(self) -> usize {
+        self as usize
+    }
+
+    /// The size in bytes.
+    pub fn bytes(self) -> usize {
+        1 << (PAGE_SHIFT + self.level() * VPN_BITS)
+    }
+}
+
+/// The page table at physical address `table`.
+fn table(table: usize) -> *mut usize {
+    (table + physical_offset()) as *mut usize
+}
+
+fn read_entry(entry: *mut usize) -> usize {
+    // SAFETY: Entries are only handed out for page tables we own.
+    unsafe { read_volatile(entry) }
+}
+
+fn write_entry(entry: *mut usize, value: usize) {
+    // SAFETY: Entries are only handed out for page tables we own.
+    unsafe { write_volatile(entry, value) }
+}
+
+fn is_leaf(entry: usize) -> bool {
+    entry & (READ | WRITE | EXECUTE) != 0
+}
+
+/// The physical address an entry points to.
+fn address(entry: usize) -> usize {
+    ((entry >> PPN_SHIFT) & PPN_MASK) << PAGE_SHIFT
+}
+
+/// An entry pointing to `address`.
+fn entry(address: usize, bits: usize) -> usize {
+    (address >> PAGE_SHIFT) << PPN_SHIFT | bits | VALID
+}
+
+/// An address space, a tree of page tables with an address space ID.
+///
+/// Mappings are set up with [`Flags::ACCESSED`] (and [`Flags::DIRTY`] when writable) already set,
+/// as hardware without Svadu raises a page fault instead of setting them. The page fault handler
+/// can use [`AddressSpace::update_accessed_dirty`] for mappings where they were cleared.
+///
+/// Changes do not flush the TLBs, see [`flush`] and [`shootdown`](super::shootdown).
+///
+pub struct AddressSpace {
+    mode: PagingMode,
+    asid: u16,
+    /// The physical address of the root page table.
+    root: usize,
+}
+
+impl AddressSpace {
+    /// An empty address space.
+    ///
+    /// # Errors
+    ///
+    /// If there is no frame for the root page table.
+    ///
+    pub fn new(
+        mode: PagingMode,
+        asid: u16,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<AddressSpace, Error> {
+        Ok(AddressSpace {
+            mode,
+            asid,
+            root: new_table(frames)?,
+        })
+    }
+
+    pub fn mode(&self) -> PagingMode {
+        self.mode
+    }
+
+    pub fn asid(&self) -> u16 {
+        self.asid
+    }
+
+    /// The physical address of the root page table.
+    pub fn root(&self) -> usize {
+        self.root
+    }
+
+    /// The value of `satp` that activates the address space.
+    pub fn satp(&self) -> usize {
+        self.mode.satp() << SATP_MODE_SHIFT
+            | (self.asid as usize) << SATP_ASID_SHIFT
+            | self.root >> PAGE_SHIFT
+    }
+
+    /// Switch the current hart to the address space.
+    ///
+    /// # Safety
+    ///
+    /// The code and data in use, including the stack, MUST stay mapped at the same addresses.
+    ///
+    pub unsafe fn activate(&self) {
+        // SAFETY: The caller guarantees execution continues in the new address space.
+        unsafe {
+            asm!("csrw satp, {}", in(reg) self.satp(), options(nostack));
+        }
+        flush(None, Some(self.asid))
+    }
+
+    /// Map the page of `size` at `virt` to the frame at `phys`.
+    ///
+    /// # Errors
+    ///
+    /// If the addresses are not aligned to `size`, part of it is already mapped, or a page table
+    /// is needed but there is no frame for it.
+    ///
+    pub fn map(
+        &mut self,
+        virt: usize,
+        phys: usize,
+        size: PageSize,
+        flags: Flags,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<(), Error> {
+        if size.level() >= self.mode.levels() {
+            return Err(Error::UnsupportedSize);
+        }
+        if virt % size.bytes() != 0 || phys % size.bytes() != 0 {
+            return Err(Error::Misaligned);
+        }
+
+        let bits = leaf_bits(flags)?;
+        let slot = self.walk(virt, size.level(), Some(frames))?;
+        if read_entry(slot) & VALID != 0 {
+            return Err(Error::AlreadyMapped);
+        }
+
+        write_entry(slot, entry(phys, bits));
+        Ok(())
+    }
+
+    /// Map `len` bytes at `virt` to the physical memory at `phys`, using the largest pages
+    /// possible.
+    ///
+    /// # Errors
+    ///
+    /// See [`map`](AddressSpace::map), the pages mapped before the error stay mapped.
+    ///
+    pub fn map_range(
+        &mut self,
+        virt: usize,
+        phys: usize,
+        len: usize,
+        flags: Flags,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<(), Error> {
+        if (virt | phys | len) % PAGE_SIZE != 0 {
+            return Err(Error::Misaligned);
+        }
+
+        let mut offset = 0;
+        while offset < len {
+            let (virt, phys) = (virt + offset, phys + offset);
+            let size = PageSize::ALL[..self.mode.levels()]
+                .iter()
+                .rev()
+                .copied()
+                .find(|size| (virt | phys) % size.bytes() == 0 && len - offset >= size.bytes())
+                .unwrap_or(PageSize::Page);
+
+            self.map(virt, phys, size, flags, frames)?;
+            offset += size.bytes();
+        }
+
+        Ok(())
+    }
+
+    /// Remove the mapping of the page at `virt`, page tables left empty are kept until the address
+    /// space is [destroyed](AddressSpace::destroy).
+    ///
+    /// # Returns
+    ///
+    /// The frame it was mapped to and the size of the page.
+    ///
+    pub fn unmap(&mut self, virt: usize) -> Result<(usize, PageSize), Error> {
+        let (slot, size) = self.find(virt)?;
+        let phys = address(read_entry(slot));
+
+        write_entry(slot, 0);
+        Ok((phys, size))
+    }
+
+    /// Change what the mapping of the page at `virt` allows.
+    pub fn protect(&mut self, virt: usize, flags: Flags) -> Result<(), Error> {
+        let bits = leaf_bits(flags)?;
+        let (slot, _) = self.find(virt)?;
+
+        write_entry(slot, entry(address(read_entry(slot)), bits));
+        Ok(())
+    }
+
+    /// The physical address `virt` is mapped to, and what the mapping allows.
+    pub fn translate(&self, virt: usize) -> Option<(usize, Flags)> {
+        let (slot, size) = self.find(virt).ok()?;
+        let entry = read_entry(slot);
+
+        let phys = address(entry) + virt % size.bytes();
+        Some((phys, Flags::from_bits((entry & FLAGS) as u8)))
+    }
+
+    /// Clear the accessed and dirty bits of the page at `virt`.
+    ///
+    /// # Returns
+    ///
+    /// What they were.
+    ///
+    pub fn clear_accessed_dirty(&mut self, virt: usize) -> Result<Flags, Error> {
+        let (slot, _) = self.find(virt)?;
+        let entry = read_entry(slot);
+
+        write_entry(slot, entry & !(ACCESSED | DIRTY));
+        Ok(Flags::from_bits((entry & (ACCESSED | DIRTY)) as u8))
+    }
+
+    /// Set the accessed (and for a `write`, the dirty) bit of the page at `virt`, if the mapping
+    /// allows the access. For page faults on hardware that does not set them by itself.
+    ///
+    /// # Returns
+    ///
+    /// Whether the access is allowed, so it can be retried.
+    ///
+    pub fn update_accessed_dirty(&mut self, virt: usize, write: bool) -> bool {
+        let Ok((slot, _)) = self.find(virt) else {
+            return false;
+        };
+
+        let entry = read_entry(slot);
+        if write && entry & WRITE == 0 {
+            return false;
+        }
+
+        let bits = if write { ACCESSED | DIRTY } else { ACCESSED };
+        write_entry(slot, entry | bits);
+        true
+    }
+
+    /// Give the page tables of the address space back to `frames`, the frames it maps are left to
+    /// the caller.
+    ///
+    /// # Safety
+    ///
+    /// The address space MUST NOT be active on any hart, and its translations MUST be flushed on
+    /// every hart, as they would walk freed page tables otherwise.
+    ///
+    pub unsafe fn destroy(self, frames: &mut impl FrameAllocator) {
+        free_table(self.root, self.mode.levels() - 1, frames)
+    }
+
+    /// The entry of `virt` in the page table of `level`, creating the page tables above it with
+    /// `frames` if given.
+    fn walk(
+        &self,
+        virt: usize,
+        level: usize,
+        mut frames: Option<&mut impl FrameAllocator>,
+    ) -> Result<*mut usize, Error> {
+        if !self.mode.is_canonical(virt) {
+            return Err(Error::InvalidAddress);
+        }
+
+        let mut table_address = self.root;
+        for current in (level + 1..self.mode.levels()).rev() {
+            let slot = slot(table_address, virt, current);
+            let entry = read_entry(slot);
+
+            table_address = if entry & VALID == 0 {
+                let frames = frames.as_deref_mut().ok_or(Error::NotMapped)?;
+                let new = new_table(frames)?;
+                write_entry(slot, self::entry(new, 0));
+                new
+            } else if is_leaf(entry) {
+                // A larger page covers the address already.
+                return Err(match frames {
+                    Some(_) => Error::AlreadyMapped,
+                    None => Error::NotMapped,
+                });
+            } else {
+                address(entry)
+            };
+        }
+
+        Ok(slot(table_address, virt, level))
+    }
+
+    /// The leaf entry mapping `virt`, and the size of its page.
+    fn find(&self, virt: usize) -> Result<(*mut usize, PageSize), Error> {
+        if !self.mode.is_canonical(virt) {
+            return Err(Error::InvalidAddress);
+        }
+
+        let mut table_address = self.root;
+        for level in (0..self.mode.levels()).rev() {
+            let slot = slot(table_address, virt, level);
+            let entry = read_entry(slot);
+
+            if entry & VALID == 0 {
+                break;
+            }
+            if is_leaf(entry) {
+                return Ok((slot, PageSize::ALL[level]));
+            }
+
+            table_address = address(entry);
+        }
+
+        Err(Error::NotMapped)
+    }
+}
+
+/// The entry translating `virt` in the page table at `table` of `level`.
+fn slot(table_address: usize, virt: usize, level: usize) -> *mut usize {
+    let index = (virt >> (PAGE_SHIFT + level * VPN_BITS)) & (ENTRIES - 1);
+    table(table_address).wrapping_add(index)
+}
+
+/// An empty page table.
+fn new_table(frames: &mut impl FrameAllocator) -> Result<usize, Error> {
+    let frame = frames.allocate().ok_or(Error::OutOfFrames)?;
+
+    // SAFETY: The frame is ours, and reachable through the physical memory mapping.
+    unsafe { ptr::write_bytes(table(frame), 0, ENTRIES) };
+    Ok(frame)
+}
+
+/// Free the page table at `table_address` of `level`, and the ones below it.
+fn free_table(table_address: usize, level: usize, frames: &mut impl FrameAllocator) {
+    if level > 0 {
+        for index in 0..ENTRIES {
+            let entry = read_entry(table(table_address).wrapping_add(index));
+            if entry & VALID != 0 && !is_leaf(entry) {
+                free_table(address(entry), level - 1, frames);
+            }
+        }
+    }
+
+    frames.deallocate(table_address);
+}
+
+/// The bits of a leaf entry with `flags`.
+fn leaf_bits(flags: Flags) -> Result<usize, Error> {
+    let mut bits = flags.bits() as usize & FLAGS;
+
+    // Writable pages must be readable, and a leaf needs at least one permission.
+    if bits & WRITE != 0 && bits & READ == 0 || !is_leaf(bits) {
+        return Err(Error::InvalidFlags);
+    }
+
+    bits |= ACCESSED;
+    if bits & WRITE != 0 {
+        bits |= DIRTY;
+    }
+    Ok(bits)
+}
+
+/// Flush the translations of `address` (or all of them) for `asid` (or every address space) on
+/// the current hart. Global mappings are only flushed without an `asid`.
+pub fn flush(address: Option<usize>, asid: Option<u16>) {
+    // SAFETY: Flushing translations only makes the hart walk the page tables again.
+    unsafe {
+        match (address, asid) {
+            (None, None) => asm!("sfence.vma", options(nostack)),
+            (None, Some(asid)) => {
+                asm!("sfence.vma zero, {}", in(reg) asid as usize, options(nostack))
+            }
+            (Some(address), None) => asm!("sfence.vma {}, zero", in(reg) address, options(nostack)),
+            (Some(address), Some(asid)) => asm!(
+                "sfence.vma {}, {}",
+                in(reg) address,
+                in(reg) asid as usize,
+                options(nostack),
+            ),
+        }
+    }
+}
+
+/// Flush every translation on the current hart.
+pub fn flush_all() {
+    flush(None, None)
+}
+
+/// Flush the translations of the address space `asid` on the current hart.
+pub fn flush_asid(asid: u16) {
+    flush(None, Some(asid))
+}
+
+/// Flush the translations of `address` in every address space on the current hart.
+pub fn flush_address(address: usize) {
+    flush(Some(address), None)
+}
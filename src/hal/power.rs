@@ -0,0 +1,70 @@
+//! Turning the system off.
+//!
+//! S-mode kernels ask the SBI through its system reset extension. M-mode kernels, and S-mode ones
+//! whose SBI can not do it, write the SiFive test device of the [`BootInfo`](super::boot::BootInfo)
+//! themselves. QEMU exits with a status telling whether the shutdown was a failure.
+
+use core::ptr;
+
+use super::{boot, interrupts};
+#[cfg(not(feature = "riscv_isa_e"))]
+use super::{
+    core::kernel_mode,
+    execution::riscv::Mode,
+    sbi::srst::{self, ResetReason, ResetType},
+};
+
+/// Values for the SiFive test device.
+pub(crate) const TEST_PASS: u32 = 0x5555;
+/// Fails with the code in the upper 16 bits, QEMU exits with `code << 1 | 1`.
+pub(crate) const TEST_FAIL: u32 = 0x3333;
+pub(crate) const TEST_RESET: u32 = 0x7777;
+
+/// Why the system is turned off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// Everything went fine.
+    Done,
+    /// Something went wrong, like a panic.
+    Failure,
+}
+
+/// Turn the system off.
+///
+/// Only the current core is stopped when there is no way to turn off the system, the others keep
+/// running.
+///
+pub fn shutdown(reason: Reason) -> ! {
+    #[cfg(not(feature = "riscv_isa_e"))]
+    if kernel_mode() == Mode::Supervisor {
+        let reason = match reason {
+            Reason::Done => ResetReason::None,
+            Reason::Failure => ResetReason::SystemFailure,
+        };
+
+        // Only returns when the SBI can not reset the system, then the device is our last resort.
+        let _ = srst::system_reset(ResetType::Shutdown, reason);
+    }
+
+    let value = match reason {
+        Reason::Done => TEST_PASS,
+        Reason::Failure => TEST_FAIL | 1 << 16,
+    };
+    write_test_device(value);
+
+    interrupts::disable();
+    loop {
+        interrupts::wait()
+    }
+}
+
+/// Write `value` to the SiFive test device, if there is one.
+///
+/// Never returns for the values above, unless the device does not do its job.
+///
+pub(crate) fn write_test_device(value: u32) {
+    if let Some(power) = boot::try_info().and_then(|info| info.power) {
+        // SAFETY: The test device is a valid MMIO region.
+        unsafe { ptr::write_volatile(power.start as *mut u32, value) }
+    }
+}
@@ -0,0 +1,366 @@
+//! The RISC-V Supervisor Binary Interface (v2.0).
+//!
+//! The calls are used by an S-mode kernel, either on top of an external SBI firmware (like
+//! OpenSBI), or on top of our own [`firmware`] when lightning owns M-mode.
+//! See the [SBI specification](https://github.com/riscv-non-isa/riscv-sbi-doc) for the details.
+
+use core::arch::asm;
+
+#[cfg(not(feature = "riscv_sbi"))]
+pub(crate) mod firmware;
+
+/// The errors an SBI call can return.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    NoSharedMemory,
+    InvalidState,
+    BadRange,
+    Timeout,
+    Io,
+    /// An error code not defined by the specification.
+    Unknown(isize),
+}
+
+impl Error {
+    /// Decode an error code, `0` (success) is not an error.
+    pub fn from_code(code: isize) -> Option<Error> {
+        Some(match code {
+            0 => return None,
+            -1 => Error::Failed,
+            -2 => Error::NotSupported,
+            -3 => Error::InvalidParam,
+            -4 => Error::Denied,
+            -5 => Error::InvalidAddress,
+            -6 => Error::AlreadyAvailable,
+            -7 => Error::AlreadyStarted,
+            -8 => Error::AlreadyStopped,
+            -9 => Error::NoSharedMemory,
+            -10 => Error::InvalidState,
+            -11 => Error::BadRange,
+            -12 => Error::Timeout,
+            -13 => Error::Io,
+            code => Error::Unknown(code),
+        })
+    }
+
+    /// The error code of the error.
+    pub fn code(self) -> isize {
+        match self {
+            Error::Failed => -1,
+            Error::NotSupported => -2,
+            Error::InvalidParam => -3,
+            Error::Denied => -4,
+            Error::InvalidAddress => -5,
+            Error::AlreadyAvailable => -6,
+            Error::AlreadyStarted => -7,
+            Error::AlreadyStopped => -8,
+            Error::NoSharedMemory => -9,
+            Error::InvalidState => -10,
+            Error::BadRange => -11,
+            Error::Timeout => -12,
+            Error::Io => -13,
+            Error::Unknown(code) => code,
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// A set of harts, `mask` bit `n` selects hart `base + n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HartMask {
+    pub mask: usize,
+    pub base: usize,
+}
+
+impl HartMask {
+    /// Every hart in the system.
+    pub const ALL: HartMask = HartMask {
+        mask: 0,
+        base: usize::MAX,
+    };
+
+    /// Just `hart`.
+    pub fn single(hart: usize) -> HartMask {
+        HartMask {
+            mask: 1,
+            base: hart,
+        }
+    }
+
+    /// Whether `hart` is part of the set.
+    pub fn contains(&self, hart: usize) -> bool {
+        self.base == usize::MAX
+            || hart
+                .checked_sub(self.base)
+                .is_some_and(|bit| bit < usize::BITS as usize && self.mask & (1 << bit) != 0)
+    }
+}
+
+/// Perform an SBI call.
+///
+/// # Safety
+///
+/// Some calls (like starting harts or fencing) have side effects the caller must be prepared for.
+///
+unsafe fn ecall(eid: usize, fid: usize, args: [usize; 6]) -> Result<usize> {
+    let (error, value): (isize, usize);
+
+    // SAFETY: The caller guarantees the call is sound.
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a5") args[5],
+            in("a6") fid,
+            in("a7") eid,
+            options(nostack),
+        );
+    }
+
+    match Error::from_code(error) {
+        Some(error) => Err(error),
+        None => Ok(value),
+    }
+}
+
+/// Split a 64-bit argument into the registers it is passed in.
+fn split(value: u64) -> (usize, usize) {
+    #[cfg(target_pointer_width = "32")]
+    return (value as usize, (value >> 32) as usize);
+    #[cfg(target_pointer_width = "64")]
+    return (value as usize, 0);
+}
+
+/// The base extension, which every SBI implementation provides.
+pub mod base {
+    use super::{Result, ecall};
+
+    pub const EID: usize = 0x10;
+
+    pub const GET_SPEC_VERSION: usize = 0;
+    pub const GET_IMPL_ID: usize = 1;
+    pub const GET_IMPL_VERSION: usize = 2;
+    pub const PROBE_EXTENSION: usize = 3;
+    pub const GET_MVENDORID: usize = 4;
+    pub const GET_MARCHID: usize = 5;
+    pub const GET_MIMPID: usize = 6;
+
+    fn call(fid: usize, arg: usize) -> Result<usize> {
+        // SAFETY: The base extension only queries information.
+        unsafe { ecall(EID, fid, [arg, 0, 0, 0, 0, 0]) }
+    }
+
+    /// The implemented specification version, as `(major, minor)`.
+    pub fn spec_version() -> (usize, usize) {
+        let version = call(GET_SPEC_VERSION, 0).unwrap_or(0);
+        ((version >> 24) & 0x7f, version & 0xff_ffff)
+    }
+
+    pub fn impl_id() -> usize {
+        call(GET_IMPL_ID, 0).unwrap_or(0)
+    }
+
+    pub fn impl_version() -> usize {
+        call(GET_IMPL_VERSION, 0).unwrap_or(0)
+    }
+
+    /// Whether the extension `eid` is available.
+    pub fn probe_extension(eid: usize) -> bool {
+        call(PROBE_EXTENSION, eid).is_ok_and(|available| available != 0)
+    }
+
+    pub fn mvendorid() -> usize {
+        call(GET_MVENDORID, 0).unwrap_or(0)
+    }
+
+    pub fn marchid() -> usize {
+        call(GET_MARCHID, 0).unwrap_or(0)
+    }
+
+    pub fn mimpid() -> usize {
+        call(GET_MIMPID, 0).unwrap_or(0)
+    }
+}
+
+/// The timer extension.
+pub mod time {
+    use super::{Result, ecall, split};
+
+    pub const EID: usize = 0x5449_4d45;
+
+    pub const SET_TIMER: usize = 0;
+
+    /// Program the timer to fire once `time` reaches `deadline`, this also clears a pending timer
+    /// interrupt. A deadline of `u64::MAX` disables the timer.
+    pub fn set_timer(deadline: u64) -> Result<()> {
+        let (low, high) = split(deadline);
+        // SAFETY: Setting the timer only affects the supervisor timer interrupt.
+        unsafe { ecall(EID, SET_TIMER, [low, high, 0, 0, 0, 0]) }.map(|_| ())
+    }
+}
+
+/// The inter-processor interrupt extension.
+pub mod ipi {
+    use super::{HartMask, Result, ecall};
+
+    pub const EID: usize = 0x73_5049;
+
+    pub const SEND_IPI: usize = 0;
+
+    /// Raise a supervisor software interrupt on every hart in `harts`.
+    pub fn send_ipi(harts: HartMask) -> Result<()> {
+        // SAFETY: Sending an IPI only affects the supervisor software interrupt.
+        unsafe { ecall(EID, SEND_IPI, [harts.mask, harts.base, 0, 0, 0, 0]) }.map(|_| ())
+    }
+}
+
+/// The remote fence extension.
+pub mod rfence {
+    use super::{HartMask, Result, ecall};
+
+    pub const EID: usize = 0x5246_4e43;
+
+    pub const REMOTE_FENCE_I: usize = 0;
+    pub const REMOTE_SFENCE_VMA: usize = 1;
+    pub const REMOTE_SFENCE_VMA_ASID: usize = 2;
+    pub const REMOTE_HFENCE_GVMA_VMID: usize = 3;
+    pub const REMOTE_HFENCE_GVMA: usize = 4;
+    pub const REMOTE_HFENCE_VVMA_ASID: usize = 5;
+    pub const REMOTE_HFENCE_VVMA: usize = 6;
+
+    /// Execute `fence.i` on every hart in `harts`.
+    pub fn remote_fence_i(harts: HartMask) -> Result<()> {
+        // SAFETY: Fences never invalidate memory the kernel relies on.
+        unsafe { ecall(EID, REMOTE_FENCE_I, [harts.mask, harts.base, 0, 0, 0, 0]) }.map(|_| ())
+    }
+
+    /// Execute `sfence.vma` for the range `start..start + size` on every hart in `harts`.
+    ///
+    /// A `start` and `size` of `0` and `usize::MAX` flush everything.
+    ///
+    pub fn remote_sfence_vma(harts: HartMask, start: usize, size: usize) -> Result<()> {
+        let args = [harts.mask, harts.base, start, size, 0, 0];
+        // SAFETY: Fences never invalidate memory the kernel relies on.
+        unsafe { ecall(EID, REMOTE_SFENCE_VMA, args) }.map(|_| ())
+    }
+
+    /// Like [`remote_sfence_vma`], but only for the address space `asid`.
+    pub fn remote_sfence_vma_asid(
+        harts: HartMask,
+        start: usize,
+        size: usize,
+        asid: usize,
+    ) -> Result<()> {
+        let args = [harts.mask, harts.base, start, size, asid, 0];
+        // SAFETY: Fences never invalidate memory the kernel relies on.
+        unsafe { ecall(EID, REMOTE_SFENCE_VMA_ASID, args) }.map(|_| ())
+    }
+}
+
+/// The hart state management extension.
+pub mod hsm {
+    use super::{Error, Result, ecall};
+
+    pub const EID: usize = 0x48_534d;
+
+    pub const HART_START: usize = 0;
+    pub const HART_STOP: usize = 1;
+    pub const HART_GET_STATUS: usize = 2;
+    pub const HART_SUSPEND: usize = 3;
+
+    /// The states a hart can be in.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum HartState {
+        Started = 0,
+        Stopped = 1,
+        StartPending = 2,
+        StopPending = 3,
+        Suspended = 4,
+        SuspendPending = 5,
+        ResumePending = 6,
+    }
+
+    impl HartState {
+        pub fn from_code(code: usize) -> Option<HartState> {
+            Some(match code {
+                0 => HartState::Started,
+                1 => HartState::Stopped,
+                2 => HartState::StartPending,
+                3 => HartState::StopPending,
+                4 => HartState::Suspended,
+                5 => HartState::SuspendPending,
+                6 => HartState::ResumePending,
+                _ => return None,
+            })
+        }
+    }
+
+    /// Start the stopped hart `hart` at `start`, in S-mode with the MMU off.
+    /// It is passed its hart ID in `a0` and `opaque` in `a1`.
+    ///
+    /// # Safety
+    ///
+    /// `start` must be a valid entry point for a hart, like [`_start`](crate::hal::boot).
+    ///
+    pub unsafe fn hart_start(hart: usize, start: usize, opaque: usize) -> Result<()> {
+        // SAFETY: The caller guarantees `start` is a valid entry point.
+        unsafe { ecall(EID, HART_START, [hart, start, opaque, 0, 0, 0]) }.map(|_| ())
+    }
+
+    /// Stop the calling hart, only returns on failure.
+    pub fn hart_stop() -> Result<()> {
+        // SAFETY: Stopping never returns on success, so nothing can observe it.
+        unsafe { ecall(EID, HART_STOP, [0; 6]) }.map(|_| ())
+    }
+
+    pub fn hart_status(hart: usize) -> Result<HartState> {
+        // SAFETY: Querying the status has no side effects.
+        let code = unsafe { ecall(EID, HART_GET_STATUS, [hart, 0, 0, 0, 0, 0]) }?;
+        HartState::from_code(code).ok_or(Error::Failed)
+    }
+}
+
+/// The system reset extension.
+pub mod srst {
+    use super::{Error, ecall};
+
+    pub const EID: usize = 0x5352_5354;
+
+    pub const SYSTEM_RESET: usize = 0;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ResetType {
+        Shutdown = 0,
+        ColdReboot = 1,
+        WarmReboot = 2,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ResetReason {
+        None = 0,
+        SystemFailure = 1,
+    }
+
+    /// Reset the system, only returns on failure.
+    pub fn system_reset(kind: ResetType, reason: ResetReason) -> Error {
+        let args = [kind as usize, reason as usize, 0, 0, 0, 0];
+        // SAFETY: Resetting never returns on success, so nothing can observe it.
+        match unsafe { ecall(EID, SYSTEM_RESET, args) } {
+            Ok(_) => Error::Failed,
+            Err(error) => error,
+        }
+    }
+}
@@ -0,0 +1,439 @@
+//! A minimal SBI implementation, for when lightning owns M-mode but runs its kernel in S-mode.
+//!
+//! This lets the same S-mode kernel run with `-bios none` as it would under OpenSBI.
+//! It drives the timer and software interrupts through the [`aclint`], and shuts down through the
+//! SiFive test device, both found through the [`BootInfo`](crate::hal::boot::BootInfo).
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use riscv::register::mhartid;
+
+use crate::hal::{
+    board::MAX_HARTS,
+    boot,
+    interrupts::aclint::{self, Mswi, Mtimer},
+    power::{self, TEST_FAIL, TEST_PASS, TEST_RESET},
+};
+
+use super::{
+    Error, HartMask, Result, base, hsm,
+    hsm::HartState,
+    ipi, rfence,
+    srst::{self, ResetReason, ResetType},
+    time,
+};
+
+/// The SBI specification version we implement, v2.0.
+const SPEC_VERSION: usize = 2 << 24;
+/// Our implementation ID, outside of the range of registered implementations.
+const IMPL_ID: usize = 0x4c49_4748;
+const IMPL_VERSION: usize = 1;
+
+const MIP_SSIP: usize = 1 << 1;
+const MIP_STIP: usize = 1 << 5;
+const MIE_MSIE: usize = 1 << 3;
+const MIE_MTIE: usize = 1 << 7;
+const MSTATUS_SIE: usize = 1 << 1;
+const MSTATUS_SPIE: usize = 1 << 5;
+const MSTATUS_MPP: usize = 0b11 << 11;
+const MSTATUS_MPP_SUPERVISOR: usize = 0b01 << 11;
+/// `menvcfg.STCE` (in `menvcfgh` on 32-bit), lets S-mode use `stimecmp`.
+#[cfg(target_pointer_width = "32")]
+const MENVCFGH_STCE: usize = 1 << 31;
+#[cfg(target_pointer_width = "64")]
+const MENVCFG_STCE: usize = 1 << 63;
+
+/// Requests from other harts, handled when the software interrupt arrives.
+///
+/// Flags are only ever set by requesters and cleared by the target, so no read-modify-write
+/// atomics are needed. Fences have a flag per requesting hart, which is cleared once the fence is
+/// done, so the requester can wait for it.
+///
+struct Requests {
+    ipi: AtomicBool,
+    fence_i: [AtomicBool; MAX_HARTS],
+    sfence_vma: [AtomicBool; MAX_HARTS],
+    start: AtomicBool,
+    start_address: AtomicUsize,
+    start_opaque: AtomicUsize,
+}
+
+impl Requests {
+    const fn new() -> Requests {
+        Requests {
+            ipi: AtomicBool::new(false),
+            fence_i: [const { AtomicBool::new(false) }; MAX_HARTS],
+            sfence_vma: [const { AtomicBool::new(false) }; MAX_HARTS],
+            start: AtomicBool::new(false),
+            start_address: AtomicUsize::new(0),
+            start_opaque: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Read a CSR by name.
+macro_rules! csr {
+    ($name:literal) => {{
+        let value: usize;
+        asm!(concat!("csrr {}, ", $name), out(reg) value);
+        value
+    }};
+}
+
+static REQUESTS: [Requests; MAX_HARTS] = [const { Requests::new() }; MAX_HARTS];
+
+/// The [`HartState`] of every hart, harts start out as [`HartState::Started`] (`0`).
+static STATES: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+fn csr_set_mip(bits: usize) {
+    // SAFETY: Only used for the supervisor pending bits, which are ours to inject.
+    unsafe { asm!("csrs mip, {}", in(reg) bits) }
+}
+
+fn csr_clear_mip(bits: usize) {
+    // SAFETY: Only used for the supervisor pending bits, which are ours to inject.
+    unsafe { asm!("csrc mip, {}", in(reg) bits) }
+}
+
+fn csr_set_mie(bits: usize) {
+    // SAFETY: Only used for the M-mode interrupts the firmware handles.
+    unsafe { asm!("csrs mie, {}", in(reg) bits) }
+}
+
+fn csr_clear_mie(bits: usize) {
+    // SAFETY: Only used for the M-mode interrupts the firmware handles.
+    unsafe { asm!("csrc mie, {}", in(reg) bits) }
+}
+
+/// Whether `hart` exists, and is one we brought up.
+fn is_valid_hart(hart: usize) -> bool {
+    let cpus = &boot::info().cpus;
+    hart < MAX_HARTS && (cpus.is_empty() || cpus.iter().any(|cpu| cpu.id == hart))
+}
+
+/// Raise (or clear) the M-mode software interrupt of `hart`.
+fn write_msip(hart: usize, pending: bool) -> Result<()> {
+    let mswi = Mswi::get().ok_or(Error::NotSupported)?;
+    mswi.set_pending(hart, pending);
+    Ok(())
+}
+
+/// Program the `mtimecmp` register of the current hart.
+fn write_mtimecmp(deadline: u64) -> Result<()> {
+    let mtimer = Mtimer::get().ok_or(Error::NotSupported)?;
+    mtimer.set_compare(mhartid::read(), deadline);
+    Ok(())
+}
+
+/// Prepare the current hart for running an S-mode kernel on top of the firmware.
+///
+/// # Safety
+///
+/// MUST be called in M-mode, with the firmware installed as the M-mode trap handler.
+///
+pub(crate) unsafe fn init() {
+    // SAFETY: The caller guarantees we are in M-mode.
+    unsafe {
+        // Allow S-mode (and U-mode) to read `cycle`, `time` and `instret`.
+        asm!("csrw mcounteren, {}", in(reg) 0b111);
+
+        // Let S-mode program its own timer, instead of going through us. (`menvcfg` is 0x30a,
+        // `menvcfgh` 0x31a, it only exists on harts recent enough to have Sstc)
+        if aclint::has_sstc(mhartid::read()) {
+            #[cfg(target_pointer_width = "32")]
+            asm!("csrs 0x31a, {}", in(reg) MENVCFGH_STCE);
+            #[cfg(target_pointer_width = "64")]
+            asm!("csrs 0x30a, {}", in(reg) MENVCFG_STCE);
+        }
+    }
+
+    STATES[mhartid::read()].store(HartState::Started as usize, Ordering::Release);
+    csr_set_mie(MIE_MSIE);
+}
+
+/// Handle an `ecall` from S-mode.
+pub(crate) fn ecall(eid: usize, fid: usize, args: [usize; 6]) -> Result<usize> {
+    match eid {
+        base::EID => base(fid, args),
+        time::EID => time(fid, args),
+        ipi::EID => ipi(fid, args),
+        rfence::EID => rfence(fid, args),
+        hsm::EID => hsm(fid, args),
+        srst::EID => srst(fid, args),
+        _ => Err(Error::NotSupported),
+    }
+}
+
+fn base(fid: usize, args: [usize; 6]) -> Result<usize> {
+    Ok(match fid {
+        base::GET_SPEC_VERSION => SPEC_VERSION,
+        base::GET_IMPL_ID => IMPL_ID,
+        base::GET_IMPL_VERSION => IMPL_VERSION,
+        base::PROBE_EXTENSION => match args[0] {
+            base::EID | time::EID | ipi::EID | rfence::EID | hsm::EID => 1,
+            srst::EID => boot::info().power.is_some() as usize,
+            _ => 0,
+        },
+        // SAFETY: The ID registers are always readable in M-mode, and have no side effects.
+        base::GET_MVENDORID => unsafe { csr!("mvendorid") },
+        base::GET_MARCHID => unsafe { csr!("marchid") },
+        base::GET_MIMPID => unsafe { csr!("mimpid") },
+        _ => return Err(Error::NotSupported),
+    })
+}
+
+fn time(fid: usize, args: [usize; 6]) -> Result<usize> {
+    if fid != time::SET_TIMER {
+        return Err(Error::NotSupported);
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    let deadline = args[0] as u64 | (args[1] as u64) << 32;
+    #[cfg(target_pointer_width = "64")]
+    let deadline = args[0] as u64;
+
+    // With Sstc enabled `mip.STIP` follows `stimecmp`, so the timer can not be forwarded.
+    if aclint::has_sstc(mhartid::read()) {
+        // SAFETY: The hart has Sstc, and we are in M-mode.
+        unsafe { aclint::write_stimecmp(deadline) };
+        return Ok(0);
+    }
+
+    write_mtimecmp(deadline)?;
+    csr_clear_mip(MIP_STIP);
+    csr_set_mie(MIE_MTIE);
+    Ok(0)
+}
+
+/// Every valid hart in `harts`.
+fn harts(harts: HartMask) -> impl Iterator<Item = usize> {
+    (0..MAX_HARTS).filter(move |&hart| harts.contains(hart) && is_valid_hart(hart))
+}
+
+fn ipi(fid: usize, args: [usize; 6]) -> Result<usize> {
+    if fid != ipi::SEND_IPI {
+        return Err(Error::NotSupported);
+    }
+
+    let mask = HartMask {
+        mask: args[0],
+        base: args[1],
+    };
+
+    for hart in harts(mask) {
+        REQUESTS[hart].ipi.store(true, Ordering::Release);
+        write_msip(hart, true)?;
+    }
+    Ok(0)
+}
+
+fn rfence(fid: usize, args: [usize; 6]) -> Result<usize> {
+    let mask = HartMask {
+        mask: args[0],
+        base: args[1],
+    };
+
+    let this = mhartid::read();
+    // Ranges and ASIDs are not tracked, every remote `sfence.vma` flushes everything.
+    let request = |hart: usize| match fid {
+        rfence::REMOTE_FENCE_I => Ok(&REQUESTS[hart].fence_i[this]),
+        rfence::REMOTE_SFENCE_VMA | rfence::REMOTE_SFENCE_VMA_ASID => {
+            Ok(&REQUESTS[hart].sfence_vma[this])
+        }
+        _ => Err(Error::NotSupported),
+    };
+
+    for hart in harts(mask) {
+        let flag = request(hart)?;
+        flag.store(true, Ordering::Release);
+
+        if hart == this {
+            handle_requests();
+        } else {
+            write_msip(hart, true)?;
+        }
+
+        // Interrupts are off while we wait, so serve the requests of other harts ourselves, which
+        // may be waiting for us as well.
+        while flag.load(Ordering::Acquire) {
+            handle_requests();
+            core::hint::spin_loop();
+        }
+    }
+    Ok(0)
+}
+
+fn hsm(fid: usize, args: [usize; 6]) -> Result<usize> {
+    match fid {
+        hsm::HART_START => {
+            let (hart, address, opaque) = (args[0], args[1], args[2]);
+            if !is_valid_hart(hart) {
+                return Err(Error::InvalidParam);
+            }
+
+            if STATES[hart].load(Ordering::Acquire) != HartState::Stopped as usize {
+                return Err(Error::AlreadyAvailable);
+            }
+
+            let requests = &REQUESTS[hart];
+            requests.start_address.store(address, Ordering::Relaxed);
+            requests.start_opaque.store(opaque, Ordering::Relaxed);
+            STATES[hart].store(HartState::StartPending as usize, Ordering::Relaxed);
+            requests.start.store(true, Ordering::Release);
+            write_msip(hart, true)?;
+            Ok(0)
+        }
+        hsm::HART_STOP => stop(),
+        hsm::HART_GET_STATUS => {
+            let hart = args[0];
+            if !is_valid_hart(hart) {
+                return Err(Error::InvalidParam);
+            }
+
+            Ok(STATES[hart].load(Ordering::Acquire))
+        }
+        hsm::HART_SUSPEND => {
+            // Only the default retentive suspend, which is just waiting for an interrupt.
+            if args[0] != 0 {
+                return Err(Error::NotSupported);
+            }
+
+            // SAFETY: Waiting for an interrupt has no side effects.
+            unsafe { asm!("wfi") };
+            Ok(0)
+        }
+        _ => Err(Error::NotSupported),
+    }
+}
+
+fn srst(fid: usize, args: [usize; 6]) -> Result<usize> {
+    if fid != srst::SYSTEM_RESET {
+        return Err(Error::NotSupported);
+    }
+
+    let kind = match args[0] {
+        0 => ResetType::Shutdown,
+        1 => ResetType::ColdReboot,
+        2 => ResetType::WarmReboot,
+        _ => return Err(Error::InvalidParam),
+    };
+
+    let reason = match args[1] {
+        0 => ResetReason::None,
+        1 => ResetReason::SystemFailure,
+        // Reserved for future use by the specification, the rest is vendor or SBI specific.
+        2..0xf000_0000 => return Err(Error::InvalidParam),
+        _ => ResetReason::SystemFailure,
+    };
+
+    if boot::info().power.is_none() {
+        return Err(Error::NotSupported);
+    }
+
+    let value = match (kind, reason) {
+        (ResetType::Shutdown, ResetReason::None) => TEST_PASS,
+        (ResetType::Shutdown, ResetReason::SystemFailure) => TEST_FAIL | 1 << 16,
+        (ResetType::ColdReboot | ResetType::WarmReboot, _) => TEST_RESET,
+    };
+
+    power::write_test_device(value);
+
+    // The write should have taken effect, if we get here the device did not do its job.
+    Err(Error::Failed)
+}
+
+/// Give up on a trap of the S-mode kernel we do not handle, by shutting down with `mcause` as the
+/// failure code (QEMU exits with `mcause << 1 | 1`), or stopping the hart without a test device.
+///
+/// Panicking is no option, the panic handler thinks it runs in S-mode and would call us again.
+///
+pub(crate) fn fail(cause: usize) -> ! {
+    power::write_test_device(TEST_FAIL | (cause as u32 & 0xffff) << 16);
+
+    loop {
+        // SAFETY: Waiting for an interrupt has no side effects, `mstatus.MIE` is clear while in
+        // the trap handler, so nothing wakes us up for long.
+        unsafe { asm!("wfi") };
+    }
+}
+
+/// Stop the current hart, until another hart starts it again through [`hsm::HART_START`].
+fn stop() -> ! {
+    let hart = mhartid::read();
+    let requests = &REQUESTS[hart];
+
+    STATES[hart].store(HartState::Stopped as usize, Ordering::Release);
+    csr_clear_mie(MIE_MTIE);
+
+    while !requests.start.load(Ordering::Acquire) {
+        // SAFETY: Waiting for an interrupt has no side effects, `mstatus.MIE` is clear while in
+        // the trap handler, so the software interrupt only wakes us up.
+        unsafe { asm!("wfi") };
+    }
+
+    requests.start.store(false, Ordering::Relaxed);
+    let _ = write_msip(hart, false);
+
+    let address = requests.start_address.load(Ordering::Relaxed);
+    let opaque = requests.start_opaque.load(Ordering::Relaxed);
+    STATES[hart].store(HartState::Started as usize, Ordering::Release);
+
+    // SAFETY: As required by the specification, the hart enters S-mode at `address` with
+    // translation and S-mode interrupts disabled. The trap stack we are on is abandoned.
+    unsafe {
+        asm!(
+            "csrw satp, zero",
+            "csrc mstatus, {clear}",
+            "csrs mstatus, {set}",
+            "csrw mepc, {address}",
+            "mret",
+            clear = in(reg) MSTATUS_MPP | MSTATUS_SIE | MSTATUS_SPIE,
+            set = in(reg) MSTATUS_MPP_SUPERVISOR,
+            address = in(reg) address,
+            in("a0") hart,
+            in("a1") opaque,
+            options(noreturn),
+        )
+    }
+}
+
+/// Handle the requests of other harts.
+fn handle_requests() {
+    let requests = &REQUESTS[mhartid::read()];
+
+    if requests.ipi.load(Ordering::Acquire) {
+        requests.ipi.store(false, Ordering::Release);
+        csr_set_mip(MIP_SSIP);
+    }
+
+    for flag in requests.fence_i.iter() {
+        if flag.load(Ordering::Acquire) {
+            // SAFETY: Fences have no side effects besides ordering.
+            unsafe { asm!("fence.i") };
+            flag.store(false, Ordering::Release);
+        }
+    }
+
+    for flag in requests.sfence_vma.iter() {
+        if flag.load(Ordering::Acquire) {
+            // SAFETY: Fences have no side effects besides ordering.
+            unsafe { asm!("sfence.vma") };
+            flag.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Handle the M-mode timer interrupt, by passing it on to S-mode.
+pub(crate) fn timer() {
+    csr_clear_mie(MIE_MTIE);
+    csr_set_mip(MIP_STIP);
+}
+
+/// Handle the M-mode software interrupt, which other harts use to send us requests.
+pub(crate) fn software() {
+    let _ = write_msip(mhartid::read(), false);
+    handle_requests();
+}
@@ -0,0 +1,420 @@
+//! System calls, how user code asks the kernel for something.
+//!
+//! User code makes a system call with `ecall`. The number is in `a7`, or in `t0` on RV32E (which
+//! has no `a7`). Up to 6 arguments are in `a0` to `a5`. The results come back in `a0` and `a1`,
+//! all other registers are preserved. A failed call returns the negated [`Errno`] in `a0`, so
+//! values from `-4095` to `-1` are errors, like on Linux.
+//!
+//! The kernel [`register`]s a [`SysCallHandler`] for every number, and hands the calls of user
+//! code to [`dispatch`]. Handlers decode their arguments with [`Caller::args`], and only reach
+//! user memory through [`Caller::read`] and friends, which check it against the [`UserMemory`] of
+//! the caller.
+
+use core::{array, cell::RefCell, marker::PhantomData, ptr};
+
+use critical_section::Mutex;
+
+use super::{
+    boot::Region,
+    mmu::{AddressSpace, Flags, PAGE_SIZE},
+    trap::TrapFrame,
+    user::Regions,
+};
+
+mod riscv;
+
+pub use riscv::NUMBER;
+
+/// The amount of system call numbers handlers can be registered for.
+pub const MAX_SYSCALLS: usize = 64;
+/// The amount of argument registers.
+pub const ARGS: usize = 6;
+
+static TABLE: Mutex<RefCell<[Option<SysCallHandler>; MAX_SYSCALLS]>> =
+    Mutex::new(RefCell::new([None; MAX_SYSCALLS]));
+
+/// Carries out a system call, returning the values for `a0` and `a1`.
+pub type SysCallHandler = fn(&Caller) -> Result<[usize; 2], Errno>;
+
+/// Why a system call failed, the numbers are those of Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    /// `EPERM`
+    NotPermitted,
+    /// `ENOENT`
+    NotFound,
+    /// `EIO`
+    Io,
+    /// `EAGAIN`
+    WouldBlock,
+    /// `ENOMEM`
+    OutOfMemory,
+    /// `EFAULT`, a pointer outside of the memory of the caller, or misaligned for its type.
+    BadAddress,
+    /// `EBUSY`
+    Busy,
+    /// `EINVAL`
+    InvalidArgument,
+    /// `ERANGE`
+    OutOfRange,
+    /// `ENOSYS`, there is no system call with the number.
+    NotImplemented,
+}
+
+impl Errno {
+    /// The error number, the call returns its negation.
+    pub fn code(self) -> usize {
+        match self {
+            Errno::NotPermitted => 1,
+            Errno::NotFound => 2,
+            Errno::Io => 5,
+            Errno::WouldBlock => 11,
+            Errno::OutOfMemory => 12,
+            Errno::BadAddress => 14,
+            Errno::Busy => 16,
+            Errno::InvalidArgument => 22,
+            Errno::OutOfRange => 34,
+            Errno::NotImplemented => 38,
+        }
+    }
+}
+
+/// The memory the caller of a system call can reach, which its pointers are checked against.
+pub trait UserMemory {
+    /// Whether the caller may access all of `region` as `flags` (read and/or write) say.
+    fn allows(&self, region: Region, flags: Flags) -> bool;
+}
+
+/// The regions of a [`Context`](super::user::Context), for M-mode kernels.
+impl UserMemory for Regions {
+    fn allows(&self, region: Region, flags: Flags) -> bool {
+        self.0.iter().flatten().any(|&(allowed, allows)| {
+            allowed.start <= region.start && region.end() <= allowed.end() && allows.contains(flags)
+        })
+    }
+}
+
+/// The user pages of an address space, for S-mode kernels.
+impl UserMemory for AddressSpace {
+    fn allows(&self, region: Region, flags: Flags) -> bool {
+        let first = region.start / PAGE_SIZE * PAGE_SIZE;
+        (first..region.end()).step_by(PAGE_SIZE).all(|page| {
+            self.translate(page)
+                .is_some_and(|(_, mapping)| mapping.contains(flags | Flags::USER))
+        })
+    }
+}
+
+/// Types any bit pattern is a valid value of, so they can be copied from user memory.
+///
+/// # Safety
+///
+/// The type MUST NOT have invalid values or padding, like `bool` or references.
+///
+pub unsafe trait Plain: Copy {}
+
+macro_rules! plain {
+    ($($type:ty),*) => {
+        // SAFETY: Integers have neither invalid values nor padding.
+        $(unsafe impl Plain for $type {})*
+    };
+}
+
+plain!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+// SAFETY: Arrays of plain types have no padding between their elements.
+unsafe impl<T: Plain, const N: usize> Plain for [T; N] {}
+
+/// A pointer into the memory of the caller, which is only checked when it is used.
+#[derive(Debug)]
+pub struct UserPtr<T> {
+    pub address: usize,
+    _type: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+/// `len` elements in the memory of the caller, passed as a pointer and a length argument.
+#[derive(Debug)]
+pub struct UserSlice<T> {
+    pub address: usize,
+    pub len: usize,
+    _type: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserSlice<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserSlice<T> {}
+
+/// An argument of a system call, decoded from the argument registers.
+pub trait Arg: Sized {
+    /// Decode the argument from the first of `registers`, taking the ones it uses.
+    fn decode(registers: &mut &[usize]) -> Result<Self, Errno>;
+}
+
+/// Take the next argument register.
+///
+/// # Panics
+///
+/// If the arguments take more than the [`ARGS`] registers there are.
+///
+fn next(registers: &mut &[usize]) -> usize {
+    let (&first, rest) = registers
+        .split_first()
+        .expect("system calls have at most 6 argument registers");
+    *registers = rest;
+    first
+}
+
+impl Arg for usize {
+    fn decode(registers: &mut &[usize]) -> Result<usize, Errno> {
+        Ok(next(registers))
+    }
+}
+
+impl Arg for isize {
+    fn decode(registers: &mut &[usize]) -> Result<isize, Errno> {
+        Ok(next(registers) as isize)
+    }
+}
+
+macro_rules! narrow_arg {
+    ($register:ty => $($type:ty),*) => {
+        $(
+            /// Values that do not fit are invalid.
+            impl Arg for $type {
+                fn decode(registers: &mut &[usize]) -> Result<$type, Errno> {
+                    let value = <$register>::decode(registers)?;
+                    <$type>::try_from(value).map_err(|_| Errno::InvalidArgument)
+                }
+            }
+        )*
+    };
+}
+
+narrow_arg!(usize => u8, u16);
+narrow_arg!(isize => i8, i16, i32);
+
+/// The RV64 calling convention passes 32 bit values sign-extended, even unsigned ones, so the
+/// upper bits must all equal bit 31. Zero-extended values are taken as well, other values that do
+/// not fit are invalid.
+impl Arg for u32 {
+    fn decode(registers: &mut &[usize]) -> Result<u32, Errno> {
+        let value = next(registers);
+        let narrow = value as u32;
+
+        if value == narrow as usize || value == narrow as i32 as usize {
+            Ok(narrow)
+        } else {
+            Err(Errno::InvalidArgument)
+        }
+    }
+}
+
+/// `0` or `1`, anything else is invalid.
+impl Arg for bool {
+    fn decode(registers: &mut &[usize]) -> Result<bool, Errno> {
+        match next(registers) {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Errno::InvalidArgument),
+        }
+    }
+}
+
+impl<T> Arg for UserPtr<T> {
+    fn decode(registers: &mut &[usize]) -> Result<UserPtr<T>, Errno> {
+        Ok(UserPtr {
+            address: next(registers),
+            _type: PhantomData,
+        })
+    }
+}
+
+/// Takes two registers, the address and the length.
+impl<T> Arg for UserSlice<T> {
+    fn decode(registers: &mut &[usize]) -> Result<UserSlice<T>, Errno> {
+        Ok(UserSlice {
+            address: next(registers),
+            len: next(registers),
+            _type: PhantomData,
+        })
+    }
+}
+
+macro_rules! tuple_arg {
+    ($($name:ident),*) => {
+        /// The arguments one after the other.
+        impl<$($name: Arg),*> Arg for ($($name,)*) {
+            #[allow(unused_variables)]
+            fn decode(registers: &mut &[usize]) -> Result<Self, Errno> {
+                Ok(($($name::decode(registers)?,)*))
+            }
+        }
+    };
+}
+
+tuple_arg!();
+tuple_arg!(A);
+tuple_arg!(A, B);
+tuple_arg!(A, B, C);
+tuple_arg!(A, B, C, D);
+tuple_arg!(A, B, C, D, E);
+tuple_arg!(A, B, C, D, E, F);
+
+/// The caller of a system call, as a [`SysCallHandler`] sees it.
+pub struct Caller<'a> {
+    /// The number of the system call.
+    pub number: usize,
+    registers: [usize; ARGS],
+    memory: &'a dyn UserMemory,
+}
+
+impl Caller<'_> {
+    /// Decode the arguments, usually a tuple like `(UserSlice<u8>, u32)`.
+    ///
+    /// # Panics
+    ///
+    /// If they take more than the [`ARGS`] registers there are.
+    ///
+    pub fn args<A: Arg>(&self) -> Result<A, Errno> {
+        A::decode(&mut &self.registers[..])
+    }
+
+    /// Read the value `pointer` points to.
+    pub fn read<T: Plain>(&self, pointer: UserPtr<T>) -> Result<T, Errno> {
+        self.check::<T>(pointer.address, 1, Flags::READ)?;
+
+        // SAFETY: The caller may read it, and any bits are a valid `T`.
+        Ok(riscv::with_user_access(|| unsafe {
+            ptr::read_volatile(pointer.address as *const T)
+        }))
+    }
+
+    /// Write `value` to where `pointer` points to.
+    pub fn write<T: Plain>(&self, pointer: UserPtr<T>, value: T) -> Result<(), Errno> {
+        self.check::<T>(pointer.address, 1, Flags::WRITE)?;
+
+        // SAFETY: The caller may write it, and it is not memory of the kernel.
+        riscv::with_user_access(|| unsafe {
+            ptr::write_volatile(pointer.address as *mut T, value)
+        });
+        Ok(())
+    }
+
+    /// Copy the start of `slice` into `buffer`.
+    ///
+    /// # Returns
+    ///
+    /// The amount of elements copied, as many as both have.
+    ///
+    pub fn read_slice<T: Plain>(
+        &self,
+        slice: UserSlice<T>,
+        buffer: &mut [T],
+    ) -> Result<usize, Errno> {
+        let len = slice.len.min(buffer.len());
+        self.check::<T>(slice.address, len, Flags::READ)?;
+
+        // SAFETY: The caller may read the elements, which can not overlap the buffer of the kernel.
+        riscv::with_user_access(|| unsafe {
+            ptr::copy_nonoverlapping(slice.address as *const T, buffer.as_mut_ptr(), len)
+        });
+        Ok(len)
+    }
+
+    /// Copy `data` to the start of `slice`.
+    ///
+    /// # Returns
+    ///
+    /// The amount of elements copied, as many as both have.
+    ///
+    pub fn write_slice<T: Plain>(&self, slice: UserSlice<T>, data: &[T]) -> Result<usize, Errno> {
+        let len = slice.len.min(data.len());
+        self.check::<T>(slice.address, len, Flags::WRITE)?;
+
+        // SAFETY: The caller may write the elements, which can not overlap the data of the kernel.
+        riscv::with_user_access(|| unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), slice.address as *mut T, len)
+        });
+        Ok(len)
+    }
+
+    /// Check that the caller may access `count` elements of `T` at `address` as `flags` say.
+    fn check<T>(&self, address: usize, count: usize, flags: Flags) -> Result<(), Errno> {
+        if count == 0 {
+            return Ok(());
+        }
+
+        let size = count
+            .checked_mul(size_of::<T>())
+            .filter(|size| address.checked_add(*size).is_some())
+            .ok_or(Errno::BadAddress)?;
+        let region = Region {
+            start: address,
+            size,
+        };
+
+        if address % align_of::<T>() != 0 || !self.memory.allows(region, flags) {
+            return Err(Errno::BadAddress);
+        }
+        Ok(())
+    }
+}
+
+/// Attach `handler` to the system call `number`, replacing the previous one.
+///
+/// # Panics
+///
+/// If `number` is at least [`MAX_SYSCALLS`].
+///
+pub fn register(number: usize, handler: SysCallHandler) {
+    set(number, Some(handler))
+}
+
+/// Detach the handler of the system call `number`, calls to it fail with
+/// [`Errno::NotImplemented`].
+pub fn unregister(number: usize) {
+    set(number, None)
+}
+
+fn set(number: usize, handler: Option<SysCallHandler>) {
+    assert!(number < MAX_SYSCALLS, "no system call {number}");
+    critical_section::with(|cs| TABLE.borrow_ref_mut(cs)[number] = handler);
+}
+
+/// Carry out the system call in `frame`, checking its pointers against `memory`, and put the
+/// results into `frame`.
+///
+/// User memory is accessed at the addresses the caller passed, so its address space has to be
+/// active. The frame already points past the `ecall`, like after
+/// [`Exit::SysCall`](super::user::Exit::SysCall).
+///
+pub fn dispatch(frame: &mut TrapFrame, memory: &dyn UserMemory) {
+    let caller = Caller {
+        number: frame.register(riscv::NUMBER),
+        registers: array::from_fn(|index| frame.arg(index)),
+        memory,
+    };
+
+    let handler =
+        critical_section::with(|cs| TABLE.borrow_ref(cs).get(caller.number).copied().flatten());
+    let result = handler.map_or(Err(Errno::NotImplemented), |handler| handler(&caller));
+
+    let [first, second] = match result {
+        Ok(values) => values,
+        Err(errno) => [errno.code().wrapping_neg(), 0],
+    };
+    frame.set_arg(0, first);
+    frame.set_arg(1, second);
+}
@@ -0,0 +1,29 @@
+use core::arch::asm;
+
+use crate::hal::{core::kernel_mode, execution::riscv::Mode};
+
+/// The register holding the number of a system call, `a7` (`x17`).
+#[cfg(not(feature = "riscv_isa_e"))]
+pub const NUMBER: usize = 17;
+/// The register holding the number of a system call, `t0` (`x5`), as RV32E stops at `x15`.
+#[cfg(feature = "riscv_isa_e")]
+pub const NUMBER: usize = 5;
+
+/// `sstatus.SUM`, lets S-mode access user pages.
+const SUM: usize = 1 << 18;
+
+/// Call `f` with access to user memory, which S-mode only has with `sstatus.SUM` set. M-mode
+/// kernels reach it at its physical address anyway.
+pub(super) fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    if kernel_mode() != Mode::Supervisor {
+        return f();
+    }
+
+    // SAFETY: Only `f` gets to access user pages, `sstatus` is restored by traps in between.
+    unsafe { asm!("csrs sstatus, {}", in(reg) SUM) }
+    let result = f();
+    // SAFETY: See above.
+    unsafe { asm!("csrc sstatus, {}", in(reg) SUM) }
+
+    result
+}
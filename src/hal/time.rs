@@ -0,0 +1,331 @@
+//! The monotonic clock, and timers on top of it.
+//!
+//! Time is counted in ticks of the timebase, whose frequency comes from the device tree. Every
+//! core keeps a queue of its own timers, and only arms the hardware timer for the nearest
+//! deadline, there is no periodic tick.
+
+use core::{
+    cell::Cell,
+    hint::spin_loop,
+    marker::PhantomData,
+    ops::{Add, AddAssign, Sub},
+    time::Duration,
+};
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+use super::interrupts::aclint;
+use super::{
+    boot,
+    core::percpu,
+    interrupts::{self, Source, without_interrupts},
+};
+
+/// The timebase frequency when the device tree has none, the one of QEMU's `virt` machine.
+const DEFAULT_FREQUENCY: u64 = 10_000_000;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// The amount of timers a core can have pending.
+pub const MAX_TIMERS: usize = 32;
+
+/// The frequency of the timebase, in Hz.
+pub fn frequency() -> u64 {
+    boot::info()
+        .timebase_frequency
+        .filter(|&frequency| frequency != 0)
+        .map_or(DEFAULT_FREQUENCY, u64::from)
+}
+
+/// The amount of ticks in `duration`, rounded up so waiting for them never ends early.
+fn to_ticks(duration: Duration) -> u64 {
+    let frequency = frequency();
+    let nanos = (duration.subsec_nanos() as u64 * frequency).div_ceil(NANOS_PER_SECOND);
+    duration
+        .as_secs()
+        .saturating_mul(frequency)
+        .saturating_add(nanos)
+}
+
+fn to_duration(ticks: u64) -> Duration {
+    let frequency = frequency();
+    let nanos = (ticks % frequency) as u128 * NANOS_PER_SECOND as u128 / frequency as u128;
+    Duration::new(ticks / frequency, nanos as u32)
+}
+
+/// A point in time, as measured by the monotonic clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The current time.
+    pub fn now() -> Instant {
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        Instant(aclint::now())
+    }
+
+    /// The instant at `ticks` of the timebase.
+    pub fn from_ticks(ticks: u64) -> Instant {
+        Instant(ticks)
+    }
+
+    /// The ticks of the timebase at this instant.
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// The time passed from `earlier` to this instant, zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// The time passed since this instant.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(to_ticks(duration)).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(to_ticks(duration)).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding a duration to an instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting a duration from an instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// A pending timer.
+#[derive(Clone, Copy)]
+struct Entry {
+    id: u64,
+    deadline: u64,
+    /// The ticks between runs, `0` for a one-shot timer.
+    period: u64,
+    callback: &'static (dyn Fn() + Sync),
+}
+
+/// The timers pending on a core.
+struct Timers {
+    entries: [Cell<Option<Entry>>; MAX_TIMERS],
+    next_id: Cell<u64>,
+}
+
+percpu! {
+    static TIMERS: Timers = Timers::new();
+}
+
+impl Timers {
+    const fn new() -> Timers {
+        Timers {
+            entries: [const { Cell::new(None) }; MAX_TIMERS],
+            next_id: Cell::new(0),
+        }
+    }
+
+    /// Queue a timer, and rearm the hardware timer if it is the nearest one now.
+    fn insert(
+        &self,
+        deadline: u64,
+        period: u64,
+        callback: &'static (dyn Fn() + Sync),
+    ) -> Option<u64> {
+        let slot = self.entries.iter().find(|slot| slot.get().is_none())?;
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+
+        slot.set(Some(Entry {
+            id,
+            deadline,
+            period,
+            callback,
+        }));
+        self.arm();
+        Some(id)
+    }
+
+    /// Remove the timer `id`.
+    ///
+    /// # Returns
+    ///
+    /// Whether it was still pending.
+    ///
+    fn remove(&self, id: u64) -> bool {
+        let slot = self
+            .entries
+            .iter()
+            .find(|slot| slot.get().is_some_and(|entry| entry.id == id));
+
+        slot.inspect(|slot| slot.set(None)).is_some()
+    }
+
+    /// Run the callbacks of the timers whose deadline has passed, then rearm the hardware timer.
+    ///
+    /// Periodic timers that fell behind skip the runs they missed, instead of catching up.
+    ///
+    fn expire(&self) {
+        let now = Instant::now().0;
+
+        for slot in self.entries.iter() {
+            let Some(entry) = slot.get().filter(|entry| entry.deadline <= now) else {
+                continue;
+            };
+
+            // Updated before the callback runs, which may cancel or queue timers itself.
+            slot.set((entry.period != 0).then(|| {
+                let next = entry.deadline.saturating_add(entry.period);
+                Entry {
+                    deadline: if next > now {
+                        next
+                    } else {
+                        now.saturating_add(entry.period)
+                    },
+                    ..entry
+                }
+            }));
+
+            (entry.callback)()
+        }
+
+        self.arm()
+    }
+
+    /// Arm the hardware timer for the nearest deadline, or disarm it without timers.
+    fn arm(&self) {
+        let nearest = self
+            .entries
+            .iter()
+            .filter_map(|slot| slot.get())
+            .map(|entry| entry.deadline)
+            .min();
+
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        aclint::set_deadline(nearest.unwrap_or(u64::MAX));
+    }
+}
+
+/// A timer queued on the current core, see [`at`], [`after`] and [`every`].
+///
+/// Dropping it does not cancel the timer. As it belongs to the core it was queued on, it can not
+/// be sent to other threads.
+///
+pub struct Timer {
+    id: u64,
+    _core_local: PhantomData<*const ()>,
+}
+
+impl Timer {
+    /// Cancel the timer, a periodic one stops running.
+    ///
+    /// # Returns
+    ///
+    /// Whether it was still pending, a one-shot timer that already ran is not.
+    ///
+    pub fn cancel(self) -> bool {
+        let timers = TIMERS.get();
+        without_interrupts(|| {
+            let removed = timers.remove(self.id);
+            timers.arm();
+            removed
+        })
+    }
+}
+
+fn queue(deadline: u64, period: u64, callback: &'static (dyn Fn() + Sync)) -> Option<Timer> {
+    let timers = TIMERS.get();
+
+    // The timer interrupt changes the queue as well.
+    without_interrupts(|| timers.insert(deadline, period, callback)).map(|id| Timer {
+        id,
+        _core_local: PhantomData,
+    })
+}
+
+/// Run `callback` on the current core once `deadline` has passed.
+///
+/// Callbacks run in the timer interrupt, with interrupts disabled.
+///
+/// # Returns
+///
+/// The timer, or `None` if the core already has [`MAX_TIMERS`] pending.
+///
+pub fn at(deadline: Instant, callback: &'static (dyn Fn() + Sync)) -> Option<Timer> {
+    queue(deadline.0, 0, callback)
+}
+
+/// Run `callback` on the current core once `delay` has passed, see [`at`].
+pub fn after(delay: Duration, callback: &'static (dyn Fn() + Sync)) -> Option<Timer> {
+    at(Instant::now() + delay, callback)
+}
+
+/// Run `callback` on the current core every `period`, starting one `period` from now, see [`at`].
+pub fn every(period: Duration, callback: &'static (dyn Fn() + Sync)) -> Option<Timer> {
+    let period = to_ticks(period).max(1);
+    queue(Instant::now().0.saturating_add(period), period, callback)
+}
+
+/// Block the current core until `deadline` has passed.
+///
+/// The core waits for interrupts in the meantime, which its timer wakes it from. Interrupts stay
+/// as they are, while they are disabled nothing else runs.
+///
+pub fn sleep_until(deadline: Instant) {
+    fn wake() {}
+
+    let timer = at(deadline, &wake);
+
+    while Instant::now() < deadline {
+        // Without a timer nothing is guaranteed to wake us up.
+        if timer.is_some() {
+            interrupts::wait()
+        } else {
+            spin_loop()
+        }
+    }
+
+    if let Some(timer) = timer {
+        timer.cancel();
+    }
+}
+
+/// Block the current core for `duration`, see [`sleep_until`].
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Run the timers of the current core from its timer interrupt.
+pub(crate) fn init() {
+    fn handle() {
+        TIMERS.get().expire()
+    }
+
+    interrupts::register(Source::Timer, &handle);
+}
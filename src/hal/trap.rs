@@ -1,20 +1,166 @@
-use super::core::{Core, CoreState};
+use core::ptr::NonNull;
+
+use super::{
+    core::{Core, CoreState},
+    interrupts::{Source, plic},
+};
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub use super::execution::riscv::Mode;
 
 #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
 mod riscv;
 
-pub type TrapHandler = fn(Trap);
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub use riscv::TrapFrame;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+pub(crate) use riscv::{TrapStacks, skip_instruction};
+
+/// A trap handler, gets the trap and the interrupted context, which it is free to modify.
+/// It decides how execution continues afterwards.
+pub type TrapHandler = fn(Trap, &mut TrapFrame) -> Resume;
+
+/// How to continue after a trap has been handled.
+#[derive(Debug)]
+pub enum Resume {
+    /// Return to where the trap happened, retrying the trapping instruction for exceptions.
+    Return,
+    /// Continue after the trapping instruction, like after an `ecall` or an emulated instruction.
+    Skip,
+    /// Continue in another context, instead of the interrupted one.
+    ///
+    /// The frame is restored in place, so it must stay valid (and not be touched by anything else)
+    /// until the next trap.
+    Switch(NonNull<TrapFrame>),
+    /// The trap can not be recovered from, this panics.
+    Fatal,
+}
 
+/// A trap, exceptions carry the address of the trapping instruction (`epc`).
+///
+/// Covers every cause of the RISC-V privileged specification, including those of the hypervisor
+/// and other optional extensions.
+///
+#[derive(Debug, Clone, Copy)]
 pub enum Trap {
     // Unknown
-    Unknown(usize),
+    /// A cause we do not know, like a platform specific interrupt.
+    Unknown {
+        interrupt: bool,
+        code: usize,
+    },
 
     // Interrupts
     Timer,
     External,
     Software,
+    /// A virtual supervisor timer interrupt, for a guest.
+    VirtualTimer,
+    /// A virtual supervisor external interrupt, for a guest.
+    VirtualExternal,
+    /// A virtual supervisor software interrupt, for a guest.
+    VirtualSoftware,
+    /// A supervisor guest external interrupt.
+    GuestExternal,
+    /// A local performance counter overflowed.
+    CounterOverflow,
 
     // Exceptions
+    Breakpoint {
+        epc: usize,
+    },
+    /// `instruction` holds the raw bits of the instruction, compressed instructions in the lower
+    /// 16 bits.
+    IllegalInstruction {
+        epc: usize,
+        instruction: u32,
+    },
+    /// `address` is the virtual address that could not be fetched.
+    InstructionPageFault {
+        epc: usize,
+        address: usize,
+    },
+    /// `address` is the virtual address that could not be loaded.
+    LoadPageFault {
+        epc: usize,
+        address: usize,
+    },
+    /// `address` is the virtual address that could not be stored to.
+    StorePageFault {
+        epc: usize,
+        address: usize,
+    },
+    InstructionFault {
+        epc: usize,
+        address: usize,
+    },
+    InstructionMisaligned {
+        epc: usize,
+        address: usize,
+    },
+    LoadFault {
+        epc: usize,
+        address: usize,
+    },
+    LoadMisaligned {
+        epc: usize,
+        address: usize,
+    },
+    StoreFault {
+        epc: usize,
+        address: usize,
+    },
+    StoreMisaligned {
+        epc: usize,
+        address: usize,
+    },
+    /// An `ecall`, `mode` is the privilege mode it was made from.
+    SysCall {
+        epc: usize,
+        mode: Mode,
+    },
+    /// An `ecall` from a guest, in virtual supervisor mode.
+    GuestSysCall {
+        epc: usize,
+    },
+    /// A trap was taken while traps were disabled for double trap detection.
+    DoubleTrap {
+        epc: usize,
+    },
+    /// A software check failed (like a control flow integrity violation), `code` says which.
+    SoftwareCheck {
+        epc: usize,
+        code: usize,
+    },
+    /// The hardware detected an uncorrectable error.
+    HardwareError {
+        epc: usize,
+    },
+    /// `address` is the guest virtual address that could not be fetched.
+    InstructionGuestPageFault {
+        epc: usize,
+        address: usize,
+    },
+    /// `address` is the guest virtual address that could not be loaded.
+    LoadGuestPageFault {
+        epc: usize,
+        address: usize,
+    },
+    /// `address` is the guest virtual address that could not be stored to.
+    StoreGuestPageFault {
+        epc: usize,
+        address: usize,
+    },
+    /// A guest executed an instruction it is not allowed to.
+    VirtualInstruction {
+        epc: usize,
+        instruction: u32,
+    },
+}
+
+/// The kinds of exceptions, the [`Trap`] variants without their details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
     Breakpoint,
     IllegalInstruction,
     InstructionPageFault,
@@ -27,10 +173,93 @@ pub enum Trap {
     StoreFault,
     StoreMisaligned,
     SysCall,
+    GuestSysCall,
+    DoubleTrap,
+    SoftwareCheck,
+    HardwareError,
+    InstructionGuestPageFault,
+    LoadGuestPageFault,
+    StoreGuestPageFault,
+    VirtualInstruction,
+}
+
+impl Exception {
+    /// The amount of kinds.
+    pub const COUNT: usize = Exception::VirtualInstruction as usize + 1;
+}
+
+impl Trap {
+    /// The kind of exception, if this is one.
+    pub fn exception(&self) -> Option<Exception> {
+        Some(match self {
+            Trap::Breakpoint { .. } => Exception::Breakpoint,
+            Trap::IllegalInstruction { .. } => Exception::IllegalInstruction,
+            Trap::InstructionPageFault { .. } => Exception::InstructionPageFault,
+            Trap::LoadPageFault { .. } => Exception::LoadPageFault,
+            Trap::StorePageFault { .. } => Exception::StorePageFault,
+            Trap::InstructionFault { .. } => Exception::InstructionFault,
+            Trap::InstructionMisaligned { .. } => Exception::InstructionMisaligned,
+            Trap::LoadFault { .. } => Exception::LoadFault,
+            Trap::LoadMisaligned { .. } => Exception::LoadMisaligned,
+            Trap::StoreFault { .. } => Exception::StoreFault,
+            Trap::StoreMisaligned { .. } => Exception::StoreMisaligned,
+            Trap::SysCall { .. } => Exception::SysCall,
+            Trap::GuestSysCall { .. } => Exception::GuestSysCall,
+            Trap::DoubleTrap { .. } => Exception::DoubleTrap,
+            Trap::SoftwareCheck { .. } => Exception::SoftwareCheck,
+            Trap::HardwareError { .. } => Exception::HardwareError,
+            Trap::InstructionGuestPageFault { .. } => Exception::InstructionGuestPageFault,
+            Trap::LoadGuestPageFault { .. } => Exception::LoadGuestPageFault,
+            Trap::StoreGuestPageFault { .. } => Exception::StoreGuestPageFault,
+            Trap::VirtualInstruction { .. } => Exception::VirtualInstruction,
+            _ => return None,
+        })
+    }
+}
+
+/// Let the core handle the trap, and carry out its [`Resume`] decision.
+///
+/// # Returns
+///
+/// The frame to resume.
+///
+fn handle_trap(state: &CoreState, trap: Trap, frame: &mut TrapFrame) -> NonNull<TrapFrame> {
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    if let Some(next) = super::user::intercept(trap, frame) {
+        return next;
+    }
+
+    if handle_interrupt(state, trap) {
+        return NonNull::from(frame);
+    }
+
+    match state.handle_trap(trap, frame) {
+        Resume::Return => {}
+        Resume::Skip =>
+        {
+            #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+            skip_instruction(frame)
+        }
+        Resume::Switch(next) => return next,
+        Resume::Fatal => panic!("unrecoverable trap {trap:?} at {:#x}", frame.epc),
+    }
+
+    NonNull::from(frame)
 }
 
-fn handle_trap(state: &CoreState, trap: Trap) {
-    state.handle_trap(trap)
+/// Call the registered [`InterruptHandler`](super::interrupts::InterruptHandler) of the
+/// interrupt, if any.
+///
+/// # Returns
+///
+/// Whether the interrupt was handled.
+///
+fn handle_interrupt(state: &CoreState, trap: Trap) -> bool {
+    match trap {
+        #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+        Trap::External => plic::handle(&state.handlers),
+        trap => Source::of(trap).is_some_and(|source| state.handlers.dispatch(source)),
+    }
 }
 
 pub fn setup_trap_handler(core: &Core) {
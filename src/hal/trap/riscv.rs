@@ -1,35 +1,241 @@
-use core::arch::naked_asm;
-
-use riscv::{
-    interrupt::{Exception, Interrupt},
-    register::{
-        mcause, mscratch, mstatus,
-        mtvec::{self, Mtvec},
-        scause, sscratch,
-        stvec::{self, Stvec, TrapMode},
-    },
+#[cfg(feature = "riscv_vectored")]
+use core::arch::global_asm;
+use core::{
+    arch::{asm, naked_asm},
+    cell::Cell,
+    mem::offset_of,
+    ptr::NonNull,
 };
 
+use riscv::register::{
+    sscratch,
+    stvec::{self, Stvec, TrapMode},
+};
+
+#[cfg(all(feature = "riscv_vectored", not(feature = "riscv_sbi")))]
+use riscv::register::mcause;
+#[cfg(feature = "riscv_vectored")]
+use riscv::register::scause;
+#[cfg(feature = "riscv_sbi")]
+use riscv::register::sstatus;
+#[cfg(not(feature = "riscv_sbi"))]
+use riscv::register::{
+    mscratch, mstatus,
+    mtvec::{self, Mtvec},
+};
+
+#[cfg(all(not(feature = "riscv_sbi"), not(feature = "riscv_isa_e")))]
+use crate::hal::sbi::firmware;
+
+#[cfg(not(feature = "riscv_sbi"))]
+use crate::hal::user;
 use crate::hal::{
-    core::{Core, CoreState},
+    board::{HART_STACK_SIZE, MAX_HARTS},
+    core::{Core, CoreState, kernel_mode},
     execution::riscv::Mode,
 };
 
+#[cfg(feature = "riscv_vectored")]
+use super::handle_interrupt;
 use super::{Trap, handle_trap};
 
+/// The amount of general purpose registers saved in a [`TrapFrame`], `x0` is not saved.
+#[cfg(feature = "riscv_isa_e")]
+const SAVED_REGISTERS: usize = 15;
+#[cfg(not(feature = "riscv_isa_e"))]
+const SAVED_REGISTERS: usize = 31;
+
+/// The stack space reserved for a [`TrapFrame`], keeping the stack 16 byte aligned.
+const FRAME_SIZE: usize = size_of::<TrapFrame>().next_multiple_of(16);
+
+/// The registers saved by the fast interrupt entries, `ra`, `sp`, the caller-saved ones, `gp` and
+/// `tp`.
+#[cfg(all(feature = "riscv_vectored", feature = "riscv_isa_e"))]
+const INTERRUPT_SAVED_REGISTERS: usize = 13;
+#[cfg(all(feature = "riscv_vectored", not(feature = "riscv_isa_e")))]
+const INTERRUPT_SAVED_REGISTERS: usize = 19;
+
+/// The stack space reserved by the fast interrupt entries, keeping the stack 16 byte aligned.
+#[cfg(feature = "riscv_vectored")]
+const INTERRUPT_FRAME_SIZE: usize =
+    (INTERRUPT_SAVED_REGISTERS * size_of::<usize>()).next_multiple_of(16);
+
+/// `sstatus.SPP`, set when the trap came from S-mode.
+const SPP: usize = 1 << 8;
+/// The position of `mstatus.MPP`, the mode the trap came from.
+#[cfg(not(feature = "riscv_sbi"))]
+const MPP_SHIFT: usize = 11;
+
+/// A trap stack, as big as the boot stack of a hart.
+#[repr(C, align(16))]
+struct Stack([u8; HART_STACK_SIZE]);
+
+/// The machine trap stacks, indexed by hart ID.
+#[cfg(not(feature = "riscv_sbi"))]
+static mut MACHINE_STACKS: [Stack; MAX_HARTS] = [const { Stack([0; HART_STACK_SIZE]) }; MAX_HARTS];
+/// The supervisor trap stacks, indexed by hart ID.
+static mut SUPERVISOR_STACKS: [Stack; MAX_HARTS] =
+    [const { Stack([0; HART_STACK_SIZE]) }; MAX_HARTS];
+
+/// What the trap entry code of one mode needs, reached through the scratch register.
+#[repr(C)]
+struct TrapContext {
+    /// The top of the trap stack, used for traps from less privileged modes.
+    top: usize,
+    /// The interrupted stack pointer, written by the entry code while it switches stacks.
+    sp: Cell<usize>,
+    /// The interrupted `t1`, written by the entry code while it switches stacks.
+    t1: Cell<usize>,
+    /// The `gp` of the kernel, the entry code loads it as user code can set `gp` to anything.
+    gp: usize,
+    /// The `tp` of the kernel, the hart ID, loaded like `gp` before any per-core data is used.
+    tp: usize,
+}
+
+impl TrapContext {
+    fn new(stack: *mut Stack, hart: usize) -> TrapContext {
+        let gp;
+        // SAFETY: Reading `gp` has no side effects.
+        unsafe { asm!("mv {}, gp", out(reg) gp, options(nomem, nostack, preserves_flags)) }
+
+        TrapContext {
+            top: stack.wrapping_add(1) as usize,
+            sp: Cell::new(0),
+            t1: Cell::new(0),
+            gp,
+            tp: hart,
+        }
+    }
+}
+
+/// The trap contexts of a core, M-mode and S-mode traps can nest, so each gets its own.
+pub struct TrapStacks {
+    #[cfg(not(feature = "riscv_sbi"))]
+    machine: TrapContext,
+    supervisor: TrapContext,
+}
+
+impl TrapStacks {
+    /// The trap stacks of `hart`, which must be lower than [`MAX_HARTS`].
+    ///
+    /// The [`CoreState`]s of a hart share them, only the loaded one uses them.
+    ///
+    pub fn new(hart: usize) -> TrapStacks {
+        assert!(hart < MAX_HARTS, "hart {hart} has no trap stacks");
+
+        TrapStacks {
+            #[cfg(not(feature = "riscv_sbi"))]
+            machine: TrapContext::new(&raw mut MACHINE_STACKS[hart], hart),
+            supervisor: TrapContext::new(&raw mut SUPERVISOR_STACKS[hart], hart),
+        }
+    }
+}
+
+/// The interrupted context, as saved by the trap entry code.
+///
+/// Changes made by the trap handler are restored when returning from the trap.
+///
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct TrapFrame {
+    /// `x1` up to and including `x31` (or `x15` with the E ISA), `x0` is always zero.
+    pub registers: [usize; SAVED_REGISTERS],
+    /// The address of the trapping instruction (`mepc`/`sepc`), execution resumes here.
+    pub epc: usize,
+    /// `mstatus`/`sstatus` at the time of the trap.
+    pub status: usize,
+    /// `mcause`/`scause`.
+    pub cause: usize,
+    /// `mtval`/`stval`, the faulting address or instruction, depending on the cause.
+    pub tval: usize,
+}
+
+impl TrapFrame {
+    /// Index of `a0`, the first argument register.
+    const A0: usize = 10;
+
+    /// A frame resuming at `epc`, with every register and the status zero.
+    pub fn new(epc: usize) -> TrapFrame {
+        TrapFrame {
+            registers: [0; SAVED_REGISTERS],
+            epc,
+            status: 0,
+            cause: 0,
+            tval: 0,
+        }
+    }
+
+    /// Get the value of register `x{index}`.
+    ///
+    /// # Panics
+    ///
+    /// If the register does not exist.
+    ///
+    pub fn register(&self, index: usize) -> usize {
+        match index {
+            0 => 0,
+            index => self.registers[index - 1],
+        }
+    }
+
+    /// Set the value of register `x{index}`, writes to `x0` are ignored.
+    ///
+    /// # Panics
+    ///
+    /// If the register does not exist.
+    ///
+    pub fn set_register(&mut self, index: usize, value: usize) {
+        if index != 0 {
+            self.registers[index - 1] = value;
+        }
+    }
+
+    /// Get argument register `a{index}`.
+    pub fn arg(&self, index: usize) -> usize {
+        self.register(Self::A0 + index)
+    }
+
+    /// Set argument register `a{index}`, which double as the return value registers.
+    pub fn set_arg(&mut self, index: usize, value: usize) {
+        self.set_register(Self::A0 + index, value)
+    }
+}
+
+/// The M-mode trap entry, saves a [`TrapFrame`] and calls [`machine_trap`].
+///
+/// `mscratch` points to the [`CoreState`]. Traps from M-mode keep using the interrupted stack, so
+/// nested traps stack up. Traps from less privileged modes switch to the machine trap stack of the
+/// core first, as their stack pointer can not be trusted (or is a virtual address).
+///
+#[cfg(not(feature = "riscv_sbi"))]
 #[unsafe(naked)]
 pub(crate) unsafe extern "C" fn machine_trap_entry() {
     #[allow(unused_unsafe)]
     unsafe {
         #[cfg(all(target_arch = "riscv32", feature = "riscv_isa_e"))]
         naked_asm!(
-            "addi sp, sp, -64",
+            "csrrw t0, mscratch, t0", // the core state, the scratch register keeps `t0`
+            "sw t1, {saved_t1}(t0)",
+            "sw sp, {saved_sp}(t0)",
+            "csrr t1, mstatus",
+            "srli t1, t1, {mpp}",
+            "andi t1, t1, 3",
+            "addi t1, t1, -3",
+            "beqz t1, 1f", // nested in M-mode, keep using its stack
+            "lw sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
             "sw x1, 0(sp)",
-            "sw x2, 4(sp)",
+            "lw x1, {saved_sp}(t0)", // the stack pointer before the trap
+            "sw x1, 4(sp)",
             "sw x3, 8(sp)",
             "sw x4, 12(sp)",
-            "sw x5, 16(sp)",
-            "sw x6, 20(sp)",
+            "lw gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "lw tp, {kernel_tp}(t0)",
+            "csrrw x1, mscratch, t0", // ready for the next trap
+            "sw x1, 16(sp)",
+            "lw x1, {saved_t1}(t0)",
+            "sw x1, 20(sp)",
             "sw x7, 24(sp)",
             "sw x8, 28(sp)",
             "sw x9, 32(sp)",
@@ -39,7 +245,21 @@ pub(crate) unsafe extern "C" fn machine_trap_entry() {
             "sw x13, 48(sp)",
             "sw x14, 52(sp)",
             "sw x15, 56(sp)",
-            "call {}",
+            "csrr t0, mepc",
+            "sw t0, {epc}(sp)",
+            "csrr t0, mstatus",
+            "sw t0, {status}(sp)",
+            "csrr t0, mcause",
+            "sw t0, {cause}(sp)",
+            "csrr t0, mtval",
+            "sw t0, {tval}(sp)",
+            "mv a0, sp",
+            "call {handler}",
+            "mv sp, a0", // the handler returns the frame to resume
+            "lw t0, {epc}(sp)",
+            "csrw mepc, t0",
+            "lw t0, {status}(sp)",
+            "csrw mstatus, t0",
             "lw x15, 56(sp)",
             "lw x14, 52(sp)",
             "lw x13, 48(sp)",
@@ -53,21 +273,46 @@ pub(crate) unsafe extern "C" fn machine_trap_entry() {
             "lw x5, 16(sp)",
             "lw x4, 12(sp)",
             "lw x3, 8(sp)",
-            "lw x2, 4(sp)",
             "lw x1, 0(sp)",
-            "addi sp, sp, 64",
+            "lw x2, 4(sp)", // last, as the stack pointer is the base of the frame
             "mret",
-            sym machine_trap,
+            handler = sym machine_trap,
+            stack = const offset_of!(CoreState, trap_stacks.machine.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.machine.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.machine.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.machine.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.machine.tp),
+            mpp = const MPP_SHIFT,
+            size = const FRAME_SIZE,
+            epc = const offset_of!(TrapFrame, epc),
+            status = const offset_of!(TrapFrame, status),
+            cause = const offset_of!(TrapFrame, cause),
+            tval = const offset_of!(TrapFrame, tval),
         );
         #[cfg(all(target_arch = "riscv32", not(feature = "riscv_isa_e")))]
         naked_asm!(
-            "addi sp, sp, -128", // twice as many registers in the I isa compared to E
+            "csrrw t0, mscratch, t0", // the core state, the scratch register keeps `t0`
+            "sw t1, {saved_t1}(t0)",
+            "sw sp, {saved_sp}(t0)",
+            "csrr t1, mstatus",
+            "srli t1, t1, {mpp}",
+            "andi t1, t1, 3",
+            "addi t1, t1, -3",
+            "beqz t1, 1f", // nested in M-mode, keep using its stack
+            "lw sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
             "sw x1, 0(sp)",
-            "sw x2, 4(sp)",
+            "lw x1, {saved_sp}(t0)", // the stack pointer before the trap
+            "sw x1, 4(sp)",
             "sw x3, 8(sp)",
             "sw x4, 12(sp)",
-            "sw x5, 16(sp)",
-            "sw x6, 20(sp)",
+            "lw gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "lw tp, {kernel_tp}(t0)",
+            "csrrw x1, mscratch, t0", // ready for the next trap
+            "sw x1, 16(sp)",
+            "lw x1, {saved_t1}(t0)",
+            "sw x1, 20(sp)",
             "sw x7, 24(sp)",
             "sw x8, 28(sp)",
             "sw x9, 32(sp)",
@@ -93,7 +338,21 @@ pub(crate) unsafe extern "C" fn machine_trap_entry() {
             "sw x29, 112(sp)",
             "sw x30, 116(sp)",
             "sw x31, 120(sp)",
-            "call {}",
+            "csrr t0, mepc",
+            "sw t0, {epc}(sp)",
+            "csrr t0, mstatus",
+            "sw t0, {status}(sp)",
+            "csrr t0, mcause",
+            "sw t0, {cause}(sp)",
+            "csrr t0, mtval",
+            "sw t0, {tval}(sp)",
+            "mv a0, sp",
+            "call {handler}",
+            "mv sp, a0", // the handler returns the frame to resume
+            "lw t0, {epc}(sp)",
+            "csrw mepc, t0",
+            "lw t0, {status}(sp)",
+            "csrw mstatus, t0",
             "lw x31, 120(sp)",
             "lw x30, 116(sp)",
             "lw x29, 112(sp)",
@@ -123,21 +382,46 @@ pub(crate) unsafe extern "C" fn machine_trap_entry() {
             "lw x5, 16(sp)",
             "lw x4, 12(sp)",
             "lw x3, 8(sp)",
-            "lw x2, 4(sp)",
             "lw x1, 0(sp)",
-            "addi sp, sp, 128",
+            "lw x2, 4(sp)", // last, as the stack pointer is the base of the frame
             "mret",
-            sym machine_trap,
+            handler = sym machine_trap,
+            stack = const offset_of!(CoreState, trap_stacks.machine.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.machine.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.machine.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.machine.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.machine.tp),
+            mpp = const MPP_SHIFT,
+            size = const FRAME_SIZE,
+            epc = const offset_of!(TrapFrame, epc),
+            status = const offset_of!(TrapFrame, status),
+            cause = const offset_of!(TrapFrame, cause),
+            tval = const offset_of!(TrapFrame, tval),
         );
         #[cfg(all(target_arch = "riscv64", feature = "riscv_isa_e"))]
         naked_asm!(
-            "addi sp, sp, -128", // 16 8 byte registers
+            "csrrw t0, mscratch, t0", // the core state, the scratch register keeps `t0`
+            "sd t1, {saved_t1}(t0)",
+            "sd sp, {saved_sp}(t0)",
+            "csrr t1, mstatus",
+            "srli t1, t1, {mpp}",
+            "andi t1, t1, 3",
+            "addi t1, t1, -3",
+            "beqz t1, 1f", // nested in M-mode, keep using its stack
+            "ld sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
             "sd x1, 0(sp)",
-            "sd x2, 8(sp)",
+            "ld x1, {saved_sp}(t0)", // the stack pointer before the trap
+            "sd x1, 8(sp)",
             "sd x3, 16(sp)",
             "sd x4, 24(sp)",
-            "sd x5, 32(sp)",
-            "sd x6, 40(sp)",
+            "ld gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "ld tp, {kernel_tp}(t0)",
+            "csrrw x1, mscratch, t0", // ready for the next trap
+            "sd x1, 32(sp)",
+            "ld x1, {saved_t1}(t0)",
+            "sd x1, 40(sp)",
             "sd x7, 48(sp)",
             "sd x8, 56(sp)",
             "sd x9, 64(sp)",
@@ -147,7 +431,21 @@ pub(crate) unsafe extern "C" fn machine_trap_entry() {
             "sd x13, 96(sp)",
             "sd x14, 104(sp)",
             "sd x15, 112(sp)",
-            "call {}",
+            "csrr t0, mepc",
+            "sd t0, {epc}(sp)",
+            "csrr t0, mstatus",
+            "sd t0, {status}(sp)",
+            "csrr t0, mcause",
+            "sd t0, {cause}(sp)",
+            "csrr t0, mtval",
+            "sd t0, {tval}(sp)",
+            "mv a0, sp",
+            "call {handler}",
+            "mv sp, a0", // the handler returns the frame to resume
+            "ld t0, {epc}(sp)",
+            "csrw mepc, t0",
+            "ld t0, {status}(sp)",
+            "csrw mstatus, t0",
             "ld x15, 112(sp)",
             "ld x14, 104(sp)",
             "ld x13, 96(sp)",
@@ -161,21 +459,46 @@ pub(crate) unsafe extern "C" fn machine_trap_entry() {
             "ld x5, 32(sp)",
             "ld x4, 24(sp)",
             "ld x3, 16(sp)",
-            "ld x2, 8(sp)",
             "ld x1, 0(sp)",
-            "addi sp, sp, 128",
+            "ld x2, 8(sp)", // last, as the stack pointer is the base of the frame
             "mret",
-            sym machine_trap,
+            handler = sym machine_trap,
+            stack = const offset_of!(CoreState, trap_stacks.machine.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.machine.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.machine.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.machine.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.machine.tp),
+            mpp = const MPP_SHIFT,
+            size = const FRAME_SIZE,
+            epc = const offset_of!(TrapFrame, epc),
+            status = const offset_of!(TrapFrame, status),
+            cause = const offset_of!(TrapFrame, cause),
+            tval = const offset_of!(TrapFrame, tval),
         );
         #[cfg(all(target_arch = "riscv64", not(feature = "riscv_isa_e")))]
         naked_asm!(
-            "addi sp, sp, -256", // 32 8 byte registers
+            "csrrw t0, mscratch, t0", // the core state, the scratch register keeps `t0`
+            "sd t1, {saved_t1}(t0)",
+            "sd sp, {saved_sp}(t0)",
+            "csrr t1, mstatus",
+            "srli t1, t1, {mpp}",
+            "andi t1, t1, 3",
+            "addi t1, t1, -3",
+            "beqz t1, 1f", // nested in M-mode, keep using its stack
+            "ld sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
             "sd x1, 0(sp)",
-            "sd x2, 8(sp)",
+            "ld x1, {saved_sp}(t0)", // the stack pointer before the trap
+            "sd x1, 8(sp)",
             "sd x3, 16(sp)",
             "sd x4, 24(sp)",
-            "sd x5, 32(sp)",
-            "sd x6, 40(sp)",
+            "ld gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "ld tp, {kernel_tp}(t0)",
+            "csrrw x1, mscratch, t0", // ready for the next trap
+            "sd x1, 32(sp)",
+            "ld x1, {saved_t1}(t0)",
+            "sd x1, 40(sp)",
             "sd x7, 48(sp)",
             "sd x8, 56(sp)",
             "sd x9, 64(sp)",
@@ -201,7 +524,21 @@ pub(crate) unsafe extern "C" fn machine_trap_entry() {
             "sd x29, 224(sp)",
             "sd x30, 232(sp)",
             "sd x31, 240(sp)",
-            "call {}",
+            "csrr t0, mepc",
+            "sd t0, {epc}(sp)",
+            "csrr t0, mstatus",
+            "sd t0, {status}(sp)",
+            "csrr t0, mcause",
+            "sd t0, {cause}(sp)",
+            "csrr t0, mtval",
+            "sd t0, {tval}(sp)",
+            "mv a0, sp",
+            "call {handler}",
+            "mv sp, a0", // the handler returns the frame to resume
+            "ld t0, {epc}(sp)",
+            "csrw mepc, t0",
+            "ld t0, {status}(sp)",
+            "csrw mstatus, t0",
             "ld x31, 240(sp)",
             "ld x30, 232(sp)",
             "ld x29, 224(sp)",
@@ -231,28 +568,57 @@ pub(crate) unsafe extern "C" fn machine_trap_entry() {
             "ld x5, 32(sp)",
             "ld x4, 24(sp)",
             "ld x3, 16(sp)",
-            "ld x2, 8(sp)",
             "ld x1, 0(sp)",
-            "addi sp, sp, 256",
+            "ld x2, 8(sp)", // last, as the stack pointer is the base of the frame
             "mret",
-            sym machine_trap,
+            handler = sym machine_trap,
+            stack = const offset_of!(CoreState, trap_stacks.machine.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.machine.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.machine.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.machine.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.machine.tp),
+            mpp = const MPP_SHIFT,
+            size = const FRAME_SIZE,
+            epc = const offset_of!(TrapFrame, epc),
+            status = const offset_of!(TrapFrame, status),
+            cause = const offset_of!(TrapFrame, cause),
+            tval = const offset_of!(TrapFrame, tval),
         );
     }
 }
 
+/// The S-mode trap entry, saves a [`TrapFrame`] and calls [`supervisor_trap`].
+///
+/// `sscratch` points to the [`CoreState`]. Traps from S-mode keep using the interrupted stack, so
+/// nested traps stack up. Traps from U-mode switch to the supervisor trap stack of the core first,
+/// as the user stack can not be trusted.
+///
 #[unsafe(naked)]
 pub(crate) unsafe extern "C" fn supervisor_trap_entry() {
     #[allow(unused_unsafe)]
     unsafe {
         #[cfg(all(target_arch = "riscv32", feature = "riscv_isa_e"))]
         naked_asm!(
-            "addi sp, sp, -64",
+            "csrrw t0, sscratch, t0", // the core state, the scratch register keeps `t0`
+            "sw t1, {saved_t1}(t0)",
+            "sw sp, {saved_sp}(t0)",
+            "csrr t1, sstatus",
+            "andi t1, t1, {spp}",
+            "bnez t1, 1f", // nested in S-mode, keep using its stack
+            "lw sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
             "sw x1, 0(sp)",
-            "sw x2, 4(sp)",
+            "lw x1, {saved_sp}(t0)", // the stack pointer before the trap
+            "sw x1, 4(sp)",
             "sw x3, 8(sp)",
             "sw x4, 12(sp)",
-            "sw x5, 16(sp)",
-            "sw x6, 20(sp)",
+            "lw gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "lw tp, {kernel_tp}(t0)",
+            "csrrw x1, sscratch, t0", // ready for the next trap
+            "sw x1, 16(sp)",
+            "lw x1, {saved_t1}(t0)",
+            "sw x1, 20(sp)",
             "sw x7, 24(sp)",
             "sw x8, 28(sp)",
             "sw x9, 32(sp)",
@@ -262,7 +628,21 @@ pub(crate) unsafe extern "C" fn supervisor_trap_entry() {
             "sw x13, 48(sp)",
             "sw x14, 52(sp)",
             "sw x15, 56(sp)",
-            "call {}",
+            "csrr t0, sepc",
+            "sw t0, {epc}(sp)",
+            "csrr t0, sstatus",
+            "sw t0, {status}(sp)",
+            "csrr t0, scause",
+            "sw t0, {cause}(sp)",
+            "csrr t0, stval",
+            "sw t0, {tval}(sp)",
+            "mv a0, sp",
+            "call {handler}",
+            "mv sp, a0", // the handler returns the frame to resume
+            "lw t0, {epc}(sp)",
+            "csrw sepc, t0",
+            "lw t0, {status}(sp)",
+            "csrw sstatus, t0",
             "lw x15, 56(sp)",
             "lw x14, 52(sp)",
             "lw x13, 48(sp)",
@@ -276,21 +656,44 @@ pub(crate) unsafe extern "C" fn supervisor_trap_entry() {
             "lw x5, 16(sp)",
             "lw x4, 12(sp)",
             "lw x3, 8(sp)",
-            "lw x2, 4(sp)",
             "lw x1, 0(sp)",
-            "addi sp, sp, 64",
+            "lw x2, 4(sp)", // last, as the stack pointer is the base of the frame
             "sret",
-            sym supervisor_trap,
+            handler = sym supervisor_trap,
+            stack = const offset_of!(CoreState, trap_stacks.supervisor.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.supervisor.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.supervisor.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.supervisor.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.supervisor.tp),
+            spp = const SPP,
+            size = const FRAME_SIZE,
+            epc = const offset_of!(TrapFrame, epc),
+            status = const offset_of!(TrapFrame, status),
+            cause = const offset_of!(TrapFrame, cause),
+            tval = const offset_of!(TrapFrame, tval),
         );
         #[cfg(all(target_arch = "riscv32", not(feature = "riscv_isa_e")))]
         naked_asm!(
-            "addi sp, sp, -128", // twice as many registers in the I isa compared to E
+            "csrrw t0, sscratch, t0", // the core state, the scratch register keeps `t0`
+            "sw t1, {saved_t1}(t0)",
+            "sw sp, {saved_sp}(t0)",
+            "csrr t1, sstatus",
+            "andi t1, t1, {spp}",
+            "bnez t1, 1f", // nested in S-mode, keep using its stack
+            "lw sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
             "sw x1, 0(sp)",
-            "sw x2, 4(sp)",
+            "lw x1, {saved_sp}(t0)", // the stack pointer before the trap
+            "sw x1, 4(sp)",
             "sw x3, 8(sp)",
             "sw x4, 12(sp)",
-            "sw x5, 16(sp)",
-            "sw x6, 20(sp)",
+            "lw gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "lw tp, {kernel_tp}(t0)",
+            "csrrw x1, sscratch, t0", // ready for the next trap
+            "sw x1, 16(sp)",
+            "lw x1, {saved_t1}(t0)",
+            "sw x1, 20(sp)",
             "sw x7, 24(sp)",
             "sw x8, 28(sp)",
             "sw x9, 32(sp)",
@@ -316,7 +719,21 @@ pub(crate) unsafe extern "C" fn supervisor_trap_entry() {
             "sw x29, 112(sp)",
             "sw x30, 116(sp)",
             "sw x31, 120(sp)",
-            "call {}",
+            "csrr t0, sepc",
+            "sw t0, {epc}(sp)",
+            "csrr t0, sstatus",
+            "sw t0, {status}(sp)",
+            "csrr t0, scause",
+            "sw t0, {cause}(sp)",
+            "csrr t0, stval",
+            "sw t0, {tval}(sp)",
+            "mv a0, sp",
+            "call {handler}",
+            "mv sp, a0", // the handler returns the frame to resume
+            "lw t0, {epc}(sp)",
+            "csrw sepc, t0",
+            "lw t0, {status}(sp)",
+            "csrw sstatus, t0",
             "lw x31, 120(sp)",
             "lw x30, 116(sp)",
             "lw x29, 112(sp)",
@@ -346,21 +763,44 @@ pub(crate) unsafe extern "C" fn supervisor_trap_entry() {
             "lw x5, 16(sp)",
             "lw x4, 12(sp)",
             "lw x3, 8(sp)",
-            "lw x2, 4(sp)",
             "lw x1, 0(sp)",
-            "addi sp, sp, 128",
+            "lw x2, 4(sp)", // last, as the stack pointer is the base of the frame
             "sret",
-            sym supervisor_trap,
+            handler = sym supervisor_trap,
+            stack = const offset_of!(CoreState, trap_stacks.supervisor.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.supervisor.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.supervisor.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.supervisor.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.supervisor.tp),
+            spp = const SPP,
+            size = const FRAME_SIZE,
+            epc = const offset_of!(TrapFrame, epc),
+            status = const offset_of!(TrapFrame, status),
+            cause = const offset_of!(TrapFrame, cause),
+            tval = const offset_of!(TrapFrame, tval),
         );
         #[cfg(all(target_arch = "riscv64", feature = "riscv_isa_e"))]
         naked_asm!(
-            "addi sp, sp, -128", // 16 8 byte registers
+            "csrrw t0, sscratch, t0", // the core state, the scratch register keeps `t0`
+            "sd t1, {saved_t1}(t0)",
+            "sd sp, {saved_sp}(t0)",
+            "csrr t1, sstatus",
+            "andi t1, t1, {spp}",
+            "bnez t1, 1f", // nested in S-mode, keep using its stack
+            "ld sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
             "sd x1, 0(sp)",
-            "sd x2, 8(sp)",
+            "ld x1, {saved_sp}(t0)", // the stack pointer before the trap
+            "sd x1, 8(sp)",
             "sd x3, 16(sp)",
             "sd x4, 24(sp)",
-            "sd x5, 32(sp)",
-            "sd x6, 40(sp)",
+            "ld gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "ld tp, {kernel_tp}(t0)",
+            "csrrw x1, sscratch, t0", // ready for the next trap
+            "sd x1, 32(sp)",
+            "ld x1, {saved_t1}(t0)",
+            "sd x1, 40(sp)",
             "sd x7, 48(sp)",
             "sd x8, 56(sp)",
             "sd x9, 64(sp)",
@@ -370,7 +810,21 @@ pub(crate) unsafe extern "C" fn supervisor_trap_entry() {
             "sd x13, 96(sp)",
             "sd x14, 104(sp)",
             "sd x15, 112(sp)",
-            "call {}",
+            "csrr t0, sepc",
+            "sd t0, {epc}(sp)",
+            "csrr t0, sstatus",
+            "sd t0, {status}(sp)",
+            "csrr t0, scause",
+            "sd t0, {cause}(sp)",
+            "csrr t0, stval",
+            "sd t0, {tval}(sp)",
+            "mv a0, sp",
+            "call {handler}",
+            "mv sp, a0", // the handler returns the frame to resume
+            "ld t0, {epc}(sp)",
+            "csrw sepc, t0",
+            "ld t0, {status}(sp)",
+            "csrw sstatus, t0",
             "ld x15, 112(sp)",
             "ld x14, 104(sp)",
             "ld x13, 96(sp)",
@@ -384,21 +838,44 @@ pub(crate) unsafe extern "C" fn supervisor_trap_entry() {
             "ld x5, 32(sp)",
             "ld x4, 24(sp)",
             "ld x3, 16(sp)",
-            "ld x2, 8(sp)",
             "ld x1, 0(sp)",
-            "addi sp, sp, 128",
+            "ld x2, 8(sp)", // last, as the stack pointer is the base of the frame
             "sret",
-            sym supervisor_trap,
+            handler = sym supervisor_trap,
+            stack = const offset_of!(CoreState, trap_stacks.supervisor.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.supervisor.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.supervisor.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.supervisor.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.supervisor.tp),
+            spp = const SPP,
+            size = const FRAME_SIZE,
+            epc = const offset_of!(TrapFrame, epc),
+            status = const offset_of!(TrapFrame, status),
+            cause = const offset_of!(TrapFrame, cause),
+            tval = const offset_of!(TrapFrame, tval),
         );
         #[cfg(all(target_arch = "riscv64", not(feature = "riscv_isa_e")))]
         naked_asm!(
-            "addi sp, sp, -256", // 32 8 byte registers
+            "csrrw t0, sscratch, t0", // the core state, the scratch register keeps `t0`
+            "sd t1, {saved_t1}(t0)",
+            "sd sp, {saved_sp}(t0)",
+            "csrr t1, sstatus",
+            "andi t1, t1, {spp}",
+            "bnez t1, 1f", // nested in S-mode, keep using its stack
+            "ld sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
             "sd x1, 0(sp)",
-            "sd x2, 8(sp)",
+            "ld x1, {saved_sp}(t0)", // the stack pointer before the trap
+            "sd x1, 8(sp)",
             "sd x3, 16(sp)",
             "sd x4, 24(sp)",
-            "sd x5, 32(sp)",
-            "sd x6, 40(sp)",
+            "ld gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "ld tp, {kernel_tp}(t0)",
+            "csrrw x1, sscratch, t0", // ready for the next trap
+            "sd x1, 32(sp)",
+            "ld x1, {saved_t1}(t0)",
+            "sd x1, 40(sp)",
             "sd x7, 48(sp)",
             "sd x8, 56(sp)",
             "sd x9, 64(sp)",
@@ -424,7 +901,21 @@ pub(crate) unsafe extern "C" fn supervisor_trap_entry() {
             "sd x29, 224(sp)",
             "sd x30, 232(sp)",
             "sd x31, 240(sp)",
-            "call {}",
+            "csrr t0, sepc",
+            "sd t0, {epc}(sp)",
+            "csrr t0, sstatus",
+            "sd t0, {status}(sp)",
+            "csrr t0, scause",
+            "sd t0, {cause}(sp)",
+            "csrr t0, stval",
+            "sd t0, {tval}(sp)",
+            "mv a0, sp",
+            "call {handler}",
+            "mv sp, a0", // the handler returns the frame to resume
+            "ld t0, {epc}(sp)",
+            "csrw sepc, t0",
+            "ld t0, {status}(sp)",
+            "csrw sstatus, t0",
             "ld x31, 240(sp)",
             "ld x30, 232(sp)",
             "ld x29, 224(sp)",
@@ -454,77 +945,870 @@ pub(crate) unsafe extern "C" fn supervisor_trap_entry() {
             "ld x5, 32(sp)",
             "ld x4, 24(sp)",
             "ld x3, 16(sp)",
-            "ld x2, 8(sp)",
             "ld x1, 0(sp)",
-            "addi sp, sp, 256",
+            "ld x2, 8(sp)", // last, as the stack pointer is the base of the frame
+            "sret",
+            handler = sym supervisor_trap,
+            stack = const offset_of!(CoreState, trap_stacks.supervisor.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.supervisor.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.supervisor.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.supervisor.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.supervisor.tp),
+            spp = const SPP,
+            size = const FRAME_SIZE,
+            epc = const offset_of!(TrapFrame, epc),
+            status = const offset_of!(TrapFrame, status),
+            cause = const offset_of!(TrapFrame, cause),
+            tval = const offset_of!(TrapFrame, tval),
+        );
+    }
+}
+
+/// The fast M-mode entry of the timer and software interrupts, calls [`machine_interrupt`].
+///
+/// Only the caller-saved registers are saved, the handler preserves the rest. Switches stacks
+/// like [`machine_trap_entry`].
+///
+#[cfg(all(feature = "riscv_vectored", not(feature = "riscv_sbi")))]
+#[unsafe(naked)]
+unsafe extern "C" fn machine_interrupt_entry() {
+    #[allow(unused_unsafe)]
+    unsafe {
+        #[cfg(all(target_arch = "riscv32", feature = "riscv_isa_e"))]
+        naked_asm!(
+            "csrrw t0, mscratch, t0", // the core state, the scratch register keeps `t0`
+            "sw t1, {saved_t1}(t0)",
+            "sw sp, {saved_sp}(t0)",
+            "csrr t1, mstatus",
+            "srli t1, t1, {mpp}",
+            "andi t1, t1, 3",
+            "addi t1, t1, -3",
+            "beqz t1, 1f", // nested in M-mode, keep using its stack
+            "lw sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
+            "sw ra, 0(sp)",
+            "lw ra, {saved_sp}(t0)", // the stack pointer before the trap
+            "sw ra, 4(sp)",
+            "csrrw ra, mscratch, t0", // ready for the next trap
+            "sw ra, 8(sp)",
+            "lw ra, {saved_t1}(t0)",
+            "sw ra, 12(sp)",
+            "sw t2, 16(sp)",
+            "sw a0, 20(sp)",
+            "sw a1, 24(sp)",
+            "sw a2, 28(sp)",
+            "sw a3, 32(sp)",
+            "sw a4, 36(sp)",
+            "sw a5, 40(sp)",
+            "sw gp, 44(sp)",
+            "sw tp, 48(sp)",
+            "lw gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "lw tp, {kernel_tp}(t0)",
+            "call {handler}",
+            "lw tp, 48(sp)",
+            "lw gp, 44(sp)",
+            "lw a5, 40(sp)",
+            "lw a4, 36(sp)",
+            "lw a3, 32(sp)",
+            "lw a2, 28(sp)",
+            "lw a1, 24(sp)",
+            "lw a0, 20(sp)",
+            "lw t2, 16(sp)",
+            "lw t1, 12(sp)",
+            "lw t0, 8(sp)",
+            "lw ra, 0(sp)",
+            "lw sp, 4(sp)", // last, as the stack pointer is the base of the frame
+            "mret",
+            handler = sym machine_interrupt,
+            stack = const offset_of!(CoreState, trap_stacks.machine.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.machine.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.machine.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.machine.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.machine.tp),
+            mpp = const MPP_SHIFT,
+            size = const INTERRUPT_FRAME_SIZE,
+        );
+        #[cfg(all(target_arch = "riscv32", not(feature = "riscv_isa_e")))]
+        naked_asm!(
+            "csrrw t0, mscratch, t0", // the core state, the scratch register keeps `t0`
+            "sw t1, {saved_t1}(t0)",
+            "sw sp, {saved_sp}(t0)",
+            "csrr t1, mstatus",
+            "srli t1, t1, {mpp}",
+            "andi t1, t1, 3",
+            "addi t1, t1, -3",
+            "beqz t1, 1f", // nested in M-mode, keep using its stack
+            "lw sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
+            "sw ra, 0(sp)",
+            "lw ra, {saved_sp}(t0)", // the stack pointer before the trap
+            "sw ra, 4(sp)",
+            "csrrw ra, mscratch, t0", // ready for the next trap
+            "sw ra, 8(sp)",
+            "lw ra, {saved_t1}(t0)",
+            "sw ra, 12(sp)",
+            "sw t2, 16(sp)",
+            "sw a0, 20(sp)",
+            "sw a1, 24(sp)",
+            "sw a2, 28(sp)",
+            "sw a3, 32(sp)",
+            "sw a4, 36(sp)",
+            "sw a5, 40(sp)",
+            "sw a6, 44(sp)",
+            "sw a7, 48(sp)",
+            "sw t3, 52(sp)",
+            "sw t4, 56(sp)",
+            "sw t5, 60(sp)",
+            "sw t6, 64(sp)",
+            "sw gp, 68(sp)",
+            "sw tp, 72(sp)",
+            "lw gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "lw tp, {kernel_tp}(t0)",
+            "call {handler}",
+            "lw tp, 72(sp)",
+            "lw gp, 68(sp)",
+            "lw t6, 64(sp)",
+            "lw t5, 60(sp)",
+            "lw t4, 56(sp)",
+            "lw t3, 52(sp)",
+            "lw a7, 48(sp)",
+            "lw a6, 44(sp)",
+            "lw a5, 40(sp)",
+            "lw a4, 36(sp)",
+            "lw a3, 32(sp)",
+            "lw a2, 28(sp)",
+            "lw a1, 24(sp)",
+            "lw a0, 20(sp)",
+            "lw t2, 16(sp)",
+            "lw t1, 12(sp)",
+            "lw t0, 8(sp)",
+            "lw ra, 0(sp)",
+            "lw sp, 4(sp)", // last, as the stack pointer is the base of the frame
+            "mret",
+            handler = sym machine_interrupt,
+            stack = const offset_of!(CoreState, trap_stacks.machine.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.machine.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.machine.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.machine.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.machine.tp),
+            mpp = const MPP_SHIFT,
+            size = const INTERRUPT_FRAME_SIZE,
+        );
+        #[cfg(all(target_arch = "riscv64", feature = "riscv_isa_e"))]
+        naked_asm!(
+            "csrrw t0, mscratch, t0", // the core state, the scratch register keeps `t0`
+            "sd t1, {saved_t1}(t0)",
+            "sd sp, {saved_sp}(t0)",
+            "csrr t1, mstatus",
+            "srli t1, t1, {mpp}",
+            "andi t1, t1, 3",
+            "addi t1, t1, -3",
+            "beqz t1, 1f", // nested in M-mode, keep using its stack
+            "ld sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
+            "sd ra, 0(sp)",
+            "ld ra, {saved_sp}(t0)", // the stack pointer before the trap
+            "sd ra, 8(sp)",
+            "csrrw ra, mscratch, t0", // ready for the next trap
+            "sd ra, 16(sp)",
+            "ld ra, {saved_t1}(t0)",
+            "sd ra, 24(sp)",
+            "sd t2, 32(sp)",
+            "sd a0, 40(sp)",
+            "sd a1, 48(sp)",
+            "sd a2, 56(sp)",
+            "sd a3, 64(sp)",
+            "sd a4, 72(sp)",
+            "sd a5, 80(sp)",
+            "sd gp, 88(sp)",
+            "sd tp, 96(sp)",
+            "ld gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "ld tp, {kernel_tp}(t0)",
+            "call {handler}",
+            "ld tp, 96(sp)",
+            "ld gp, 88(sp)",
+            "ld a5, 80(sp)",
+            "ld a4, 72(sp)",
+            "ld a3, 64(sp)",
+            "ld a2, 56(sp)",
+            "ld a1, 48(sp)",
+            "ld a0, 40(sp)",
+            "ld t2, 32(sp)",
+            "ld t1, 24(sp)",
+            "ld t0, 16(sp)",
+            "ld ra, 0(sp)",
+            "ld sp, 8(sp)", // last, as the stack pointer is the base of the frame
+            "mret",
+            handler = sym machine_interrupt,
+            stack = const offset_of!(CoreState, trap_stacks.machine.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.machine.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.machine.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.machine.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.machine.tp),
+            mpp = const MPP_SHIFT,
+            size = const INTERRUPT_FRAME_SIZE,
+        );
+        #[cfg(all(target_arch = "riscv64", not(feature = "riscv_isa_e")))]
+        naked_asm!(
+            "csrrw t0, mscratch, t0", // the core state, the scratch register keeps `t0`
+            "sd t1, {saved_t1}(t0)",
+            "sd sp, {saved_sp}(t0)",
+            "csrr t1, mstatus",
+            "srli t1, t1, {mpp}",
+            "andi t1, t1, 3",
+            "addi t1, t1, -3",
+            "beqz t1, 1f", // nested in M-mode, keep using its stack
+            "ld sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
+            "sd ra, 0(sp)",
+            "ld ra, {saved_sp}(t0)", // the stack pointer before the trap
+            "sd ra, 8(sp)",
+            "csrrw ra, mscratch, t0", // ready for the next trap
+            "sd ra, 16(sp)",
+            "ld ra, {saved_t1}(t0)",
+            "sd ra, 24(sp)",
+            "sd t2, 32(sp)",
+            "sd a0, 40(sp)",
+            "sd a1, 48(sp)",
+            "sd a2, 56(sp)",
+            "sd a3, 64(sp)",
+            "sd a4, 72(sp)",
+            "sd a5, 80(sp)",
+            "sd a6, 88(sp)",
+            "sd a7, 96(sp)",
+            "sd t3, 104(sp)",
+            "sd t4, 112(sp)",
+            "sd t5, 120(sp)",
+            "sd t6, 128(sp)",
+            "sd gp, 136(sp)",
+            "sd tp, 144(sp)",
+            "ld gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "ld tp, {kernel_tp}(t0)",
+            "call {handler}",
+            "ld tp, 144(sp)",
+            "ld gp, 136(sp)",
+            "ld t6, 128(sp)",
+            "ld t5, 120(sp)",
+            "ld t4, 112(sp)",
+            "ld t3, 104(sp)",
+            "ld a7, 96(sp)",
+            "ld a6, 88(sp)",
+            "ld a5, 80(sp)",
+            "ld a4, 72(sp)",
+            "ld a3, 64(sp)",
+            "ld a2, 56(sp)",
+            "ld a1, 48(sp)",
+            "ld a0, 40(sp)",
+            "ld t2, 32(sp)",
+            "ld t1, 24(sp)",
+            "ld t0, 16(sp)",
+            "ld ra, 0(sp)",
+            "ld sp, 8(sp)", // last, as the stack pointer is the base of the frame
+            "mret",
+            handler = sym machine_interrupt,
+            stack = const offset_of!(CoreState, trap_stacks.machine.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.machine.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.machine.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.machine.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.machine.tp),
+            mpp = const MPP_SHIFT,
+            size = const INTERRUPT_FRAME_SIZE,
+        );
+    }
+}
+
+/// The fast S-mode entry of the timer and software interrupts, calls [`supervisor_interrupt`].
+///
+/// Only the caller-saved registers are saved, the handler preserves the rest. Switches stacks
+/// like [`supervisor_trap_entry`].
+///
+#[cfg(feature = "riscv_vectored")]
+#[unsafe(naked)]
+unsafe extern "C" fn supervisor_interrupt_entry() {
+    #[allow(unused_unsafe)]
+    unsafe {
+        #[cfg(all(target_arch = "riscv32", feature = "riscv_isa_e"))]
+        naked_asm!(
+            "csrrw t0, sscratch, t0", // the core state, the scratch register keeps `t0`
+            "sw t1, {saved_t1}(t0)",
+            "sw sp, {saved_sp}(t0)",
+            "csrr t1, sstatus",
+            "andi t1, t1, {spp}",
+            "bnez t1, 1f", // nested in S-mode, keep using its stack
+            "lw sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
+            "sw ra, 0(sp)",
+            "lw ra, {saved_sp}(t0)", // the stack pointer before the trap
+            "sw ra, 4(sp)",
+            "csrrw ra, sscratch, t0", // ready for the next trap
+            "sw ra, 8(sp)",
+            "lw ra, {saved_t1}(t0)",
+            "sw ra, 12(sp)",
+            "sw t2, 16(sp)",
+            "sw a0, 20(sp)",
+            "sw a1, 24(sp)",
+            "sw a2, 28(sp)",
+            "sw a3, 32(sp)",
+            "sw a4, 36(sp)",
+            "sw a5, 40(sp)",
+            "sw gp, 44(sp)",
+            "sw tp, 48(sp)",
+            "lw gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "lw tp, {kernel_tp}(t0)",
+            "call {handler}",
+            "lw tp, 48(sp)",
+            "lw gp, 44(sp)",
+            "lw a5, 40(sp)",
+            "lw a4, 36(sp)",
+            "lw a3, 32(sp)",
+            "lw a2, 28(sp)",
+            "lw a1, 24(sp)",
+            "lw a0, 20(sp)",
+            "lw t2, 16(sp)",
+            "lw t1, 12(sp)",
+            "lw t0, 8(sp)",
+            "lw ra, 0(sp)",
+            "lw sp, 4(sp)", // last, as the stack pointer is the base of the frame
+            "sret",
+            handler = sym supervisor_interrupt,
+            stack = const offset_of!(CoreState, trap_stacks.supervisor.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.supervisor.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.supervisor.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.supervisor.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.supervisor.tp),
+            spp = const SPP,
+            size = const INTERRUPT_FRAME_SIZE,
+        );
+        #[cfg(all(target_arch = "riscv32", not(feature = "riscv_isa_e")))]
+        naked_asm!(
+            "csrrw t0, sscratch, t0", // the core state, the scratch register keeps `t0`
+            "sw t1, {saved_t1}(t0)",
+            "sw sp, {saved_sp}(t0)",
+            "csrr t1, sstatus",
+            "andi t1, t1, {spp}",
+            "bnez t1, 1f", // nested in S-mode, keep using its stack
+            "lw sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
+            "sw ra, 0(sp)",
+            "lw ra, {saved_sp}(t0)", // the stack pointer before the trap
+            "sw ra, 4(sp)",
+            "csrrw ra, sscratch, t0", // ready for the next trap
+            "sw ra, 8(sp)",
+            "lw ra, {saved_t1}(t0)",
+            "sw ra, 12(sp)",
+            "sw t2, 16(sp)",
+            "sw a0, 20(sp)",
+            "sw a1, 24(sp)",
+            "sw a2, 28(sp)",
+            "sw a3, 32(sp)",
+            "sw a4, 36(sp)",
+            "sw a5, 40(sp)",
+            "sw a6, 44(sp)",
+            "sw a7, 48(sp)",
+            "sw t3, 52(sp)",
+            "sw t4, 56(sp)",
+            "sw t5, 60(sp)",
+            "sw t6, 64(sp)",
+            "sw gp, 68(sp)",
+            "sw tp, 72(sp)",
+            "lw gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "lw tp, {kernel_tp}(t0)",
+            "call {handler}",
+            "lw tp, 72(sp)",
+            "lw gp, 68(sp)",
+            "lw t6, 64(sp)",
+            "lw t5, 60(sp)",
+            "lw t4, 56(sp)",
+            "lw t3, 52(sp)",
+            "lw a7, 48(sp)",
+            "lw a6, 44(sp)",
+            "lw a5, 40(sp)",
+            "lw a4, 36(sp)",
+            "lw a3, 32(sp)",
+            "lw a2, 28(sp)",
+            "lw a1, 24(sp)",
+            "lw a0, 20(sp)",
+            "lw t2, 16(sp)",
+            "lw t1, 12(sp)",
+            "lw t0, 8(sp)",
+            "lw ra, 0(sp)",
+            "lw sp, 4(sp)", // last, as the stack pointer is the base of the frame
+            "sret",
+            handler = sym supervisor_interrupt,
+            stack = const offset_of!(CoreState, trap_stacks.supervisor.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.supervisor.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.supervisor.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.supervisor.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.supervisor.tp),
+            spp = const SPP,
+            size = const INTERRUPT_FRAME_SIZE,
+        );
+        #[cfg(all(target_arch = "riscv64", feature = "riscv_isa_e"))]
+        naked_asm!(
+            "csrrw t0, sscratch, t0", // the core state, the scratch register keeps `t0`
+            "sd t1, {saved_t1}(t0)",
+            "sd sp, {saved_sp}(t0)",
+            "csrr t1, sstatus",
+            "andi t1, t1, {spp}",
+            "bnez t1, 1f", // nested in S-mode, keep using its stack
+            "ld sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
+            "sd ra, 0(sp)",
+            "ld ra, {saved_sp}(t0)", // the stack pointer before the trap
+            "sd ra, 8(sp)",
+            "csrrw ra, sscratch, t0", // ready for the next trap
+            "sd ra, 16(sp)",
+            "ld ra, {saved_t1}(t0)",
+            "sd ra, 24(sp)",
+            "sd t2, 32(sp)",
+            "sd a0, 40(sp)",
+            "sd a1, 48(sp)",
+            "sd a2, 56(sp)",
+            "sd a3, 64(sp)",
+            "sd a4, 72(sp)",
+            "sd a5, 80(sp)",
+            "sd gp, 88(sp)",
+            "sd tp, 96(sp)",
+            "ld gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "ld tp, {kernel_tp}(t0)",
+            "call {handler}",
+            "ld tp, 96(sp)",
+            "ld gp, 88(sp)",
+            "ld a5, 80(sp)",
+            "ld a4, 72(sp)",
+            "ld a3, 64(sp)",
+            "ld a2, 56(sp)",
+            "ld a1, 48(sp)",
+            "ld a0, 40(sp)",
+            "ld t2, 32(sp)",
+            "ld t1, 24(sp)",
+            "ld t0, 16(sp)",
+            "ld ra, 0(sp)",
+            "ld sp, 8(sp)", // last, as the stack pointer is the base of the frame
             "sret",
-            sym supervisor_trap,
+            handler = sym supervisor_interrupt,
+            stack = const offset_of!(CoreState, trap_stacks.supervisor.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.supervisor.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.supervisor.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.supervisor.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.supervisor.tp),
+            spp = const SPP,
+            size = const INTERRUPT_FRAME_SIZE,
+        );
+        #[cfg(all(target_arch = "riscv64", not(feature = "riscv_isa_e")))]
+        naked_asm!(
+            "csrrw t0, sscratch, t0", // the core state, the scratch register keeps `t0`
+            "sd t1, {saved_t1}(t0)",
+            "sd sp, {saved_sp}(t0)",
+            "csrr t1, sstatus",
+            "andi t1, t1, {spp}",
+            "bnez t1, 1f", // nested in S-mode, keep using its stack
+            "ld sp, {stack}(t0)", // from a less privileged mode, never trust its stack
+            "1:",
+            "addi sp, sp, -{size}",
+            "sd ra, 0(sp)",
+            "ld ra, {saved_sp}(t0)", // the stack pointer before the trap
+            "sd ra, 8(sp)",
+            "csrrw ra, sscratch, t0", // ready for the next trap
+            "sd ra, 16(sp)",
+            "ld ra, {saved_t1}(t0)",
+            "sd ra, 24(sp)",
+            "sd t2, 32(sp)",
+            "sd a0, 40(sp)",
+            "sd a1, 48(sp)",
+            "sd a2, 56(sp)",
+            "sd a3, 64(sp)",
+            "sd a4, 72(sp)",
+            "sd a5, 80(sp)",
+            "sd a6, 88(sp)",
+            "sd a7, 96(sp)",
+            "sd t3, 104(sp)",
+            "sd t4, 112(sp)",
+            "sd t5, 120(sp)",
+            "sd t6, 128(sp)",
+            "sd gp, 136(sp)",
+            "sd tp, 144(sp)",
+            "ld gp, {kernel_gp}(t0)", // the interrupted `gp` and `tp` may be those of user code
+            "ld tp, {kernel_tp}(t0)",
+            "call {handler}",
+            "ld tp, 144(sp)",
+            "ld gp, 136(sp)",
+            "ld t6, 128(sp)",
+            "ld t5, 120(sp)",
+            "ld t4, 112(sp)",
+            "ld t3, 104(sp)",
+            "ld a7, 96(sp)",
+            "ld a6, 88(sp)",
+            "ld a5, 80(sp)",
+            "ld a4, 72(sp)",
+            "ld a3, 64(sp)",
+            "ld a2, 56(sp)",
+            "ld a1, 48(sp)",
+            "ld a0, 40(sp)",
+            "ld t2, 32(sp)",
+            "ld t1, 24(sp)",
+            "ld t0, 16(sp)",
+            "ld ra, 0(sp)",
+            "ld sp, 8(sp)", // last, as the stack pointer is the base of the frame
+            "sret",
+            handler = sym supervisor_interrupt,
+            stack = const offset_of!(CoreState, trap_stacks.supervisor.top),
+            saved_sp = const offset_of!(CoreState, trap_stacks.supervisor.sp),
+            saved_t1 = const offset_of!(CoreState, trap_stacks.supervisor.t1),
+            kernel_gp = const offset_of!(CoreState, trap_stacks.supervisor.gp),
+            kernel_tp = const offset_of!(CoreState, trap_stacks.supervisor.tp),
+            spp = const SPP,
+            size = const INTERRUPT_FRAME_SIZE,
         );
     }
 }
 
-fn convert_trap(trap: riscv::interrupt::Trap<usize, usize>) -> Trap {
-    match trap.try_into::<Interrupt, Exception>() {
-        Ok(trap) => match trap {
-            mcause::Trap::Interrupt(int) => match int {
-                Interrupt::MachineTimer | Interrupt::SupervisorTimer => Trap::Timer,
-                Interrupt::MachineExternal | Interrupt::SupervisorExternal => Trap::External,
-                Interrupt::MachineSoft | Interrupt::SupervisorSoft => Trap::Software,
-            },
-            mcause::Trap::Exception(exc) => match exc {
-                Exception::Breakpoint => Trap::Breakpoint,
-                Exception::IllegalInstruction => Trap::IllegalInstruction,
-                Exception::UserEnvCall => Trap::SysCall,
-                Exception::InstructionPageFault => Trap::InstructionPageFault,
-                Exception::LoadPageFault => Trap::LoadPageFault,
-                Exception::StorePageFault => Trap::StorePageFault,
-                Exception::InstructionFault => Trap::InstructionFault,
-                Exception::InstructionMisaligned => Trap::InstructionMisaligned,
-                Exception::LoadFault => Trap::LoadFault,
-                Exception::LoadMisaligned => Trap::LoadMisaligned,
-                Exception::MachineEnvCall => Trap::SysCall,
-                Exception::StoreFault => Trap::StoreFault,
-                Exception::StoreMisaligned => Trap::StoreMisaligned,
-                Exception::SupervisorEnvCall => Trap::SysCall,
-            },
+#[cfg(feature = "riscv_vectored")]
+unsafe extern "C" {
+    /// The M-mode vector table, `mtvec` points here in vectored mode.
+    #[cfg(not(feature = "riscv_sbi"))]
+    fn machine_trap_vector();
+    /// The S-mode vector table, `stvec` points here in vectored mode.
+    fn supervisor_trap_vector();
+}
+
+// The M-mode vector table, the timer and software interrupts take the fast path.
+// Platform interrupts (16 and up) are never enabled, so the table ends there.
+#[cfg(all(feature = "riscv_vectored", not(feature = "riscv_sbi")))]
+global_asm!(
+    ".pushsection .text.machine_trap_vector, \"ax\"",
+    ".global machine_trap_vector",
+    ".balign 64",
+    "machine_trap_vector:",
+    ".option push",
+    ".option norvc", // every entry must be 4 bytes
+    "j {full}", // exceptions
+    "j {full}",
+    "j {full}",
+    "j {fast}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {fast}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    ".option pop",
+    ".popsection",
+    full = sym machine_trap_entry,
+    fast = sym machine_interrupt_entry,
+);
+
+// The S-mode vector table, the timer and software interrupts take the fast path.
+// Platform interrupts (16 and up) are never enabled, so the table ends there.
+#[cfg(feature = "riscv_vectored")]
+global_asm!(
+    ".pushsection .text.supervisor_trap_vector, \"ax\"",
+    ".global supervisor_trap_vector",
+    ".balign 64",
+    "supervisor_trap_vector:",
+    ".option push",
+    ".option norvc", // every entry must be 4 bytes
+    "j {full}", // exceptions
+    "j {fast}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {fast}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    "j {full}",
+    ".option pop",
+    ".popsection",
+    full = sym supervisor_trap_entry,
+    fast = sym supervisor_interrupt_entry,
+);
+
+/// Decode the cause of a trap taken to `mode`.
+fn convert_trap(frame: &TrapFrame, mode: Mode) -> Trap {
+    let (interrupt, code) = cause(frame);
+    let epc = frame.epc;
+    let address = frame.tval;
+
+    if interrupt {
+        return convert_interrupt(code);
+    }
+
+    match code {
+        0 => Trap::InstructionMisaligned { epc, address },
+        1 => Trap::InstructionFault { epc, address },
+        2 => Trap::IllegalInstruction {
+            epc,
+            instruction: trapping_instruction(frame, mode),
+        },
+        3 => Trap::Breakpoint { epc },
+        4 => Trap::LoadMisaligned { epc, address },
+        5 => Trap::LoadFault { epc, address },
+        6 => Trap::StoreMisaligned { epc, address },
+        7 => Trap::StoreFault { epc, address },
+        8 => Trap::SysCall {
+            epc,
+            mode: Mode::User,
+        },
+        9 => Trap::SysCall {
+            epc,
+            mode: Mode::Supervisor,
+        },
+        10 => Trap::GuestSysCall { epc },
+        11 => Trap::SysCall {
+            epc,
+            mode: Mode::Machine,
         },
-        Err(_) => match trap {
-            mcause::Trap::Interrupt(code) => Trap::Unknown(code),
-            mcause::Trap::Exception(code) => Trap::Unknown(code),
+        12 => Trap::InstructionPageFault { epc, address },
+        13 => Trap::LoadPageFault { epc, address },
+        15 => Trap::StorePageFault { epc, address },
+        16 => Trap::DoubleTrap { epc },
+        18 => Trap::SoftwareCheck {
+            epc,
+            code: frame.tval,
+        },
+        19 => Trap::HardwareError { epc },
+        20 => Trap::InstructionGuestPageFault { epc, address },
+        21 => Trap::LoadGuestPageFault { epc, address },
+        22 => Trap::VirtualInstruction {
+            epc,
+            instruction: trapping_instruction(frame, mode),
+        },
+        23 => Trap::StoreGuestPageFault { epc, address },
+        code => Trap::Unknown { interrupt, code },
+    }
+}
+
+/// Decode the code of an interrupt.
+fn convert_interrupt(code: usize) -> Trap {
+    match code {
+        1 | 3 => Trap::Software,
+        2 => Trap::VirtualSoftware,
+        5 | 7 => Trap::Timer,
+        6 => Trap::VirtualTimer,
+        9 | 11 => Trap::External,
+        10 => Trap::VirtualExternal,
+        12 => Trap::GuestExternal,
+        13 => Trap::CounterOverflow,
+        code => Trap::Unknown {
+            interrupt: true,
+            code,
         },
     }
 }
 
-fn machine_trap() {
-    let trap = mcause::read().cause();
-    let trap = convert_trap(trap);
+/// Split the cause of the trap into whether it is an interrupt, and its code.
+fn cause(frame: &TrapFrame) -> (bool, usize) {
+    ((frame.cause as isize) < 0, frame.cause & (usize::MAX >> 1))
+}
+
+/// The bits of the trapping instruction, taken from `tval` when the hardware reports them there.
+///
+/// Otherwise they are only read for traps from `mode` itself, and are 0 for traps from less
+/// privileged code. Its `epc` is not necessarily readable for us, S-mode can not access user pages
+/// without `sstatus.SUM` and M-mode would read the physical address.
+///
+fn trapping_instruction(frame: &TrapFrame, mode: Mode) -> u32 {
+    if frame.tval != 0 {
+        frame.tval as u32
+    } else if from_mode(frame, mode) {
+        read_instruction(frame.epc)
+    } else {
+        0
+    }
+}
+
+/// Whether a trap taken to `mode` also came from it.
+fn from_mode(frame: &TrapFrame, mode: Mode) -> bool {
+    match mode {
+        #[cfg(not(feature = "riscv_sbi"))]
+        Mode::Machine => frame.status >> MPP_SHIFT & 3 == 3,
+        _ => frame.status & SPP != 0,
+    }
+}
+
+/// Read the instruction at `epc`, which must have been fetched before.
+fn read_instruction(epc: usize) -> u32 {
+    let halfword = |offset: usize| {
+        // SAFETY: The instruction was fetched from there, so it is readable. Instructions are at
+        // least 2 byte aligned.
+        unsafe { ((epc + offset) as *const u16).read_volatile() as u32 }
+    };
 
+    let low = halfword(0);
+    if low & 0b11 == 0b11 {
+        low | halfword(2) << 16
+    } else {
+        low
+    }
+}
+
+/// The M-mode trap handler, `frame` is saved by [`machine_trap_entry`].
+///
+/// When the kernel runs in S-mode, M-mode only sees the traps that are not delegated, which are
+/// the S-mode `ecall`s and M-mode interrupts our SBI [`firmware`] handles.
+///
+#[cfg(not(feature = "riscv_sbi"))]
+extern "C" fn machine_trap(frame: &mut TrapFrame) -> NonNull<TrapFrame> {
+    user::unconfine();
+    let trap = convert_trap(frame, Mode::Machine);
+
+    // SAFETY: Once the trap handler is set up, `mscratch` always points to a `'static` state.
     let state = unsafe { &*(mscratch::read() as *const CoreState) };
     if state.env.kernel == Mode::Machine {
-        handle_trap(state, trap);
+        let next = handle_trap(state, trap, frame);
+        user::confine();
+        return next;
     }
+
+    #[cfg(not(feature = "riscv_isa_e"))]
+    match trap {
+        Trap::SysCall {
+            mode: Mode::Supervisor,
+            ..
+        } => {
+            let a = |index| frame.arg(index);
+            let result = firmware::ecall(a(7), a(6), [a(0), a(1), a(2), a(3), a(4), a(5)]);
+            let (error, value) = match result {
+                Ok(value) => (0, value),
+                Err(error) => (error.code() as usize, 0),
+            };
+
+            frame.set_arg(0, error);
+            frame.set_arg(1, value);
+            skip_instruction(frame);
+        }
+        Trap::Timer => firmware::timer(),
+        Trap::Software => firmware::software(),
+        _ => firmware::fail(frame.cause),
+    }
+
+    NonNull::from(frame)
 }
 
-fn supervisor_trap() {
-    let trap = scause::read().cause();
-    let trap = convert_trap(trap);
+/// The S-mode trap handler, `frame` is saved by [`supervisor_trap_entry`].
+extern "C" fn supervisor_trap(frame: &mut TrapFrame) -> NonNull<TrapFrame> {
+    let trap = convert_trap(frame, Mode::Supervisor);
 
+    // SAFETY: Once the trap handler is set up, `sscratch` always points to a `'static` state.
     let handler = unsafe { &*(sscratch::read() as *const CoreState) };
-    handle_trap(handler, trap);
+    handle_trap(handler, trap, frame)
+}
+
+/// The M-mode fast interrupt handler, called by [`machine_interrupt_entry`].
+///
+/// Dispatches straight to the registered handler, or to our SBI [`firmware`] when the kernel runs
+/// in S-mode.
+///
+#[cfg(all(feature = "riscv_vectored", not(feature = "riscv_sbi")))]
+extern "C" fn machine_interrupt() {
+    user::unconfine();
+    let code = mcause::read().bits() & (usize::MAX >> 1);
+    let trap = convert_interrupt(code);
+
+    // SAFETY: Once the trap handler is set up, `mscratch` always points to a `'static` state.
+    let state = unsafe { &*(mscratch::read() as *const CoreState) };
+    if state.env.kernel == Mode::Machine {
+        if !handle_interrupt(state, trap) {
+            // SAFETY: Masking an interrupt nobody handles only stops it from firing again.
+            unsafe { asm!("csrc mie, {}", in(reg) 1usize << code) }
+        }
+        user::confine();
+        return;
+    }
+
+    #[cfg(not(feature = "riscv_isa_e"))]
+    match trap {
+        Trap::Timer => firmware::timer(),
+        Trap::Software => firmware::software(),
+        _ => {}
+    }
 }
 
+/// The S-mode fast interrupt handler, called by [`supervisor_interrupt_entry`].
+#[cfg(feature = "riscv_vectored")]
+extern "C" fn supervisor_interrupt() {
+    let code = scause::read().bits() & (usize::MAX >> 1);
+    let trap = convert_interrupt(code);
+
+    // SAFETY: Once the trap handler is set up, `sscratch` always points to a `'static` state.
+    let state = unsafe { &*(sscratch::read() as *const CoreState) };
+    if !handle_interrupt(state, trap) {
+        // SAFETY: Masking an interrupt nobody handles only stops it from firing again.
+        unsafe { asm!("csrc sie, {}", in(reg) 1usize << code) }
+    }
+}
+
+/// Advance the frame past the trapping instruction.
+pub(crate) fn skip_instruction(frame: &mut TrapFrame) {
+    frame.epc += instruction_length(frame);
+}
+
+/// The length of the trapping instruction in bytes, taking compressed instructions into account.
+fn instruction_length(frame: &TrapFrame) -> usize {
+    let bits = match convert_trap(frame, kernel_mode()) {
+        // `ecall` has no compressed form.
+        Trap::SysCall { .. } | Trap::GuestSysCall { .. } => return 4,
+        Trap::IllegalInstruction { instruction, .. }
+        | Trap::VirtualInstruction { instruction, .. } => instruction,
+        _ => read_instruction(frame.epc),
+    };
+
+    if bits & 0b11 == 0b11 { 4 } else { 2 }
+}
+
+/// The M-mode trap vector, and its mode.
+#[cfg(not(feature = "riscv_sbi"))]
+fn machine_vector() -> (TrapMode, usize) {
+    #[cfg(feature = "riscv_vectored")]
+    return (TrapMode::Vectored, machine_trap_vector as usize);
+    #[cfg(not(feature = "riscv_vectored"))]
+    return (TrapMode::Direct, machine_trap_entry as usize);
+}
+
+/// The S-mode trap vector, and its mode.
+fn supervisor_vector() -> (TrapMode, usize) {
+    #[cfg(feature = "riscv_vectored")]
+    return (TrapMode::Vectored, supervisor_trap_vector as usize);
+    #[cfg(not(feature = "riscv_vectored"))]
+    return (TrapMode::Direct, supervisor_trap_entry as usize);
+}
+
+#[cfg(not(feature = "riscv_sbi"))]
 pub(crate) fn setup_trap_handler(core: &Core) {
+    let (mode, address) = machine_vector();
     let mut mvec = Mtvec::from_bits(0);
-    mvec.set_trap_mode(TrapMode::Direct);
-    mvec.set_address(machine_trap_entry as usize);
+    mvec.set_trap_mode(mode);
+    mvec.set_address(address);
     // SAFETY: The `mvec` is properly set up.
     unsafe {
         mtvec::write(mvec);
     }
 
     if core.state.env.kernel == Mode::Supervisor {
+        let (mode, address) = supervisor_vector();
         let mut svec = Stvec::from_bits(0);
-        svec.set_address(supervisor_trap_entry as usize);
+        svec.set_trap_mode(mode);
+        svec.set_address(address);
 
         // SAFETY: The `svec` is properly set up.
         unsafe {
@@ -533,3 +1817,18 @@ pub(crate) fn setup_trap_handler(core: &Core) {
         }
     }
 }
+
+/// Under an SBI firmware only the S-mode trap handler is ours to set up.
+#[cfg(feature = "riscv_sbi")]
+pub(crate) fn setup_trap_handler(_core: &Core) {
+    let (mode, address) = supervisor_vector();
+    let mut svec = Stvec::from_bits(0);
+    svec.set_trap_mode(mode);
+    svec.set_address(address);
+
+    // SAFETY: The `svec` is properly set up.
+    unsafe {
+        stvec::write(svec);
+        sstatus::set_sie();
+    }
+}
@@ -0,0 +1,310 @@
+//! Running untrusted code in the user mode of the execution environment, isolated from the kernel.
+//!
+//! [`Context::run`] enters the code with `sret`/`mret`, and returns once it makes a system call or
+//! faults. Interrupts are handled as usual while it runs, after which it continues.
+//!
+//! With an S-mode kernel the code sees the active address space, so only the pages mapped with
+//! [`Flags::USER`] are reachable. With an M-mode kernel, PMP entries limit it to the regions of its
+//! [`Context`]. Without U-mode the code runs in M-mode, confined by locked PMP entries that keep it
+//! from writing outside of its regions (Smepmp is needed to unlock them again on every trap). That
+//! only catches stray writes, M-mode code can always reprogram the PMP, so it must not be hostile.
+
+use core::{arch::asm, cell::Cell, ptr::NonNull};
+
+use super::{
+    boot::Region,
+    core::{percpu, with_current},
+    mmu::Flags,
+    trap::{Mode, Trap, TrapFrame, skip_instruction},
+};
+
+mod riscv;
+
+/// The most regions a [`Context`] can be given.
+pub const MAX_REGIONS: usize = 4;
+
+percpu! {
+    /// The user code run on the core, if any.
+    static RUN: Cell<Option<Run>> = Cell::new(None);
+}
+
+/// User code, and the memory it may use.
+#[derive(Debug, Clone)]
+pub struct Context {
+    /// The registers of the code, updated whenever it returns to the kernel.
+    pub frame: TrapFrame,
+    regions: Regions,
+}
+
+/// The memory user code may use, and what it may do with it.
+#[derive(Debug, Clone, Copy)]
+pub struct Regions(pub [Option<(Region, Flags)>; MAX_REGIONS]);
+
+/// Why user code returned to the kernel.
+#[derive(Debug, Clone, Copy)]
+pub enum Exit {
+    /// The code made a system call with `ecall`, the frame already points past it.
+    SysCall,
+    /// Any other exception, the frame still points at the trapping instruction.
+    Fault(Trap),
+}
+
+/// Why user code can not be run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The context has [`MAX_REGIONS`], or there are not enough PMP entries for its regions.
+    TooManyRegions,
+    /// The region is empty or not 4 byte aligned, or writable but not readable.
+    InvalidRegion,
+    /// The core can not isolate the code. Confining M-mode code needs Smepmp, and M-mode kernels
+    /// need the `riscv_pmp` feature for it.
+    Unsupported,
+}
+
+/// Where a [`Run`] is at.
+#[derive(Clone, Copy)]
+enum State {
+    /// [`Context::run`] is about to trap into the kernel, which switches to the code.
+    Entering,
+    /// The code runs, or the kernel handles an interrupt of it.
+    Running,
+    /// The code returned to the kernel.
+    Exited(Exit),
+}
+
+/// User code run on a core.
+#[derive(Clone, Copy)]
+struct Run {
+    /// The address of the [`Context`].
+    context: usize,
+    /// The address of the [`TrapFrame`] of the kernel, resumed once the code exits.
+    kernel: usize,
+    state: State,
+    /// The PMP rules for the code, `None` when the kernel runs in S-mode or there is no PMP.
+    rules: Option<riscv::Rules>,
+}
+
+impl Context {
+    /// User code starting at `entry`, with its stack pointer at `stack`, that may not use any
+    /// memory yet.
+    pub fn new(entry: usize, stack: usize) -> Context {
+        let mut frame = TrapFrame::new(entry);
+        frame.set_register(2, stack);
+
+        Context {
+            frame,
+            regions: Regions([None; MAX_REGIONS]),
+        }
+    }
+
+    /// Let the code use `region`, as far as `flags` allow.
+    ///
+    /// Only the PMP of M-mode kernels enforces this, S-mode kernels isolate the code with the
+    /// active address space instead.
+    ///
+    pub fn allow(&mut self, region: Region, flags: Flags) -> Result<(), Error> {
+        if region.size == 0
+            || region.start % 4 != 0
+            || region.size % 4 != 0
+            || flags.contains(Flags::WRITE) && !flags.contains(Flags::READ)
+        {
+            return Err(Error::InvalidRegion);
+        }
+
+        let free = self.regions.0.iter_mut().find(|slot| slot.is_none());
+        *free.ok_or(Error::TooManyRegions)? = Some((region, flags));
+        Ok(())
+    }
+
+    /// The memory the code may use, to check the pointers of its system calls against.
+    pub fn regions(&self) -> Regions {
+        self.regions
+    }
+
+    /// Run the code until it makes a system call or faults.
+    ///
+    /// The code continues where it left off (or at its entry), so this can be called again after
+    /// handling the system call.
+    ///
+    /// # Panics
+    ///
+    /// If the core already runs user code, like when called from a trap handler while it does.
+    ///
+    pub fn run(&mut self) -> Result<Exit, Error> {
+        let (kernel, user) = with_current(|state| (state.env.kernel, state.env.user));
+        let run = Run {
+            context: self as *mut Context as usize,
+            kernel: 0,
+            state: State::Entering,
+            rules: riscv::rules(&self.regions.0, kernel, user)?,
+        };
+
+        let cell = RUN.get();
+        assert!(cell.get().is_none(), "the core already runs user code");
+        cell.set(Some(run));
+
+        // SAFETY: The breakpoint traps into `intercept`, which saves this context and switches to
+        // the code. Once the code exits, every register is restored and we continue after it.
+        unsafe { asm!("ebreak") }
+
+        match cell.take() {
+            Some(Run {
+                state: State::Exited(exit),
+                ..
+            }) => Ok(exit),
+            _ => unreachable!("user code returned without exiting"),
+        }
+    }
+}
+
+/// Switch between the kernel and the user code run on the core, called for every trap.
+///
+/// # Returns
+///
+/// The frame to resume instead of `frame`, if the trap enters or exits the code.
+///
+pub(crate) fn intercept(trap: Trap, frame: &mut TrapFrame) -> Option<NonNull<TrapFrame>> {
+    let cell = RUN.get();
+    let mut run = cell.get()?;
+    let (kernel, user) = with_current(|state| (state.env.kernel, state.env.user));
+
+    // SAFETY: `Context::run` borrows the context until the code exits.
+    let context = unsafe { &mut *(run.context as *mut Context) };
+
+    let next = match run.state {
+        State::Entering if matches!(trap, Trap::Breakpoint { .. }) => {
+            skip_instruction(frame);
+            run.kernel = frame as *mut TrapFrame as usize;
+            run.state = State::Running;
+            context.frame.status = riscv::user_status(frame.status, kernel, user);
+
+            // Confining rules are only written once the kernel is done, see `confine`.
+            if let (Mode::User, Some(rules)) = (user, &run.rules) {
+                // SAFETY: Unlocked rules only apply to U-mode.
+                unsafe { rules.write() }
+            }
+
+            NonNull::from(&mut context.frame)
+        }
+        State::Running
+            if trap.exception().is_some() && riscv::from_user(frame.status, kernel, user) =>
+        {
+            let exit = match trap {
+                Trap::SysCall { .. } => {
+                    skip_instruction(frame);
+                    Exit::SysCall
+                }
+                trap => Exit::Fault(trap),
+            };
+
+            context.frame = frame.clone();
+            run.state = State::Exited(exit);
+            // The frame of the kernel stays on its stack, below `Context::run`.
+            NonNull::new(run.kernel as *mut TrapFrame)?
+        }
+        _ => return None,
+    };
+
+    cell.set(Some(run));
+    Some(next)
+}
+
+/// Lift the locked PMP entries confining M-mode user code, called first on every M-mode trap.
+///
+/// The kernel can not write most of its memory otherwise.
+///
+#[cfg(not(feature = "riscv_sbi"))]
+pub(crate) fn unconfine() {
+    if let Some(Run {
+        state: State::Running,
+        rules: Some(rules),
+        ..
+    }) = RUN.get().get()
+        && rules.is_locked()
+    {
+        riscv::clear_rules()
+    }
+}
+
+/// Confine M-mode user code again, called last on every M-mode trap, before returning to it.
+///
+/// Afterwards only the regions of the code and the [`CoreState`](super::core::CoreState) can be
+/// written, so this has to be the last thing the trap handler does.
+///
+#[cfg(not(feature = "riscv_sbi"))]
+pub(crate) fn confine() {
+    if let Some(Run {
+        state: State::Running,
+        rules: Some(rules),
+        ..
+    }) = RUN.get().get()
+        && rules.is_locked()
+    {
+        // SAFETY: `mseccfg.RLB` is set, so `unconfine` can remove the locked rules again.
+        unsafe { rules.write() }
+    }
+}
+
+/// Check that user code returns to the kernel on a system call, which the kernel carries out, and
+/// on a fault after it.
+///
+/// The code runs from the kernel image, which a moved kernel maps without [`Flags::USER`], so the
+/// check only runs while the kernel is not moved. Cores that can not isolate user code skip it.
+///
+#[cfg(feature = "self_test")]
+pub(crate) fn self_test() {
+    use core::arch::naked_asm;
+
+    use super::{
+        mmu,
+        syscall::{self, Caller, Errno},
+    };
+
+    const ADD: usize = 0;
+
+    fn add(caller: &Caller) -> Result<[usize; 2], Errno> {
+        let (first, second) = caller.args::<(usize, usize)>()?;
+        Ok([first.wrapping_add(second), 0])
+    }
+
+    /// Makes a system call, and executes an illegal instruction once it continues.
+    #[unsafe(naked)]
+    extern "C" fn code() {
+        naked_asm!("ecall", "unimp")
+    }
+
+    if mmu::physical_offset() != 0 {
+        return;
+    }
+
+    // The instructions take at most 8 bytes from the 4 byte boundary before them.
+    let entry = code as usize;
+    let region = Region {
+        start: entry & !3,
+        size: 8,
+    };
+
+    let mut context = Context::new(entry, 0);
+    context
+        .allow(region, Flags::READ | Flags::EXECUTE)
+        .expect("user code region rejected");
+    context.frame.set_register(syscall::NUMBER, ADD);
+    context.frame.set_arg(0, 20);
+    context.frame.set_arg(1, 22);
+    syscall::register(ADD, add);
+
+    match context.run() {
+        Err(Error::Unsupported) => return,
+        Ok(Exit::SysCall) => {}
+        exit => panic!("user code did not make a system call: {exit:?}"),
+    }
+    assert_eq!(context.frame.epc, entry + 4, "system call not skipped");
+
+    syscall::dispatch(&mut context.frame, &context.regions());
+    assert_eq!(context.frame.arg(0), 42, "system call not carried out");
+
+    match context.run() {
+        Ok(Exit::Fault(Trap::IllegalInstruction { epc, .. })) if epc == entry + 4 => {}
+        exit => panic!("user code did not fault: {exit:?}"),
+    }
+}
@@ -0,0 +1,270 @@
+#[cfg(all(feature = "riscv_pmp", not(feature = "riscv_sbi")))]
+use core::arch::asm;
+
+#[cfg(all(feature = "riscv_pmp", not(feature = "riscv_sbi")))]
+use crate::hal::{
+    boot,
+    core::{CoreState, id, with_current},
+};
+use crate::hal::{boot::Region, execution::riscv::Mode, mmu::Flags};
+
+use super::{Error, MAX_REGIONS};
+
+/// `sstatus` bits.
+const SPIE: usize = 1 << 5;
+const SPP: usize = 1 << 8;
+const SUM: usize = 1 << 18;
+/// `mstatus` bits.
+const MPIE: usize = 1 << 7;
+const MPP: usize = 3 << 11;
+const MPRV: usize = 1 << 17;
+
+/// The PMP entries used for user code, held by `pmpcfg0` on RV64 and `pmpcfg0`/`pmpcfg1` on RV32.
+#[cfg(all(feature = "riscv_pmp", not(feature = "riscv_sbi")))]
+const PMP_ENTRIES: usize = 8;
+
+/// PMP configuration bits.
+#[cfg(all(feature = "riscv_pmp", not(feature = "riscv_sbi")))]
+mod pmp {
+    pub const READ: u8 = 1 << 0;
+    pub const WRITE: u8 = 1 << 1;
+    pub const EXECUTE: u8 = 1 << 2;
+    pub const TOR: u8 = 1 << 3;
+    pub const NAPOT: u8 = 3 << 3;
+    pub const LOCKED: u8 = 1 << 7;
+}
+
+/// `mseccfg.RLB` of Smepmp, lets M-mode change and remove locked PMP entries.
+#[cfg(all(feature = "riscv_pmp", not(feature = "riscv_sbi")))]
+const MSECCFG_RLB: usize = 1 << 2;
+
+/// The PMP entries that isolate user code, the first matching entry applies.
+#[cfg(all(feature = "riscv_pmp", not(feature = "riscv_sbi")))]
+#[derive(Default, Clone, Copy)]
+pub(super) struct Rules {
+    config: [u8; PMP_ENTRIES],
+    addresses: [usize; PMP_ENTRIES],
+    len: usize,
+}
+
+/// There are no PMP rules without the PMP, or when the kernel runs in S-mode.
+#[cfg(not(all(feature = "riscv_pmp", not(feature = "riscv_sbi"))))]
+#[derive(Clone, Copy)]
+pub(super) enum Rules {}
+
+#[cfg(all(feature = "riscv_pmp", not(feature = "riscv_sbi")))]
+impl Rules {
+    /// Add a rule for `region`, a single NAPOT entry if it is a naturally aligned power of two,
+    /// two entries for its bounds otherwise.
+    fn push(&mut self, region: Region, config: u8) -> Result<(), Error> {
+        let Region { start, size } = region;
+        if size.is_power_of_two() && size >= 8 && start % size == 0 {
+            self.entry(start >> 2 | ((size >> 3) - 1), pmp::NAPOT | config)
+        } else {
+            self.entry(start >> 2, 0)?;
+            self.entry(region.end() >> 2, pmp::TOR | config)
+        }
+    }
+
+    /// Add a rule for the whole address space.
+    fn push_all(&mut self, config: u8) -> Result<(), Error> {
+        self.entry(usize::MAX, pmp::NAPOT | config)
+    }
+
+    fn entry(&mut self, address: usize, config: u8) -> Result<(), Error> {
+        if self.len == PMP_ENTRIES {
+            return Err(Error::TooManyRegions);
+        }
+
+        self.addresses[self.len] = address;
+        self.config[self.len] = config;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Whether the rules also apply to M-mode.
+    pub(super) fn is_locked(&self) -> bool {
+        self.config[..self.len]
+            .iter()
+            .any(|config| config & pmp::LOCKED != 0)
+    }
+
+    /// Program the rules into the PMP, switching off the other entries.
+    ///
+    /// # Safety
+    ///
+    /// Locked rules apply to M-mode as well, which can only remove them with `mseccfg.RLB` set.
+    ///
+    pub(super) unsafe fn write(&self) {
+        for (index, &address) in self.addresses[..self.len].iter().enumerate() {
+            write_address(index, address);
+        }
+
+        let config = u64::from_le_bytes(self.config);
+        // SAFETY: The caller guarantees locked rules can be removed again.
+        unsafe {
+            #[cfg(target_arch = "riscv64")]
+            asm!("csrw pmpcfg0, {}", in(reg) config);
+            #[cfg(target_arch = "riscv32")]
+            asm!(
+                "csrw pmpcfg0, {}",
+                "csrw pmpcfg1, {}",
+                in(reg) config as u32,
+                in(reg) (config >> 32) as u32,
+            );
+        }
+    }
+}
+
+#[cfg(not(all(feature = "riscv_pmp", not(feature = "riscv_sbi"))))]
+impl Rules {
+    pub(super) fn is_locked(&self) -> bool {
+        match *self {}
+    }
+
+    pub(super) unsafe fn write(&self) {
+        match *self {}
+    }
+}
+
+/// Set `pmpaddr{index}`, CSR numbers are part of the instruction.
+#[cfg(all(feature = "riscv_pmp", not(feature = "riscv_sbi")))]
+fn write_address(index: usize, address: usize) {
+    // SAFETY: The entries are switched off or reconfigured by the following write of the
+    // configuration, so an address alone changes nothing.
+    unsafe {
+        match index {
+            0 => asm!("csrw pmpaddr0, {}", in(reg) address),
+            1 => asm!("csrw pmpaddr1, {}", in(reg) address),
+            2 => asm!("csrw pmpaddr2, {}", in(reg) address),
+            3 => asm!("csrw pmpaddr3, {}", in(reg) address),
+            4 => asm!("csrw pmpaddr4, {}", in(reg) address),
+            5 => asm!("csrw pmpaddr5, {}", in(reg) address),
+            6 => asm!("csrw pmpaddr6, {}", in(reg) address),
+            7 => asm!("csrw pmpaddr7, {}", in(reg) address),
+            _ => unreachable!("PMP entry {index} is not used for user code"),
+        }
+    }
+}
+
+/// Switch off the PMP entries of user code, including locked ones.
+#[cfg(not(feature = "riscv_sbi"))]
+pub(super) fn clear_rules() {
+    #[cfg(feature = "riscv_pmp")]
+    // SAFETY: Locked rules are only written with `mseccfg.RLB` set, which lets M-mode remove them.
+    unsafe {
+        asm!("csrw pmpcfg0, zero");
+        #[cfg(target_arch = "riscv32")]
+        asm!("csrw pmpcfg1, zero");
+    }
+}
+
+/// The PMP configuration bits allowing what `flags` do.
+#[cfg(all(feature = "riscv_pmp", not(feature = "riscv_sbi")))]
+fn permissions(flags: Flags) -> u8 {
+    [
+        (Flags::READ, pmp::READ),
+        (Flags::WRITE, pmp::WRITE),
+        (Flags::EXECUTE, pmp::EXECUTE),
+    ]
+    .into_iter()
+    .filter(|&(flag, _)| flags.contains(flag))
+    .fold(0, |config, (_, bit)| config | bit)
+}
+
+/// Whether the current hart has Smepmp, as reported by its ISA string.
+#[cfg(all(feature = "riscv_pmp", not(feature = "riscv_sbi")))]
+fn has_smepmp() -> bool {
+    boot::info()
+        .cpus
+        .iter()
+        .any(|cpu| cpu.id == id() && cpu.has_extension("smepmp"))
+}
+
+/// The PMP rules isolating user code that may use `regions`.
+///
+/// S-mode kernels need none, M-mode kernels limit U-mode to the regions with unlocked rules. M-mode
+/// user code gets locked rules, which also allow writes to the [`CoreState`] (the trap entry code
+/// saves registers there) and reads and execution everywhere, so traps reach the kernel.
+///
+#[cfg(all(feature = "riscv_pmp", not(feature = "riscv_sbi")))]
+pub(super) fn rules(
+    regions: &[Option<(Region, Flags)>; MAX_REGIONS],
+    kernel: Mode,
+    user: Mode,
+) -> Result<Option<Rules>, Error> {
+    if kernel != Mode::Machine {
+        return Ok(None);
+    }
+
+    let confined = user == Mode::Machine;
+    let lock = if confined { pmp::LOCKED } else { 0 };
+    let mut rules = Rules::default();
+
+    if confined {
+        if !has_smepmp() {
+            return Err(Error::Unsupported);
+        }
+
+        // SAFETY: Setting `RLB` only lets M-mode remove locked rules, it has to be set before any
+        // are written.
+        unsafe { asm!("csrs 0x747, {}", in(reg) MSECCFG_RLB) }
+
+        let state = with_current(|state| Region {
+            start: state as *const CoreState as usize,
+            size: size_of::<CoreState>(),
+        });
+        rules.push(state, pmp::READ | pmp::WRITE | lock)?;
+    }
+
+    for &(region, flags) in regions.iter().flatten() {
+        rules.push(region, permissions(flags) | lock)?;
+    }
+
+    if confined {
+        rules.push_all(pmp::READ | pmp::EXECUTE | lock)?;
+    }
+
+    Ok(Some(rules))
+}
+
+/// The PMP rules isolating user code, of which there are none without the PMP.
+///
+/// S-mode kernels isolate U-mode with the address space instead, M-mode kernels do not isolate it
+/// at all. M-mode user code can not be confined.
+///
+#[cfg(not(all(feature = "riscv_pmp", not(feature = "riscv_sbi"))))]
+pub(super) fn rules(
+    _regions: &[Option<(Region, Flags)>; MAX_REGIONS],
+    kernel: Mode,
+    user: Mode,
+) -> Result<Option<Rules>, Error> {
+    match (kernel, user) {
+        (Mode::Machine, Mode::Machine) => Err(Error::Unsupported),
+        _ => Ok(None),
+    }
+}
+
+/// The status of user code, from the `status` of the kernel entering it.
+///
+/// Returning from the trap drops to the user mode, with interrupts enabled.
+///
+pub(super) fn user_status(status: usize, kernel: Mode, user: Mode) -> usize {
+    match kernel {
+        Mode::Supervisor => status & !(SPP | SUM) | SPIE,
+        _ => {
+            let mpp = if user == Mode::Machine { MPP } else { 0 };
+            status & !(MPP | MPRV) | mpp | MPIE
+        }
+    }
+}
+
+/// Whether a trap with `status` came from user code, rather than from the kernel.
+pub(super) fn from_user(status: usize, kernel: Mode, user: Mode) -> bool {
+    match (kernel, user) {
+        // M-mode user code can not be told apart from the kernel, only its exceptions exit it.
+        (_, Mode::Machine) => true,
+        (Mode::Supervisor, _) => status & SPP == 0,
+        _ => status & MPP == 0,
+    }
+}
@@ -1,23 +1,60 @@
 #![no_std]
 #![no_main]
 
-use core::{hint::spin_loop, panic::PanicInfo};
+extern crate alloc;
 
-use hal::{interrupts, trap::Trap};
+use core::panic::PanicInfo;
 
+use hal::{
+    boot::BootInfo,
+    interrupts, ipi,
+    power::{self, Reason},
+    trap::{Exception, Resume, Trap, TrapFrame},
+};
+
+#[cfg(feature = "bench_interrupts")]
+mod bench;
 mod hal;
+#[cfg(feature = "self_test")]
+mod self_test;
+
+pub fn main(_info: &'static BootInfo) -> ! {
+    #[cfg(feature = "bench_interrupts")]
+    bench::run();
+    #[cfg(feature = "self_test")]
+    self_test::run();
+
+    interrupts::register_exception(Exception::Breakpoint, &skip);
+    interrupts::register_exception(Exception::SysCall, &skip);
+    interrupts::enable();
 
-pub fn main() -> ! {
     loop {
         interrupts::wait()
     }
 }
 
-pub fn handle_trap(_trap: Trap) {}
+/// Continue after the trapping instruction, as there is nothing to do for it yet.
+fn skip(_trap: Trap, _frame: &mut TrapFrame) -> Resume {
+    Resume::Skip
+}
+
+/// Handles the traps no handler is registered for.
+pub fn handle_trap(trap: Trap, _frame: &mut TrapFrame) -> Resume {
+    match trap {
+        Trap::Timer | Trap::External | Trap::Software => Resume::Return,
+        _ => Resume::Fatal,
+    }
+}
 
 #[panic_handler]
 fn handle_panic(_info: &PanicInfo) -> ! {
-    loop {
-        spin_loop()
+    interrupts::disable();
+
+    // The other cores might depend on whatever broke here, so they stop as well. Only the first
+    // panic shuts down, any later one (like one while shutting down) just stops its core.
+    if !ipi::halt_others() {
+        ipi::halt()
     }
+
+    power::shutdown(Reason::Failure)
 }
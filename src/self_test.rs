@@ -0,0 +1,18 @@
+//! Checks of the HAL at boot, run by the `self-test` recipe.
+//!
+//! Every check panics when it fails, which shuts the system down with a failure. Once all of them
+//! pass it shuts down normally, so QEMU exits with a status telling how it went.
+
+use crate::hal::{
+    core,
+    power::{self, Reason},
+    user,
+};
+
+/// Run every check and shut down, so this never returns.
+pub fn run() {
+    core::self_test();
+    user::self_test();
+
+    power::shutdown(Reason::Done)
+}